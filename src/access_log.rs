@@ -0,0 +1,152 @@
+mod test;
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::http_version::HttpVersion;
+use crate::logging::warning;
+use crate::middleware::{
+    MiddlewareEntry, MiddlewareHandler, MiddlewareLayer, MiddlewareType, PathParameter,
+};
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::{json_string, utc_date_time};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// `host ident user [time] "request line" status bytes`
+    Common,
+    /// `Common` followed by the quoted referer and user agent.
+    Combined,
+    /// One JSON object per line, with the duration, user agent and request id.
+    Json,
+}
+
+/// Writes a line per request to a sink as its response is sent, e.g.
+/// `server.layer(AccessLog::stdout(AccessLogFormat::Combined))`.
+///
+/// The byte count is the body as sent, after compression. The duration stops
+/// before the response is written.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+    path: PathParameter,
+}
+
+impl AccessLog {
+    pub fn new<W: Write + Send + 'static>(format: AccessLogFormat, sink: W) -> Self {
+        AccessLog {
+            format,
+            sink: Arc::new(Mutex::new(Box::new(sink))),
+            path: PathParameter::Wildcard,
+        }
+    }
+
+    pub fn stdout(format: AccessLogFormat) -> Self {
+        Self::new(format, std::io::stdout())
+    }
+
+    /// Only log requests whose path matches `path`.
+    pub fn path(mut self, path: PathParameter) -> Self {
+        self.path = path;
+        self
+    }
+}
+
+impl MiddlewareLayer for AccessLog {
+    fn into_middlewares(self) -> Vec<MiddlewareEntry> {
+        let (format, sink) = (self.format, self.sink);
+        vec![MiddlewareEntry {
+            middleware_type: MiddlewareType::Logger(self.path),
+            handler: MiddlewareHandler::Logger(Arc::new(move |request: &Request, response: &Response| {
+                let mut line = format_line(format, request, response, SystemTime::now());
+                line.push('\n');
+                let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = sink.write_all(line.as_bytes()).and_then(|_| sink.flush()) {
+                    warning!(error = %e, "Failed to write access log.");
+                }
+            })),
+        }]
+    }
+}
+
+pub(crate) fn format_line(format: AccessLogFormat, request: &Request, response: &Response, now: SystemTime) -> String {
+    let host = match request.peer_addr() {
+        Some(addr) => addr.ip().to_string(),
+        None => "-".to_string(),
+    };
+    // Event streams are still being written.
+    let bytes = response.event_stream.is_none().then_some(response.bytes.len());
+    let header = |name: &str| request.headers.get_single(name).map(String::as_str);
+
+    match format {
+        AccessLogFormat::Common | AccessLogFormat::Combined => {
+            let (year, month, day, hour, minute, second) = utc_date_time(now);
+            let mut line = format!(
+                "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{:?} {} {}\" {} {}",
+                host,
+                day,
+                MONTHS[month as usize - 1],
+                year,
+                hour,
+                minute,
+                second,
+                request.method,
+                escape_quoted(&request.path),
+                version(&request.http_version),
+                response.status_code.code,
+                bytes.map_or("-".to_string(), |bytes| bytes.to_string()),
+            );
+            if format == AccessLogFormat::Combined {
+                line.push_str(&format!(
+                    " \"{}\" \"{}\"",
+                    escape_quoted(header("referer").unwrap_or("-")),
+                    escape_quoted(header("user-agent").unwrap_or("-")),
+                ));
+            }
+            line
+        }
+        AccessLogFormat::Json => {
+            let (year, month, day, hour, minute, second) = utc_date_time(now);
+            let string = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+            format!(
+                "{{\"time\":\"{}-{:02}-{:02}T{:02}:{:02}:{:02}Z\",\"peer\":{},\"method\":\"{:?}\",\"path\":{},\"version\":\"{}\",\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\"referer\":{},\"user_agent\":{},\"request_id\":{},\"trace_id\":{}}}",
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                string(request.peer().map(ToString::to_string).as_deref()),
+                request.method,
+                json_string(&request.path),
+                version(&request.http_version),
+                response.status_code.code,
+                bytes.map_or("null".to_string(), |bytes| bytes.to_string()),
+                request.received_at().elapsed().as_secs_f64() * 1000.0,
+                string(header("referer")),
+                string(header("user-agent")),
+                string(request.request_id()),
+                string(request.trace_context().map(|trace| trace.trace_id.as_str())),
+            )
+        }
+    }
+}
+
+fn version(version: &HttpVersion) -> &'static str {
+    match version {
+        HttpVersion::Http1_0 => "HTTP/1.0",
+        HttpVersion::Http1_1 => "HTTP/1.1",
+        HttpVersion::Http2 => "HTTP/2.0",
+    }
+}
+
+/// Escapes a value written between quotes in the Common Log Format.
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
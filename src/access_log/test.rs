@@ -0,0 +1,47 @@
+#![cfg(test)]
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::access_log::*;
+use crate::http_method::HttpMethod;
+use crate::listener::Peer;
+use crate::map::{DuplicateMap, Map};
+use crate::request::Request;
+use crate::response::text;
+
+fn request() -> Request {
+    let mut headers: Map<DuplicateMap> = Map::default();
+    headers.add("user-agent", "curl/8.0 \"test\"".to_string());
+    headers.add("referer", "https://example.com/".to_string());
+    Request {
+        method: HttpMethod::GET,
+        path: "/a?b=c".to_string(),
+        headers,
+        peer: Some(Peer::Tcp("127.0.0.1:5000".parse().unwrap())),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_common_log_format() {
+    // 2000-10-10 13:55:36 UTC
+    let now = UNIX_EPOCH + Duration::from_secs(971186136);
+    let line = format_line(AccessLogFormat::Common, &request(), &text("hello"), now);
+    assert_eq!(line, "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a?b=c HTTP/1.1\" 200 5");
+}
+
+#[test]
+fn test_combined_log_format_escapes_quotes() {
+    let now = UNIX_EPOCH + Duration::from_secs(971186136);
+    let line = format_line(AccessLogFormat::Combined, &request(), &text("hello"), now);
+    assert!(line.ends_with("200 5 \"https://example.com/\" \"curl/8.0 \\\"test\\\"\""));
+}
+
+#[test]
+fn test_json_log_format() {
+    let now = UNIX_EPOCH + Duration::from_secs(951782400); // 2000-02-29
+    let line = format_line(AccessLogFormat::Json, &request(), &text("hello"), now);
+    assert!(line.starts_with("{\"time\":\"2000-02-29T00:00:00Z\",\"peer\":\"127.0.0.1:5000\",\"method\":\"GET\",\"path\":\"/a?b=c\""));
+    assert!(line.contains("\"status\":200,\"bytes\":5,\"duration_ms\":"));
+    assert!(line.ends_with("\"user_agent\":\"curl/8.0 \\\"test\\\"\",\"request_id\":null,\"trace_id\":null}"));
+}
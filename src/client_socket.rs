@@ -1,5 +1,6 @@
 
 use std::fmt::Display;
+use std::time::{Duration, Instant};
 
 use futures::FutureExt;
 use smol::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
@@ -71,8 +72,8 @@ impl Display for WriteError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             WriteError::IoError(e) => write!(f, "IO Error: {}", e),
-            WriteError::Timeout => write!(f, "Read timeout"),
-            WriteError::Cancellation => write!(f, "Read cancelled"),
+            WriteError::Timeout => write!(f, "Write timeout"),
+            WriteError::Cancellation => write!(f, "Write cancelled"),
             WriteError::UnexpectedError => write!(f, "Unexpected error"),
         }
     }
@@ -220,6 +221,11 @@ pub(crate) trait SocketReader {
 
 pub(crate) trait SocketWriter {
     async fn write_all(&mut self, data: &[u8]) -> Result<(), WriteError>;
+
+    /// Resolves once the server asks this connection to stop.
+    async fn cancelled(&mut self) {
+        futures::future::pending::<()>().await
+    }
 }
 
 
@@ -228,28 +234,97 @@ pub(crate) trait Socket: SocketReader + SocketWriter {}
 impl <T: SocketReader + SocketWriter> Socket for T {}
 
 
+/// A type-erased connection, used once a connection is handed over to a
+/// handler that outlives the HTTP request (e.g. WebSockets).
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// What the connection is reading, which decides how long a read may take.
+pub(crate) enum ReadPhase {
+    /// Waiting for the next request. The headers have `header_timeout` from
+    /// their first byte.
+    Idle { timeout: Duration, header_timeout: Duration },
+    /// Reading request headers, which have to be complete by `deadline`.
+    Headers { deadline: Instant },
+    /// Reading a request body. Past a grace period of `read_timeout` the
+    /// client has to keep up `min_rate` bytes per second on average.
+    Body { started: Instant, read: usize, min_rate: Option<u64> },
+    /// Only `read_timeout` applies, e.g. to WebSocket frames.
+    Streaming,
+}
+
 pub struct ClientSocket<T: AsyncRead + AsyncWrite + Unpin>  {
     pub socket: T,
     pub cancellation_token: smol::channel::Receiver<()>,
-    pub read_timeout: std::time::Duration,
+    /// The longest a single read may wait for data.
+    pub read_timeout: Duration,
+    pub(crate) write_timeout: Duration,
+    pub(crate) phase: ReadPhase,
+    /// Closed when the server starts draining, which only interrupts reads
+    /// while the connection is idle.
+    pub(crate) draining: Option<smol::channel::Receiver<()>>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> ClientSocket<T> {
+    fn timeout(&self, now: Instant) -> Duration {
+        match self.phase {
+            ReadPhase::Idle { timeout, .. } => timeout,
+            ReadPhase::Headers { deadline } => deadline.saturating_duration_since(now).min(self.read_timeout),
+            ReadPhase::Body { started, read, min_rate: Some(min_rate) } => {
+                let allowed = Duration::from_secs_f64(read as f64 / min_rate.max(1) as f64);
+                (started + self.read_timeout + allowed)
+                    .saturating_duration_since(now)
+                    .min(self.read_timeout)
+            }
+            ReadPhase::Body { min_rate: None, .. } | ReadPhase::Streaming => self.read_timeout,
+        }
+    }
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> SocketReader for ClientSocket<T> {
     async fn read_buffer(&mut self, buffer: &mut [u8]) -> Result<usize, ReadError> {
-        futures::select! {
+        let timeout = self.timeout(Instant::now());
+        let idle = matches!(self.phase, ReadPhase::Idle { .. });
+        let draining = self.draining.as_ref().filter(|_| idle);
+        let drained = async move {
+            match draining {
+                Some(draining) => {
+                    let _ = draining.recv().await;
+                }
+                None => futures::future::pending::<()>().await,
+            }
+        };
+        let result = futures::select! {
             read_result = self.socket.read(buffer).fuse() => {
                 match read_result {
                     Ok(size) => Ok(size),
                     Err(e) => Err(ReadError::IoError(e)),
                 }
             },
-            _ = smol::Timer::after(self.read_timeout).fuse() => {
+            _ = drained.fuse() => {
+                Err(ReadError::Cancellation)
+            },
+            _ = smol::Timer::after(timeout).fuse() => {
                 Err(ReadError::Timeout)
             },
             _ = self.cancellation_token.recv().fuse() => {
                 Err(ReadError::Cancellation)
             },
+        };
+        if let Ok(size) = result && size > 0 {
+            match &mut self.phase {
+                ReadPhase::Idle { header_timeout, .. } => {
+                    // Counted from the first byte, not from when the connection went idle.
+                    self.phase = ReadPhase::Headers { deadline: Instant::now() + *header_timeout };
+                }
+                ReadPhase::Body { read, .. } => *read += size,
+                ReadPhase::Headers { .. } | ReadPhase::Streaming => {}
+            }
         }
+        result
     }
 }
 
@@ -262,7 +337,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SocketWriter for ClientSocket<T> {
                     Err(e) => Err(WriteError::IoError(e)),
                 }
             },
-            _ = smol::Timer::after(self.read_timeout).fuse() => {
+            _ = smol::Timer::after(self.write_timeout).fuse() => {
                 Err(WriteError::Timeout)
             },
             _ = self.cancellation_token.recv().fuse() => {
@@ -270,4 +345,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SocketWriter for ClientSocket<T> {
             },
         }
     }
+
+    async fn cancelled(&mut self) {
+        let _ = self.cancellation_token.recv().await;
+    }
 }
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use event_listener::Event;
+use futures::FutureExt;
+
+use crate::logging::{info, warning};
+use crate::http_server::{ConnectionLimitMode, HttpServerConnectionConfig, ShutdownMode};
+use crate::listener::Peer;
+
+/// The shutdown asked for through a `ServerHandle`. Kept as a state that
+/// stops are re-checked against rather than a message, so an immediate
+/// shutdown still takes over a graceful one already under way.
+#[derive(Default)]
+pub(crate) struct ShutdownSignal {
+    /// `Some(None)` asks for the configured mode of each listener.
+    requested: Mutex<Option<Option<ShutdownMode>>>,
+    changed: Event,
+}
+
+impl ShutdownSignal {
+    /// Asks for a shutdown. Only an immediate one replaces an earlier request.
+    pub(crate) fn request(&self, mode: Option<ShutdownMode>) {
+        {
+            let mut requested = self.requested.lock().unwrap_or_else(|e| e.into_inner());
+            if requested.is_none() || matches!(mode, Some(ShutdownMode::Immediate)) {
+                *requested = Some(mode);
+            }
+        }
+        self.changed.notify(usize::MAX);
+    }
+
+    fn requested(&self) -> Option<Option<ShutdownMode>> {
+        *self.requested.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn wait_for<T>(&self, check: impl Fn(Option<Option<ShutdownMode>>) -> Option<T>) -> T {
+        loop {
+            if let Some(value) = check(self.requested()) {
+                return value;
+            }
+            let listener = self.changed.listen();
+            if let Some(value) = check(self.requested()) {
+                return value;
+            }
+            listener.await;
+        }
+    }
+
+    /// Resolves with the mode asked for once a shutdown is requested.
+    pub(crate) async fn requested_mode(&self) -> Option<ShutdownMode> {
+        self.wait_for(|requested| requested).await
+    }
+
+    /// Resolves once an immediate shutdown is requested.
+    pub(crate) async fn immediate(&self) {
+        self.wait_for(|requested| matches!(requested, Some(Some(ShutdownMode::Immediate))).then_some(()))
+            .await
+    }
+}
+
+/// The open connections of a listener and the signals telling them to stop.
+pub(crate) struct Connections {
+    limits: HttpServerConnectionConfig,
+    signal: Arc<ShutdownSignal>,
+    active: AtomicUsize,
+    accepted: AtomicUsize,
+    rejected: AtomicUsize,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    /// Notified whenever a connection closes.
+    released: Event,
+    /// Closed once the listener stops accepting: idle connections close, the
+    /// others once their current request is answered.
+    draining: (smol::channel::Sender<()>, smol::channel::Receiver<()>),
+    /// Closed when the remaining connections have to stop right away.
+    cancel: (smol::channel::Sender<()>, smol::channel::Receiver<()>),
+}
+
+impl Connections {
+    pub(crate) fn new(limits: HttpServerConnectionConfig, signal: Arc<ShutdownSignal>) -> Self {
+        Connections {
+            limits,
+            signal,
+            active: AtomicUsize::new(0),
+            accepted: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            per_ip: Mutex::new(HashMap::new()),
+            released: Event::new(),
+            draining: smol::channel::bounded(1),
+            cancel: smol::channel::bounded(1),
+        }
+    }
+
+    pub(crate) fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn accepted(&self) -> usize {
+        self.accepted.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Counts a new connection until the guard is dropped, or `None` when
+    /// the connection is over one of the limits and has to be turned away.
+    pub(crate) fn admit(self: &Arc<Self>, peer: &Peer) -> Option<ConnectionGuard> {
+        let full = self.limits.max_connections.is_some_and(|max| self.active() >= max);
+        let ip = match peer {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix(_) => None,
+        };
+        let admitted = !full && match (ip, self.limits.max_connections_per_ip) {
+            (Some(ip), Some(max)) => {
+                let mut per_ip = self.per_ip.lock().unwrap_or_else(|e| e.into_inner());
+                let count = per_ip.entry(ip).or_insert(0);
+                if *count < max {
+                    *count += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => true,
+        };
+        if !admitted {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.accepted.fetch_add(1, Ordering::SeqCst);
+        Some(ConnectionGuard {
+            connections: self.clone(),
+            ip: ip.filter(|_| self.limits.max_connections_per_ip.is_some()),
+        })
+    }
+
+    /// Resolves once another connection may be accepted. Always ready unless
+    /// the listener waits for a free slot when full.
+    pub(crate) async fn capacity(&self) {
+        let max = match self.limits {
+            HttpServerConnectionConfig {
+                max_connections: Some(max),
+                when_full: ConnectionLimitMode::Wait,
+                ..
+            } => max,
+            _ => return,
+        };
+        loop {
+            if self.active() < max {
+                return;
+            }
+            let listener = self.released.listen();
+            if self.active() < max {
+                return;
+            }
+            listener.await;
+        }
+    }
+
+    /// Resolves once the connections are told to stop.
+    pub(crate) fn cancellation_token(&self) -> smol::channel::Receiver<()> {
+        self.cancel.1.clone()
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            if self.active() == 0 {
+                return;
+            }
+            let listener = self.released.listen();
+            if self.active() == 0 {
+                return;
+            }
+            listener.await;
+        }
+    }
+
+    /// Stops the connections according to `mode` and waits for them to close.
+    pub(crate) async fn shutdown(&self, mode: ShutdownMode) {
+        match mode {
+            ShutdownMode::Immediate => {
+                info!("Shutting down server immediately.");
+            }
+            ShutdownMode::Graceful(timeout) => {
+                info!(timeout = ?timeout, "Shutting down server gracefully.");
+                self.draining.0.close();
+                futures::select! {
+                    _ = self.wait_idle().fuse() => {}
+                    _ = smol::Timer::after(timeout).fuse() => {
+                        warning!(remaining = self.active(), "Graceful shutdown period ended, cancelling the remaining connections.");
+                    }
+                    _ = self.signal.immediate().fuse() => {
+                        info!(remaining = self.active(), "Immediate shutdown requested, cancelling the remaining connections.");
+                    }
+                }
+            }
+        }
+        // Closing the channel wakes every connection, unlike a single message.
+        self.cancel.0.close();
+        self.wait_idle().await;
+    }
+}
+
+/// Keeps a connection counted as active, and tells it when to stop.
+pub(crate) struct ConnectionGuard {
+    connections: Arc<Connections>,
+    /// Set when the connection counts towards a per-IP cap.
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionGuard {
+    /// Closed once the server is draining.
+    pub(crate) fn draining(&self) -> smol::channel::Receiver<()> {
+        self.connections.draining.1.clone()
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.connections.draining.1.is_closed()
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            let mut per_ip = self.connections.per_ip.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(count) = per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(&ip);
+                }
+            }
+        }
+        self.connections.active.fetch_sub(1, Ordering::SeqCst);
+        self.connections.released.notify(usize::MAX);
+    }
+}
+
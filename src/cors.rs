@@ -0,0 +1,283 @@
+mod test;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::http_method::HttpMethod;
+use crate::middleware::{
+    MiddlewareEntry, MiddlewareHandler, MiddlewareLayer, MiddlewareResult, MiddlewareType,
+    PathParameter,
+};
+use crate::request::Request;
+use crate::response::Response;
+use crate::status_code::NO_CONTENT;
+
+#[derive(Clone)]
+pub enum AllowedOrigins {
+    /// `*`, or the request's origin when credentials are allowed.
+    Any,
+    List(Vec<String>),
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl AllowedOrigins {
+    fn allows(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigins::Any => true,
+            AllowedOrigins::List(origins) => origins.iter().any(|allowed| allowed == origin),
+            AllowedOrigins::Predicate(predicate) => predicate(origin),
+        }
+    }
+}
+
+/// Answers cross-origin requests and their preflights, e.g.
+/// `server.layer(Cors::new().allow_origin("https://app.example.com"))`.
+///
+/// Preflights skip the pre-request middlewares registered after the layer and
+/// are answered from the routes matching their path, so register it before
+/// authentication. The allowed methods are those of the routes, narrowed
+/// down by `allow_methods`. Like other post-request middlewares, the layer
+/// doesn't see responses sent by a pre-request middleware.
+#[derive(Clone)]
+pub struct Cors {
+    origins: AllowedOrigins,
+    methods: Option<Vec<String>>,
+    headers: Option<Vec<String>>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+    path: PathParameter,
+}
+
+impl Cors {
+    /// Allows no origin until one is added.
+    pub fn new() -> Self {
+        Cors {
+            origins: AllowedOrigins::List(vec![]),
+            methods: None,
+            headers: Some(vec![]),
+            expose_headers: vec![],
+            credentials: false,
+            max_age: None,
+            path: PathParameter::Wildcard,
+        }
+    }
+
+    pub fn allow_any_origin(mut self) -> Self {
+        self.origins = AllowedOrigins::Any;
+        self
+    }
+
+    /// Adds an origin such as `https://app.example.com`.
+    pub fn allow_origin<S: Into<String>>(mut self, origin: S) -> Self {
+        match &mut self.origins {
+            AllowedOrigins::List(origins) => origins.push(origin.into()),
+            origins => *origins = AllowedOrigins::List(vec![origin.into()]),
+        }
+        self
+    }
+
+    pub fn allow_origins<I: IntoIterator<Item = S>, S: Into<String>>(mut self, origins: I) -> Self {
+        for origin in origins {
+            self = self.allow_origin(origin);
+        }
+        self
+    }
+
+    /// Allows the origins `predicate` accepts, e.g. every subdomain.
+    pub fn allow_origin_fn<F: Fn(&str) -> bool + Send + Sync + 'static>(mut self, predicate: F) -> Self {
+        self.origins = AllowedOrigins::Predicate(Arc::new(predicate));
+        self
+    }
+
+    /// Restricts the methods allowed in preflights, all the methods of the
+    /// matching routes by default.
+    pub fn allow_methods<I: IntoIterator<Item = HttpMethod>>(mut self, methods: I) -> Self {
+        self.methods = Some(methods.into_iter().map(|method| format!("{:?}", method)).collect());
+        self
+    }
+
+    /// The request headers preflights may ask for, beyond the CORS-safelisted ones.
+    pub fn allow_headers<I: IntoIterator<Item = S>, S: Into<String>>(mut self, headers: I) -> Self {
+        self.headers = Some(headers.into_iter().map(|header| header.into().to_lowercase()).collect());
+        self
+    }
+
+    /// Allows whichever headers preflights ask for.
+    pub fn allow_any_header(mut self) -> Self {
+        self.headers = None;
+        self
+    }
+
+    /// The response headers scripts may read, beyond the CORS-safelisted ones.
+    pub fn expose_headers<I: IntoIterator<Item = S>, S: Into<String>>(mut self, headers: I) -> Self {
+        self.expose_headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Lets requests carry cookies and credentials.
+    pub fn allow_credentials(mut self, allow: bool) -> Self {
+        self.credentials = allow;
+        self
+    }
+
+    /// How long browsers may cache a preflight response.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Only handle requests whose path matches `path`.
+    pub fn path(mut self, path: PathParameter) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// The `Access-Control-Allow-Origin` value for `origin`, `None` when
+    /// it isn't allowed.
+    fn allow_origin_value(&self, origin: &str) -> Option<String> {
+        if !self.origins.allows(origin) {
+            return None;
+        }
+        match self.origins {
+            AllowedOrigins::Any if !self.credentials => Some("*".to_string()),
+            _ => Some(origin.to_string()),
+        }
+    }
+
+    pub(crate) fn apply(&self, request: &Request, response: &mut Response) {
+        if !matches!(self.origins, AllowedOrigins::Any) || self.credentials {
+            add_vary(response, "Origin");
+        }
+        let Some(origin) = request.headers.get_single("origin") else {
+            return;
+        };
+        let Some(allow_origin) = self.allow_origin_value(origin) else {
+            return;
+        };
+
+        if is_preflight(request) {
+            if !self.apply_preflight(request, response) {
+                return;
+            }
+        } else if !self.expose_headers.is_empty() {
+            set_header(response, "Access-Control-Expose-Headers", self.expose_headers.join(", "));
+        }
+        set_header(response, "Access-Control-Allow-Origin", allow_origin);
+        if self.credentials {
+            set_header(response, "Access-Control-Allow-Credentials", "true".to_string());
+        }
+    }
+
+    /// Turns the `Allow` answer to an OPTIONS request into a preflight
+    /// response. Returns `false` when the preflight isn't allowed.
+    fn apply_preflight(&self, request: &Request, response: &mut Response) -> bool {
+        let Some(allow) = response
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("allow"))
+            .map(|(_, value)| value.clone())
+        else {
+            return false;
+        };
+        let methods: Vec<&str> = allow
+            .split(',')
+            .map(str::trim)
+            .filter(|method| self.methods.as_ref().is_none_or(|allowed| allowed.iter().any(|m| m == method)))
+            .collect();
+        let requested_method = request.headers.get_single("access-control-request-method").map_or("", |m| m.trim());
+        if !methods.contains(&requested_method) {
+            return false;
+        }
+
+        let requested_headers: Vec<String> = request
+            .headers
+            .get_single("access-control-request-headers")
+            .map(|headers| {
+                headers
+                    .split(',')
+                    .map(|header| header.trim().to_lowercase())
+                    .filter(|header| !header.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(allowed) = &self.headers
+            && !requested_headers.iter().all(|header| allowed.contains(header))
+        {
+            return false;
+        }
+
+        response.status_code = NO_CONTENT;
+        response.bytes.clear();
+        set_header(response, "Access-Control-Allow-Methods", methods.join(", "));
+        if !requested_headers.is_empty() {
+            set_header(response, "Access-Control-Allow-Headers", requested_headers.join(", "));
+            if self.headers.is_none() {
+                add_vary(response, "Access-Control-Request-Headers");
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            set_header(response, "Access-Control-Max-Age", max_age.as_secs().to_string());
+        }
+        true
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MiddlewareLayer for Cors {
+    fn into_middlewares(self) -> Vec<MiddlewareEntry> {
+        let path = self.path.clone();
+        vec![
+            MiddlewareEntry {
+                middleware_type: MiddlewareType::PreRequest(path.clone()),
+                // Preflights carry no credentials, so the pre-request
+                // middlewares after this one (e.g. authentication) are skipped.
+                handler: MiddlewareHandler::PreRequest(Arc::new(|request: &mut Request| {
+                    if is_preflight(request) {
+                        MiddlewareResult::SkipMiddlewares
+                    } else {
+                        MiddlewareResult::NextMiddleware
+                    }
+                })),
+            },
+            MiddlewareEntry {
+                middleware_type: MiddlewareType::PostRequest(path),
+                handler: MiddlewareHandler::PostRequest(Arc::new(
+                    move |request: &Request, response: &mut Response| {
+                        self.apply(request, response);
+                        MiddlewareResult::NextMiddleware
+                    },
+                )),
+            },
+        ]
+    }
+}
+
+fn is_preflight(request: &Request) -> bool {
+    request.method == HttpMethod::OPTIONS
+        && request.headers.get_single("origin").is_some()
+        && request.headers.get_single("access-control-request-method").is_some()
+}
+
+fn set_header(response: &mut Response, name: &str, value: String) {
+    response.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    response.headers.push((name.to_string(), value));
+}
+
+/// Adds `value` to the `Vary` header unless it's already listed.
+fn add_vary(response: &mut Response, value: &str) {
+    match response.headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("vary")) {
+        Some((_, vary)) => {
+            if !vary.split(',').any(|v| v.trim().eq_ignore_ascii_case(value) || v.trim() == "*") {
+                vary.push_str(", ");
+                vary.push_str(value);
+            }
+        }
+        None => response.headers.push(("Vary".to_string(), value.to_string())),
+    }
+}
@@ -0,0 +1,91 @@
+#![cfg(test)]
+
+use std::time::Duration;
+
+use crate::cors::*;
+use crate::http_method::HttpMethod;
+use crate::map::{DuplicateMap, Map};
+use crate::request::Request;
+use crate::response::{Response, status, text};
+
+fn request(method: HttpMethod, headers: &[(&str, &str)]) -> Request {
+    let mut map: Map<DuplicateMap> = Map::default();
+    for (name, value) in headers {
+        map.add(name, value.to_string());
+    }
+    Request { method, headers: map, ..Default::default() }
+}
+
+/// The answer of the server's OPTIONS discovery.
+fn options_answer() -> Response {
+    status(200).header("Allow", "GET, OPTIONS, POST, PUT")
+}
+
+fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+}
+
+#[test]
+fn test_simple_request() {
+    let cors = Cors::new().allow_origin("https://app.example.com").expose_headers(["X-Total"]);
+
+    let mut res = text("ok");
+    cors.apply(&request(HttpMethod::GET, &[("origin", "https://app.example.com")]), &mut res);
+    assert_eq!(header(&res, "Access-Control-Allow-Origin"), Some("https://app.example.com"));
+    assert_eq!(header(&res, "Access-Control-Expose-Headers"), Some("X-Total"));
+    assert_eq!(header(&res, "Vary"), Some("Origin"));
+
+    let mut res = text("ok").header("Vary", "Accept-Encoding");
+    cors.apply(&request(HttpMethod::GET, &[("origin", "https://evil.example.com")]), &mut res);
+    assert_eq!(header(&res, "Access-Control-Allow-Origin"), None);
+    assert_eq!(header(&res, "Vary"), Some("Accept-Encoding, Origin"));
+}
+
+#[test]
+fn test_any_origin() {
+    let mut res = text("ok");
+    let req = request(HttpMethod::GET, &[("origin", "https://a.example.com")]);
+    Cors::new().allow_any_origin().apply(&req, &mut res);
+    assert_eq!(header(&res, "Access-Control-Allow-Origin"), Some("*"));
+    assert_eq!(header(&res, "Vary"), None);
+
+    // Credentials can't be combined with `*`.
+    let mut res = text("ok");
+    Cors::new().allow_any_origin().allow_credentials(true).apply(&req, &mut res);
+    assert_eq!(header(&res, "Access-Control-Allow-Origin"), Some("https://a.example.com"));
+    assert_eq!(header(&res, "Access-Control-Allow-Credentials"), Some("true"));
+    assert_eq!(header(&res, "Vary"), Some("Origin"));
+}
+
+#[test]
+fn test_preflight() {
+    let cors = Cors::new()
+        .allow_origin_fn(|origin| origin.ends_with(".example.com"))
+        .allow_methods([HttpMethod::GET, HttpMethod::PUT])
+        .allow_headers(["Content-Type"])
+        .max_age(Duration::from_secs(600));
+    let preflight = |method: &str, headers: &str| {
+        let mut res = options_answer();
+        let req = request(HttpMethod::OPTIONS, &[
+            ("origin", "https://app.example.com"),
+            ("access-control-request-method", method),
+            ("access-control-request-headers", headers),
+        ]);
+        cors.apply(&req, &mut res);
+        res
+    };
+
+    let res = preflight("PUT", "content-type");
+    assert_eq!(res.status_code.code, 204);
+    assert_eq!(header(&res, "Access-Control-Allow-Origin"), Some("https://app.example.com"));
+    assert_eq!(header(&res, "Access-Control-Allow-Methods"), Some("GET, PUT"));
+    assert_eq!(header(&res, "Access-Control-Allow-Headers"), Some("content-type"));
+    assert_eq!(header(&res, "Access-Control-Max-Age"), Some("600"));
+
+    // Not allowed by the layer, or not answered by any route.
+    for (method, headers) in [("POST", ""), ("DELETE", ""), ("PUT", "x-secret")] {
+        let res = preflight(method, headers);
+        assert_eq!(res.status_code.code, 200);
+        assert_eq!(header(&res, "Access-Control-Allow-Origin"), None);
+    }
+}
@@ -0,0 +1,97 @@
+mod test;
+
+use std::sync::Arc;
+
+use crate::request::Request;
+use crate::response::{Response, status, text};
+use crate::status_code::StatusCode;
+use crate::utils::json_string;
+
+pub type ErrorRenderHandler = Arc<dyn Fn(&ErrorContext) -> Response + Send + Sync>;
+
+/// An error answered by the server itself rather than a route handler.
+pub struct ErrorContext<'a> {
+    pub status: StatusCode,
+    /// `None` when the request couldn't be parsed.
+    pub request: Option<&'a Request>,
+    pub detail: Option<String>,
+}
+
+/// Builds the responses for the errors the server answers itself: requests
+/// it can't parse (`400`, `413`), unrouted requests (`404`, `405`), timeouts
+/// and panicking handlers, e.g.
+/// `server.error_renderer(ErrorRenderer::new().problem_json())`.
+///
+/// A handler registered for the status wins over the fallback, which wins
+/// over the default body: empty, or RFC 9457 `application/problem+json`.
+/// `405` responses always carry `Allow`, and the error-handler middlewares
+/// still run for requests that could be parsed.
+#[derive(Clone, Default)]
+pub struct ErrorRenderer {
+    problem_json: bool,
+    handlers: Vec<(u16, ErrorRenderHandler)>,
+    fallback: Option<ErrorRenderHandler>,
+}
+
+impl ErrorRenderer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers with `application/problem+json` bodies by default.
+    pub fn problem_json(mut self) -> Self {
+        self.problem_json = true;
+        self
+    }
+
+    /// Renders the errors with status `code`.
+    pub fn status<F>(mut self, code: u16, handler: F) -> Self
+    where
+        F: Fn(&ErrorContext) -> Response + Send + Sync + 'static,
+    {
+        self.handlers.push((code, Arc::new(handler)));
+        self
+    }
+
+    /// Renders the errors without a handler for their status.
+    pub fn fallback<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&ErrorContext) -> Response + Send + Sync + 'static,
+    {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    pub(crate) fn render(&self, context: &ErrorContext) -> Response {
+        let handler = self
+            .handlers
+            .iter()
+            .find(|(code, _)| *code == context.status.code)
+            .map(|(_, handler)| handler)
+            .or(self.fallback.as_ref());
+        match handler {
+            Some(handler) => handler(context),
+            None if self.problem_json => problem_json(context),
+            None => status(context.status),
+        }
+    }
+}
+
+/// An RFC 9457 problem details body for the error.
+pub fn problem_json(context: &ErrorContext) -> Response {
+    let mut body = format!(
+        "{{\"type\":\"about:blank\",\"title\":{},\"status\":{}",
+        json_string(context.status.reason),
+        context.status.code
+    );
+    if let Some(detail) = &context.detail {
+        body.push_str(&format!(",\"detail\":{}", json_string(detail)));
+    }
+    if let Some(request) = context.request {
+        body.push_str(&format!(",\"instance\":{}", json_string(&request.path)));
+    }
+    body.push('}');
+    text(body)
+        .status(context.status)
+        .content_type("application/problem+json")
+}
@@ -0,0 +1,42 @@
+#![cfg(test)]
+
+use crate::error_renderer::*;
+use crate::request::Request;
+use crate::response::text;
+use crate::status_code::{BAD_REQUEST, NOT_FOUND};
+
+#[test]
+fn test_default_renders_empty_body() {
+    let res = ErrorRenderer::new().render(&ErrorContext { status: NOT_FOUND, request: None, detail: None });
+    assert_eq!(res.status_code, NOT_FOUND);
+    assert!(res.bytes.is_empty());
+}
+
+#[test]
+fn test_problem_json() {
+    let request = Request { path: "/users/1".to_string(), ..Default::default() };
+    let res = ErrorRenderer::new().problem_json().render(&ErrorContext {
+        status: NOT_FOUND,
+        request: Some(&request),
+        detail: Some("No \"user\".".to_string()),
+    });
+    assert_eq!(res.content_type.to_string(), "application/problem+json");
+    assert_eq!(
+        String::from_utf8(res.bytes).unwrap(),
+        "{\"type\":\"about:blank\",\"title\":\"Not Found\",\"status\":404,\"detail\":\"No \\\"user\\\".\",\"instance\":\"/users/1\"}"
+    );
+}
+
+#[test]
+fn test_status_handler_wins_over_fallback() {
+    let renderer = ErrorRenderer::new()
+        .problem_json()
+        .status(404, |_| text("missing").status(404))
+        .fallback(|context| text(format!("error {}", context.status.code)).status(context.status));
+
+    let res = renderer.render(&ErrorContext { status: NOT_FOUND, request: None, detail: None });
+    assert_eq!(res.bytes, b"missing");
+    let res = renderer.render(&ErrorContext { status: BAD_REQUEST, request: None, detail: None });
+    assert_eq!(res.bytes, b"error 400");
+    assert_eq!(res.status_code, BAD_REQUEST);
+}
@@ -0,0 +1,81 @@
+mod test;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::request::Request;
+use crate::response::{Response, text};
+use crate::status_code::SERVICE_UNAVAILABLE;
+
+pub type ReadinessCheck = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// Liveness and readiness of a server, answered by `/healthz` and `/readyz`
+/// once mounted with `server.health_endpoints()`.
+///
+/// Readiness fails as soon as the server starts shutting down, so load
+/// balancers stop sending traffic while in-flight requests drain. With
+/// `HttpServer::drain_delay`, new connections are still accepted meanwhile.
+#[derive(Default)]
+pub struct Health {
+    checks: Mutex<Vec<(String, ReadinessCheck)>>,
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a check run on every readiness probe, e.g. pinging the
+    /// database. An `Err` makes the server report itself as not ready.
+    pub fn readiness_check<S, F>(&self, name: S, check: F)
+    where
+        S: Into<String>,
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.checks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((name.into(), Arc::new(check)));
+    }
+
+    pub(crate) fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Runs the readiness checks, returning the failures as `(name, error)`.
+    pub fn readiness(&self) -> Result<(), Vec<(String, String)>> {
+        if self.is_shutting_down() {
+            return Err(vec![("shutdown".to_string(), "server is shutting down".to_string())]);
+        }
+        // Cloned so a slow check doesn't hold up registering others.
+        let checks = self.checks.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let failures: Vec<(String, String)> = checks
+            .into_iter()
+            .filter_map(|(name, check)| check().err().map(|e| (name, e)))
+            .collect();
+        if failures.is_empty() { Ok(()) } else { Err(failures) }
+    }
+
+    /// Answers `200` as long as the server can answer at all.
+    pub fn liveness_handler(self: &Arc<Self>) -> impl Fn(Request) -> Response + Send + Sync + 'static {
+        |_| text("ok")
+    }
+
+    /// Answers `200` when every readiness check passes, `503` with the
+    /// failing checks otherwise.
+    pub fn readiness_handler(self: &Arc<Self>) -> impl Fn(Request) -> Response + Send + Sync + 'static {
+        let health = self.clone();
+        move |_| match health.readiness() {
+            Ok(()) => text("ok"),
+            Err(failures) => {
+                let body: Vec<String> = failures.iter().map(|(name, e)| format!("{name}: {e}")).collect();
+                text(body.join("\n")).status(SERVICE_UNAVAILABLE)
+            }
+        }
+    }
+}
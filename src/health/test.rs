@@ -0,0 +1,36 @@
+#![cfg(test)]
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::health::*;
+use crate::request::Request;
+
+#[test]
+fn test_readiness_checks() {
+    let health = Arc::new(Health::new());
+    let database_up = Arc::new(AtomicBool::new(true));
+    {
+        let database_up = database_up.clone();
+        health.readiness_check("database", move || {
+            if database_up.load(Ordering::SeqCst) { Ok(()) } else { Err("unreachable".to_string()) }
+        });
+    }
+    let handler = health.readiness_handler();
+    assert_eq!(handler(Request::default()).status_code.code, 200);
+
+    database_up.store(false, Ordering::SeqCst);
+    let res = handler(Request::default());
+    assert_eq!(res.status_code.code, 503);
+    assert_eq!(res.bytes, b"database: unreachable");
+}
+
+#[test]
+fn test_readiness_fails_once_shutting_down() {
+    let health = Arc::new(Health::new());
+    assert!(health.readiness().is_ok());
+    health.begin_shutdown();
+    assert!(health.is_shutting_down());
+    assert_eq!(health.readiness_handler()(Request::default()).status_code.code, 503);
+    assert_eq!(health.liveness_handler()(Request::default()).status_code.code, 200);
+}
@@ -0,0 +1,1189 @@
+//! HTTP/2 connections (RFC 9113), dispatching into the same routes and
+//! middlewares as HTTP/1.1.
+//!
+//! Each connection runs a reader, which parses frames and runs middlewares,
+//! and a writer, which owns the socket's write half and enforces the peer's
+//! flow control windows. They talk through a channel of [`Command`]s, which
+//! event streams also use to push their DATA frames. Route handlers run on
+//! the blocking thread pool, so a slow one doesn't hold up the other streams.
+
+mod frame;
+mod hpack;
+mod huffman;
+mod test;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt, StreamExt};
+
+use crate::logging::{debug, RequestSpan};
+use crate::http2::frame::*;
+use crate::http_method::{HttpMethod, parse_method};
+use crate::http_server::{HttpServerConfig, HttpServerTimeoutConfig, ServerState};
+use crate::http_version::HttpVersion;
+use crate::listener::ConnectionInfo;
+use crate::map::{DuplicateMap, Map};
+use crate::middleware::{run_post_request, run_pre_request};
+use crate::request::{Request, RequestParsingError, header_can_be_duplicate, is_valid_header_name, is_valid_header_value, parse_query_params};
+use crate::response::{Response, status};
+use crate::sse::EventStream;
+use crate::status_code::{
+    StatusCode, BAD_REQUEST, PAYLOAD_TOO_LARGE, REQUEST_HEADER_FIELDS_TOO_LARGE, REQUEST_TIMEOUT,
+    SWITCHING_PROTOCOLS,
+};
+
+pub const ALPN_PROTOCOL: &[u8] = b"h2";
+
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+/// Commands the reader and event streams may queue for the writer before
+/// they wait for it.
+const COMMAND_QUEUE_SIZE: usize = 64;
+
+/// Headers that only make sense for a single HTTP/1.1 hop.
+const CONNECTION_SPECIFIC_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Work for the writer half of a connection.
+pub(crate) enum Command {
+    /// An already encoded frame that isn't subject to flow control.
+    Frame(Vec<u8>),
+    Headers { stream_id: u32, headers: Vec<(String, String)>, end_stream: bool },
+    Data { stream_id: u32, data: Vec<u8>, end_stream: bool },
+    WindowUpdate { stream_id: u32, increment: u32 },
+    InitialWindowSize(u32),
+    MaxFrameSize(u32),
+    /// Drops whatever is still queued for a stream the peer reset.
+    Reset(u32),
+    Close,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Http2Error {
+    /// Ends the connection with a GOAWAY carrying the error code.
+    Connection(u32),
+    /// Resets a single stream with RST_STREAM.
+    Stream(u32, u32),
+}
+
+enum InvalidRequest {
+    /// Malformed per RFC 9113 section 8.1.1, answered with a stream error.
+    Malformed,
+    Rejected(StatusCode),
+}
+
+/// What woke up the reader.
+enum Next {
+    Frame(FrameHeader, Vec<u8>),
+    /// A handler finished with the response for a stream.
+    Response(Box<(u32, Request, Response)>),
+    Cancelled,
+    Idle,
+    /// A stream took too long to send its request.
+    StreamTimeout,
+}
+
+/// A stream whose request hasn't been fully received yet.
+struct PendingStream {
+    header_block: Vec<u8>,
+    headers: Option<Vec<(String, String)>>,
+    body: Vec<u8>,
+    end_stream: bool,
+    refused: bool,
+    /// When its first HEADERS frame arrived.
+    opened: Instant,
+    /// When the header block was complete and the body started.
+    body_started: Option<Instant>,
+    /// When the last DATA frame arrived.
+    last_read: Instant,
+}
+
+impl PendingStream {
+    fn new(header_block: Vec<u8>, end_stream: bool, refused: bool) -> Self {
+        let now = Instant::now();
+        PendingStream {
+            header_block,
+            headers: None,
+            body: vec![],
+            end_stream,
+            refused,
+            opened: now,
+            body_started: None,
+            last_read: now,
+        }
+    }
+
+    /// When the request has to be complete by, with the same limits as
+    /// HTTP/1.1: `header_timeout` for the header block, then `read_timeout`
+    /// between DATA frames and the minimum body rate.
+    fn deadline(&self, timeouts: &HttpServerTimeoutConfig) -> Instant {
+        let Some(started) = self.body_started else {
+            return self.opened + timeouts.header_timeout_duration;
+        };
+        let deadline = self.last_read + timeouts.read_timeout_duration;
+        match timeouts.body_min_bytes_per_second {
+            Some(min_rate) => {
+                let allowed = Duration::from_secs_f64(self.body.len() as f64 / min_rate.max(1) as f64);
+                deadline.min(started + timeouts.read_timeout_duration + allowed)
+            }
+            None => deadline,
+        }
+    }
+}
+
+/// Reads whole frames, keeping bytes that arrived past the current frame.
+/// Reading is cancel-safe: nothing is lost if a read future is dropped.
+struct FrameReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    /// Returns `None` if the peer closed the connection between frames.
+    async fn read_exact(&mut self, length: usize) -> std::io::Result<Option<Vec<u8>>> {
+        let mut chunk = [0u8; READ_BUFFER_SIZE];
+        while self.buffer.len() < length {
+            let read = self.reader.read(&mut chunk).await?;
+            if read == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Connection closed in the middle of a frame.",
+                ));
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+        Ok(Some(self.buffer.drain(..length).collect()))
+    }
+
+    /// Reads the next frame. The payload of an oversized frame is left
+    /// unread, the caller closes the connection anyway.
+    async fn read_frame(&mut self, max_frame_size: u32) -> std::io::Result<Option<(FrameHeader, Vec<u8>)>> {
+        let header = match self.read_exact(FRAME_HEADER_SIZE).await? {
+            Some(bytes) => {
+                let bytes: [u8; FRAME_HEADER_SIZE] = bytes.try_into().expect("read exactly one frame header");
+                parse_frame_header(&bytes)
+            }
+            None => return Ok(None),
+        };
+        if header.length > max_frame_size {
+            return Ok(Some((header, Vec::new())));
+        }
+        match self.read_exact(header.length as usize).await? {
+            Some(payload) => Ok(Some((header, payload))),
+            None if header.length == 0 => Ok(Some((header, Vec::new()))),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Connection closed in the middle of a frame.",
+            )),
+        }
+    }
+}
+
+/// Data waiting for flow control window.
+struct PendingData {
+    stream_id: u32,
+    data: Vec<u8>,
+    offset: usize,
+    end_stream: bool,
+}
+
+/// The writer's view of the connection: send windows and queued data.
+pub(crate) struct Outbound {
+    connection_window: i64,
+    initial_window: i64,
+    max_frame_size: usize,
+    windows: HashMap<u32, i64>,
+    pending: VecDeque<PendingData>,
+    last_stream_id: u32,
+}
+
+impl Outbound {
+    pub(crate) fn new() -> Self {
+        Outbound {
+            connection_window: DEFAULT_WINDOW_SIZE as i64,
+            initial_window: DEFAULT_WINDOW_SIZE as i64,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE as usize,
+            windows: HashMap::new(),
+            pending: VecDeque::new(),
+            last_stream_id: 0,
+        }
+    }
+
+    /// Applies a command, appending any frames it produces to `output`.
+    /// Returns `Ok(true)` once the connection should close, or the error
+    /// code of a connection error.
+    pub(crate) fn apply(&mut self, command: Command, output: &mut Vec<u8>) -> Result<bool, u32> {
+        match command {
+            Command::Frame(frame) => output.extend_from_slice(&frame),
+            Command::Headers { stream_id, headers, end_stream } => {
+                self.last_stream_id = self.last_stream_id.max(stream_id);
+                if end_stream {
+                    self.windows.remove(&stream_id);
+                } else {
+                    self.windows.entry(stream_id).or_insert(self.initial_window);
+                }
+                let block = hpack::encode(&headers);
+                let mut chunks = block.chunks(self.max_frame_size).peekable();
+                let mut frame_type = HEADERS;
+                let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+                if chunks.peek().is_none() {
+                    output.extend_from_slice(&encode_frame(frame_type, flags | FLAG_END_HEADERS, stream_id, &[]));
+                }
+                while let Some(chunk) = chunks.next() {
+                    if chunks.peek().is_none() {
+                        flags |= FLAG_END_HEADERS;
+                    }
+                    output.extend_from_slice(&encode_frame(frame_type, flags, stream_id, chunk));
+                    frame_type = CONTINUATION;
+                    flags = 0;
+                }
+            }
+            Command::Data { stream_id, data, end_stream } => {
+                // The stream was reset or already ended.
+                if !self.windows.contains_key(&stream_id) || (data.is_empty() && !end_stream) {
+                    return Ok(false);
+                }
+                self.pending.push_back(PendingData { stream_id, data, offset: 0, end_stream });
+            }
+            Command::WindowUpdate { stream_id: 0, increment } => {
+                self.connection_window += increment as i64;
+                if self.connection_window > MAX_WINDOW_SIZE as i64 {
+                    return Err(FLOW_CONTROL_ERROR);
+                }
+            }
+            Command::WindowUpdate { stream_id, increment } => {
+                if let Some(window) = self.windows.get_mut(&stream_id) {
+                    *window += increment as i64;
+                    if *window > MAX_WINDOW_SIZE as i64 {
+                        self.reset(stream_id);
+                        output.extend_from_slice(&rst_stream(stream_id, FLOW_CONTROL_ERROR));
+                    }
+                }
+            }
+            Command::InitialWindowSize(size) => {
+                let delta = size as i64 - self.initial_window;
+                self.initial_window = size as i64;
+                for window in self.windows.values_mut() {
+                    *window += delta;
+                    if *window > MAX_WINDOW_SIZE as i64 {
+                        return Err(FLOW_CONTROL_ERROR);
+                    }
+                }
+            }
+            Command::MaxFrameSize(size) => self.max_frame_size = size as usize,
+            Command::Reset(stream_id) => self.reset(stream_id),
+            Command::Close => return Ok(true),
+        }
+        Ok(false)
+    }
+
+    /// Streams whose response hasn't been written in full, waiting for
+    /// flow control window.
+    pub(crate) fn queued_streams(&self) -> usize {
+        self.pending.iter().map(|pending| pending.stream_id).collect::<HashSet<_>>().len()
+    }
+
+    fn reset(&mut self, stream_id: u32) {
+        self.windows.remove(&stream_id);
+        self.pending.retain(|pending| pending.stream_id != stream_id);
+    }
+
+    /// Appends as much queued data as the windows allow, keeping the order
+    /// of data within each stream.
+    pub(crate) fn flush(&mut self, output: &mut Vec<u8>) {
+        let mut blocked = HashSet::new();
+        let mut index = 0;
+        while index < self.pending.len() {
+            let item = &mut self.pending[index];
+            if blocked.contains(&item.stream_id) {
+                index += 1;
+                continue;
+            }
+            let window = self.windows.entry(item.stream_id).or_insert(self.initial_window);
+            loop {
+                let remaining = item.data.len() - item.offset;
+                let size = remaining
+                    .min(self.max_frame_size)
+                    .min((*window).max(0) as usize)
+                    .min(self.connection_window.max(0) as usize);
+                if size == 0 && remaining > 0 {
+                    break;
+                }
+                let chunk = &item.data[item.offset..item.offset + size];
+                item.offset += size;
+                *window -= size as i64;
+                self.connection_window -= size as i64;
+                let finished = item.offset == item.data.len();
+                let flags = if finished && item.end_stream { FLAG_END_STREAM } else { 0 };
+                output.extend_from_slice(&encode_frame(DATA, flags, item.stream_id, chunk));
+                if finished {
+                    break;
+                }
+            }
+
+            if item.offset == item.data.len() {
+                if item.end_stream {
+                    self.windows.remove(&item.stream_id);
+                }
+                self.pending.remove(index);
+            } else {
+                blocked.insert(item.stream_id);
+                index += 1;
+            }
+        }
+    }
+}
+
+async fn write_with_timeout<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8], timeout: Duration) -> std::io::Result<()> {
+    let write = async {
+        writer.write_all(bytes).await?;
+        writer.flush().await
+    };
+    futures::select! {
+        result = write.fuse() => result,
+        _ = FutureExt::fuse(smol::Timer::after(timeout)) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "Write timeout.",
+        )),
+    }
+}
+
+/// Writes what the commands produce. `queued` is kept up to date with the
+/// streams whose response is still waiting for flow control window.
+async fn write_loop<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    commands: smol::channel::Receiver<Command>,
+    write_timeout: Duration,
+    queued: Arc<AtomicUsize>,
+) -> std::io::Result<()> {
+    let mut outbound = Outbound::new();
+    let mut result = Ok(());
+    while let Ok(command) = commands.recv().await {
+        let mut output = Vec::new();
+        let mut close = false;
+        // Batch everything that is already queued into a single write.
+        let mut next = Some(command);
+        while let Some(command) = next {
+            match outbound.apply(command, &mut output) {
+                Ok(false) => {}
+                Ok(true) => close = true,
+                Err(error_code) => {
+                    output.extend_from_slice(&goaway(outbound.last_stream_id, error_code));
+                    close = true;
+                }
+            }
+            if close {
+                break;
+            }
+            next = commands.try_recv().ok();
+        }
+        outbound.flush(&mut output);
+        queued.store(outbound.queued_streams(), Ordering::Relaxed);
+
+        if !output.is_empty()
+            && let Err(e) = write_with_timeout(&mut writer, &output, write_timeout).await
+        {
+            result = Err(e);
+            break;
+        }
+        if close {
+            break;
+        }
+    }
+    let _ = writer.close().await;
+    result
+}
+
+struct Connection<'a> {
+    state: &'a ServerState,
+    config: HttpServerConfig,
+    commands: smol::channel::Sender<Command>,
+    /// Commands waiting to be handed to the writer by `flush`.
+    outgoing: Vec<Command>,
+    /// Streams whose response the writer still holds, see `write_loop`.
+    queued: Arc<AtomicUsize>,
+    decoder: hpack::Decoder,
+    streams: HashMap<u32, PendingStream>,
+    /// Streams still sending events; dropping the sender stops the stream.
+    event_streams: HashMap<u32, smol::channel::Sender<()>>,
+    /// Requests being answered by their handler.
+    handlers: FuturesUnordered<BoxFuture<'a, (u32, Request, Response)>>,
+    /// The streams of `handlers` that weren't reset meanwhile.
+    in_flight: HashSet<u32>,
+    /// The stream whose header block is waiting for CONTINUATION frames.
+    continuation: Option<u32>,
+    last_stream_id: u32,
+    settings_received: bool,
+    /// Set once the peer has sent GOAWAY: the streams it opened before are
+    /// still answered, later ones are refused.
+    peer_going_away: bool,
+    /// Set once the server has sent GOAWAY while draining; later streams
+    /// are refused.
+    going_away: bool,
+    /// Streams opened so far, for `max_requests_per_connection`.
+    requests: usize,
+    connection: ConnectionInfo,
+}
+
+impl<'a> Connection<'a> {
+    fn send(&mut self, command: Command) {
+        self.outgoing.push(command);
+    }
+
+    /// Hands the queued commands to the writer, waiting while it's behind
+    /// so a peer that doesn't read can't make the server buffer more.
+    async fn flush(&mut self) {
+        for command in self.outgoing.drain(..) {
+            // The writer only goes away together with the connection.
+            if self.commands.send(command).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    fn is_idle(&mut self) -> bool {
+        self.event_streams.retain(|_, closed| !closed.is_closed());
+        self.streams.is_empty() && self.event_streams.is_empty() && self.handlers.is_empty()
+    }
+
+    /// Streams counted against MAX_CONCURRENT_STREAMS: until their response
+    /// has been written in full, not just handed to the writer.
+    fn open_streams(&mut self) -> usize {
+        self.event_streams.retain(|_, closed| !closed.is_closed());
+        self.streams.len() + self.event_streams.len() + self.handlers.len() + self.queued.load(Ordering::Relaxed)
+    }
+
+    async fn run<R: AsyncRead + Unpin>(
+        &mut self,
+        mut reader: FrameReader<R>,
+        cancellation_token: smol::channel::Receiver<()>,
+        writer_done: smol::channel::Receiver<()>,
+        upgrade: Option<Upgrade>,
+    ) -> std::io::Result<()> {
+        self.send(Command::Frame(settings_frame(&[
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, self.config.size_config.request_header_max_size as u32),
+        ])));
+
+        // The request that asked for the upgrade is answered on stream 1. The
+        // 101 response already acknowledged the settings sent along with it.
+        if let Some(upgrade) = upgrade {
+            if let Err(Http2Error::Connection(error_code)) = self.apply_settings(&upgrade.settings) {
+                self.send(Command::Frame(goaway(0, error_code)));
+                self.send(Command::Close);
+                self.flush().await;
+                return Ok(());
+            }
+            self.last_stream_id = 1;
+            self.handle_request(1, upgrade.request);
+        }
+
+        let read_timeout = self.config.timeout_config.read_timeout_duration;
+        let result = async {
+            self.flush().await;
+            let preface = futures::select! {
+                preface = reader.read_exact(CONNECTION_PREFACE.len()).fuse() => preface?,
+                _ = FutureExt::fuse(smol::Timer::after(read_timeout)) => None,
+            };
+            if preface.as_deref() != Some(CONNECTION_PREFACE) {
+                self.send(Command::Frame(goaway(0, PROTOCOL_ERROR)));
+                return Ok(());
+            }
+
+            loop {
+                self.flush().await;
+                if self.connection.is_draining() && !self.going_away {
+                    self.going_away = true;
+                    self.send(Command::Frame(goaway(self.last_stream_id, NO_ERROR)));
+                }
+                if (self.going_away || self.peer_going_away) && self.is_idle() {
+                    return Ok(());
+                }
+                let idle_timer = if self.is_idle() {
+                    smol::Timer::after(self.config.timeout_config.keep_alive_timeout_duration)
+                } else {
+                    smol::Timer::never()
+                };
+                let stream_timer = match self.streams.values().map(|stream| stream.deadline(&self.config.timeout_config)).min() {
+                    Some(deadline) => smol::Timer::at(deadline),
+                    None => smol::Timer::never(),
+                };
+                let drained = if self.going_away {
+                    futures::future::Either::Left(futures::future::pending::<()>())
+                } else {
+                    futures::future::Either::Right(self.connection.drained())
+                };
+                let next = futures::select! {
+                    frame = reader.read_frame(DEFAULT_MAX_FRAME_SIZE).fuse() => match frame? {
+                        Some((header, payload)) => Next::Frame(header, payload),
+                        None => return Ok(()),
+                    },
+                    response = self.handlers.select_next_some() => Next::Response(Box::new(response)),
+                    _ = cancellation_token.recv().fuse() => Next::Cancelled,
+                    _ = writer_done.recv().fuse() => return Ok(()),
+                    _ = drained.fuse() => continue,
+                    _ = FutureExt::fuse(idle_timer) => Next::Idle,
+                    _ = FutureExt::fuse(stream_timer) => Next::StreamTimeout,
+                };
+                match next {
+                    Next::Frame(header, payload) => match self.handle_frame(header, payload) {
+                        Ok(()) => {}
+                        Err(Http2Error::Stream(stream_id, error_code)) => self.reset(stream_id, error_code),
+                        Err(Http2Error::Connection(error_code)) => {
+                            self.send(Command::Frame(goaway(self.last_stream_id, error_code)));
+                            return Ok(());
+                        }
+                    },
+                    Next::Response(response) => {
+                        let (stream_id, req, res) = *response;
+                        // Dropped if the stream was reset meanwhile.
+                        if self.in_flight.remove(&stream_id) {
+                            self.send_response(stream_id, &req, res);
+                        }
+                    }
+                    Next::Cancelled => {
+                        debug!(peer = %self.connection.peer, "HTTP/2 connection cancelled.");
+                        self.send(Command::Frame(goaway(self.last_stream_id, NO_ERROR)));
+                        return Ok(());
+                    }
+                    Next::Idle => {
+                        self.send(Command::Frame(goaway(self.last_stream_id, NO_ERROR)));
+                        return Ok(());
+                    }
+                    Next::StreamTimeout => {
+                        if let Err(Http2Error::Connection(error_code)) = self.expire_streams() {
+                            self.send(Command::Frame(goaway(self.last_stream_id, error_code)));
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+        .await;
+
+        self.send(Command::Close);
+        self.flush().await;
+        result
+    }
+
+    /// Answers the streams past their deadline with `408 Request Timeout`.
+    fn expire_streams(&mut self) -> Result<(), Http2Error> {
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.deadline(&self.config.timeout_config) <= now)
+            .map(|(stream_id, _)| *stream_id)
+            .collect();
+        for stream_id in expired {
+            self.streams.remove(&stream_id);
+            self.state.metrics.record_parse_error(&RequestParsingError::Timeout);
+            let res = self.state.error_response(REQUEST_TIMEOUT, None, None);
+            self.send_simple_response(stream_id, res);
+            if self.continuation == Some(stream_id) {
+                // The rest of the header block can't be skipped without
+                // breaking HPACK state.
+                return Err(Http2Error::Connection(NO_ERROR));
+            }
+            // Tell the client to stop sending the rest of the body.
+            self.send(Command::Frame(rst_stream(stream_id, NO_ERROR)));
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, stream_id: u32, error_code: u32) {
+        self.streams.remove(&stream_id);
+        self.event_streams.remove(&stream_id);
+        self.in_flight.remove(&stream_id);
+        self.send(Command::Reset(stream_id));
+        self.send(Command::Frame(rst_stream(stream_id, error_code)));
+    }
+
+    fn handle_frame(&mut self, header: FrameHeader, payload: Vec<u8>) -> Result<(), Http2Error> {
+        if header.length > DEFAULT_MAX_FRAME_SIZE {
+            return Err(Http2Error::Connection(FRAME_SIZE_ERROR));
+        }
+        if !self.settings_received && header.frame_type != SETTINGS {
+            return Err(Http2Error::Connection(PROTOCOL_ERROR));
+        }
+        if let Some(stream_id) = self.continuation
+            && (header.frame_type != CONTINUATION || header.stream_id != stream_id)
+        {
+            return Err(Http2Error::Connection(PROTOCOL_ERROR));
+        }
+
+        match header.frame_type {
+            DATA => self.on_data(header, &payload),
+            HEADERS => self.on_headers(header, &payload),
+            CONTINUATION => self.on_continuation(header, &payload),
+            SETTINGS => self.on_settings(header, &payload),
+            PRIORITY => {
+                if header.stream_id == 0 {
+                    return Err(Http2Error::Connection(PROTOCOL_ERROR));
+                }
+                if payload.len() != 5 {
+                    return Err(Http2Error::Stream(header.stream_id, FRAME_SIZE_ERROR));
+                }
+                Ok(())
+            }
+            RST_STREAM => {
+                if header.stream_id == 0 || header.stream_id > self.last_stream_id {
+                    return Err(Http2Error::Connection(PROTOCOL_ERROR));
+                }
+                if payload.len() != 4 {
+                    return Err(Http2Error::Connection(FRAME_SIZE_ERROR));
+                }
+                self.streams.remove(&header.stream_id);
+                self.event_streams.remove(&header.stream_id);
+                self.in_flight.remove(&header.stream_id);
+                self.send(Command::Reset(header.stream_id));
+                Ok(())
+            }
+            PING => {
+                if header.stream_id != 0 {
+                    return Err(Http2Error::Connection(PROTOCOL_ERROR));
+                }
+                if payload.len() != 8 {
+                    return Err(Http2Error::Connection(FRAME_SIZE_ERROR));
+                }
+                if !header.has_flag(FLAG_ACK) {
+                    self.send(Command::Frame(ping_ack(&payload)));
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                if header.stream_id != 0 {
+                    return Err(Http2Error::Connection(PROTOCOL_ERROR));
+                }
+                self.peer_going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => {
+                if payload.len() != 4 {
+                    return Err(Http2Error::Connection(FRAME_SIZE_ERROR));
+                }
+                let increment = read_u32(&payload) & MAX_WINDOW_SIZE;
+                if increment == 0 {
+                    return Err(match header.stream_id {
+                        0 => Http2Error::Connection(PROTOCOL_ERROR),
+                        stream_id => Http2Error::Stream(stream_id, PROTOCOL_ERROR),
+                    });
+                }
+                self.send(Command::WindowUpdate { stream_id: header.stream_id, increment });
+                Ok(())
+            }
+            PUSH_PROMISE => Err(Http2Error::Connection(PROTOCOL_ERROR)),
+            // Unknown frame types must be ignored.
+            _ => Ok(()),
+        }
+    }
+
+    fn on_settings(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), Http2Error> {
+        if header.stream_id != 0 {
+            return Err(Http2Error::Connection(PROTOCOL_ERROR));
+        }
+        if header.has_flag(FLAG_ACK) {
+            if !payload.is_empty() {
+                return Err(Http2Error::Connection(FRAME_SIZE_ERROR));
+            }
+            return Ok(());
+        }
+        if !payload.len().is_multiple_of(6) {
+            return Err(Http2Error::Connection(FRAME_SIZE_ERROR));
+        }
+        self.settings_received = true;
+        self.apply_settings(payload)?;
+        self.send(Command::Frame(settings_ack()));
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), Http2Error> {
+        for (id, value) in parse_settings(payload) {
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(Http2Error::Connection(PROTOCOL_ERROR)),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(Http2Error::Connection(FLOW_CONTROL_ERROR));
+                    }
+                    self.send(Command::InitialWindowSize(value));
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_ALLOWED_FRAME_SIZE).contains(&value) {
+                        return Err(Http2Error::Connection(PROTOCOL_ERROR));
+                    }
+                    self.send(Command::MaxFrameSize(value));
+                }
+                // The encoder doesn't use the dynamic table, so the
+                // header table size doesn't matter; others are advisory.
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), Http2Error> {
+        let stream_id = header.stream_id;
+        if stream_id == 0 || stream_id.is_multiple_of(2) {
+            return Err(Http2Error::Connection(PROTOCOL_ERROR));
+        }
+        let mut block = strip_padding(&header, payload).ok_or(Http2Error::Connection(PROTOCOL_ERROR))?;
+        if header.has_flag(FLAG_PRIORITY) {
+            if block.len() < 5 {
+                return Err(Http2Error::Connection(FRAME_SIZE_ERROR));
+            }
+            block = &block[5..];
+        }
+        let end_stream = header.has_flag(FLAG_END_STREAM);
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            // Trailers after the request body.
+            stream.header_block = block.to_vec();
+            stream.end_stream = end_stream;
+        } else {
+            if stream_id <= self.last_stream_id {
+                return Err(Http2Error::Connection(STREAM_CLOSED));
+            }
+            self.last_stream_id = stream_id;
+            let refused = self.going_away || self.peer_going_away || self.open_streams() >= MAX_CONCURRENT_STREAMS as usize;
+            self.streams.insert(stream_id, PendingStream::new(block.to_vec(), end_stream, refused));
+            if !refused {
+                self.requests += 1;
+                let max_requests = self.config.connection_config.max_requests_per_connection;
+                if max_requests.is_some_and(|max| self.requests >= max) {
+                    // Answer the streams opened so far, then close.
+                    self.going_away = true;
+                    self.send(Command::Frame(goaway(stream_id, NO_ERROR)));
+                }
+            }
+        }
+
+        if header.has_flag(FLAG_END_HEADERS) {
+            self.finish_headers(stream_id)
+        } else {
+            self.continuation = Some(stream_id);
+            Ok(())
+        }
+    }
+
+    fn on_continuation(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), Http2Error> {
+        if self.continuation != Some(header.stream_id) {
+            return Err(Http2Error::Connection(PROTOCOL_ERROR));
+        }
+        let max_size = self.config.size_config.request_header_max_size;
+        let stream = self
+            .streams
+            .get_mut(&header.stream_id)
+            .ok_or(Http2Error::Connection(PROTOCOL_ERROR))?;
+        stream.header_block.extend_from_slice(payload);
+        // The block can't be skipped without breaking HPACK state, so an
+        // endless header block ends the connection.
+        if stream.header_block.len() > max_size {
+            return Err(Http2Error::Connection(ENHANCE_YOUR_CALM));
+        }
+        if header.has_flag(FLAG_END_HEADERS) {
+            self.finish_headers(header.stream_id)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish_headers(&mut self, stream_id: u32) -> Result<(), Http2Error> {
+        self.continuation = None;
+        let stream = self
+            .streams
+            .get_mut(&stream_id)
+            .ok_or(Http2Error::Connection(PROTOCOL_ERROR))?;
+        let block = std::mem::take(&mut stream.header_block);
+        let max_size = self.config.size_config.request_header_max_size;
+        let headers = match self.decoder.decode(&block, max_size) {
+            Ok(headers) => Some(headers),
+            Err(hpack::HpackError::HeaderListTooLarge) => None,
+            Err(_) => return Err(Http2Error::Connection(COMPRESSION_ERROR)),
+        };
+
+        if stream.refused {
+            return Err(Http2Error::Stream(stream_id, REFUSED_STREAM));
+        }
+        let Some(headers) = headers else {
+            let end_stream = stream.end_stream;
+            self.streams.remove(&stream_id);
+            self.state.metrics.record_parse_error(&RequestParsingError::PayloadTooLarge);
+            let res = self.state.error_response(REQUEST_HEADER_FIELDS_TOO_LARGE, None, None);
+            self.send_simple_response(stream_id, res);
+            if !end_stream {
+                // Tell the client to stop sending the body.
+                self.send(Command::Frame(rst_stream(stream_id, NO_ERROR)));
+            }
+            return Ok(());
+        };
+        if stream.headers.is_none() {
+            stream.headers = Some(headers);
+            stream.body_started = Some(Instant::now());
+            stream.last_read = Instant::now();
+        } else if !stream.end_stream {
+            // Trailers have to end the stream; their fields are not used.
+            return Err(Http2Error::Stream(stream_id, PROTOCOL_ERROR));
+        }
+
+        if stream.end_stream {
+            return self.dispatch(stream_id);
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), Http2Error> {
+        let stream_id = header.stream_id;
+        if stream_id == 0 {
+            return Err(Http2Error::Connection(PROTOCOL_ERROR));
+        }
+        // Received data is consumed right away, so the whole frame
+        // (padding included) is given back to the peer.
+        if !payload.is_empty() {
+            self.send(Command::Frame(window_update(0, payload.len() as u32)));
+        }
+
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) if stream.headers.is_some() => stream,
+            _ if stream_id > self.last_stream_id => return Err(Http2Error::Connection(PROTOCOL_ERROR)),
+            _ => return Err(Http2Error::Stream(stream_id, STREAM_CLOSED)),
+        };
+        let data = strip_padding(&header, payload).ok_or(Http2Error::Connection(PROTOCOL_ERROR))?;
+        stream.body.extend_from_slice(data);
+        stream.last_read = Instant::now();
+
+        if stream.body.len() > self.config.size_config.request_body_max_size {
+            self.streams.remove(&stream_id);
+            self.state.metrics.record_parse_error(&RequestParsingError::PayloadTooLarge);
+            let res = self.state.error_response(PAYLOAD_TOO_LARGE, None, None);
+            self.send_simple_response(stream_id, res);
+            // Tell the client to stop sending the rest of the body.
+            self.send(Command::Frame(rst_stream(stream_id, NO_ERROR)));
+            return Ok(());
+        }
+
+        if header.has_flag(FLAG_END_STREAM) {
+            stream.end_stream = true;
+            return self.dispatch(stream_id);
+        }
+        if !payload.is_empty() {
+            self.send(Command::Frame(window_update(stream_id, payload.len() as u32)));
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, stream_id: u32) -> Result<(), Http2Error> {
+        let stream = match self.streams.remove(&stream_id) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let headers = stream.headers.unwrap_or_default();
+        match build_request(headers, stream.body) {
+            Ok(mut request) => {
+                self.connection.apply(&mut request);
+                self.handle_request(stream_id, request);
+                Ok(())
+            }
+            Err(InvalidRequest::Malformed) => {
+                self.state.metrics.record_parse_error(&RequestParsingError::InvalidRequest);
+                Err(Http2Error::Stream(stream_id, PROTOCOL_ERROR))
+            }
+            Err(InvalidRequest::Rejected(status_code)) => {
+                self.state.metrics.record_parse_error(&RequestParsingError::InvalidRequest);
+                let res = self.state.error_response(status_code, None, None);
+                self.send_simple_response(stream_id, res);
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs the pre-request middlewares, then hands the request to its
+    /// handler; the response comes back through `handlers`.
+    fn handle_request(&mut self, stream_id: u32, mut req: Request) {
+        let in_flight = self.state.metrics.request_started();
+        if let Some(mut res) = run_pre_request(&self.state.middlewares, &mut req) {
+            self.state.finish_response(&req, &mut res);
+            self.send_response(stream_id, &req, res);
+            return;
+        }
+
+        let state = self.state;
+        let timeout = self.config.timeout_config.handler_timeout_duration;
+        let span = RequestSpan::new(&req);
+        let respond = span.instrument(async move {
+            let _in_flight = in_flight;
+            debug!(method = ?req.method, path = %req.path, stream_id = stream_id, "Request received.");
+            let (kept_request, dispatched) = state.dispatch_unblocked(req, timeout).await;
+            let mut res = state.dispatched_response(&kept_request, dispatched, timeout);
+            run_post_request(&state.middlewares, &kept_request, &mut res);
+            state.finish_response(&kept_request, &mut res);
+            (stream_id, kept_request, res)
+        });
+        self.in_flight.insert(stream_id);
+        self.handlers.push(respond.boxed());
+    }
+
+    fn send_simple_response(&mut self, stream_id: u32, res: Response) {
+        let mut headers = vec![(":status".to_string(), res.status_code.code.to_string())];
+        if !res.bytes.is_empty() {
+            headers.push(("content-type".to_string(), res.content_type.to_string()));
+        }
+        headers.push(("content-length".to_string(), res.bytes.len().to_string()));
+        push_response_headers(&mut headers, &res);
+        self.send_headers_and_body(stream_id, headers, res.bytes);
+    }
+
+    fn send_response(&mut self, stream_id: u32, req: &Request, mut res: Response) {
+        if let Some(stream) = res.event_stream.take() {
+            self.send_event_stream(stream_id, res, stream);
+            return;
+        }
+
+        let mut headers = vec![(":status".to_string(), res.status_code.code.to_string())];
+        headers.push(("content-type".to_string(), res.content_type.to_string()));
+        headers.push(("content-length".to_string(), res.bytes.len().to_string()));
+        push_response_headers(&mut headers, &res);
+
+        if req.method == HttpMethod::HEAD {
+            res.bytes.clear();
+        }
+        self.send_headers_and_body(stream_id, headers, res.bytes);
+    }
+
+    fn send_headers_and_body(&mut self, stream_id: u32, headers: Vec<(String, String)>, body: Vec<u8>) {
+        let end_stream = body.is_empty();
+        self.send(Command::Headers { stream_id, headers, end_stream });
+        if !end_stream {
+            self.send(Command::Data { stream_id, data: body, end_stream: true });
+        }
+    }
+
+    /// Sends the response headers, then spawns a task that forwards events as
+    /// DATA frames until every sender is dropped or the stream goes away.
+    fn send_event_stream(&mut self, stream_id: u32, res: Response, stream: EventStream) {
+        let mut headers = vec![
+            (":status".to_string(), res.status_code.code.to_string()),
+            ("content-type".to_string(), res.content_type.to_string()),
+            ("cache-control".to_string(), "no-cache".to_string()),
+        ];
+        push_response_headers(&mut headers, &res);
+        self.send(Command::Headers { stream_id, headers, end_stream: false });
+
+        let (closed_tx, closed_rx) = smol::channel::bounded::<()>(1);
+        self.event_streams.insert(stream_id, closed_tx);
+        let commands = self.commands.clone();
+        smol::spawn(async move {
+            loop {
+                let data = futures::select! {
+                    event = stream.receiver.recv().fuse() => match event {
+                        Ok(event) => event.to_bytes(),
+                        Err(_) => break,
+                    },
+                    _ = FutureExt::fuse(smol::Timer::after(stream.keep_alive)) => b": keep-alive\n\n".to_vec(),
+                    _ = closed_rx.recv().fuse() => {
+                        stream.receiver.close();
+                        return;
+                    }
+                };
+                if commands.send(Command::Data { stream_id, data, end_stream: false }).await.is_err() {
+                    break;
+                }
+            }
+            stream.receiver.close();
+            let _ = commands.send(Command::Data { stream_id, data: Vec::new(), end_stream: true }).await;
+        })
+        .detach();
+    }
+}
+
+fn push_response_headers(headers: &mut Vec<(String, String)>, res: &Response) {
+    for (key, value) in &res.headers {
+        let key = key.to_lowercase();
+        if CONNECTION_SPECIFIC_HEADERS.contains(&key.as_str()) {
+            continue;
+        }
+        headers.push((key, value.clone()));
+    }
+}
+
+/// Builds a `Request` from a decoded header list, applying the HTTP/2
+/// rules for pseudo-headers and connection-specific fields.
+fn build_request(headers: Vec<(String, String)>, body: Vec<u8>) -> Result<Request, InvalidRequest> {
+    let mut method = None;
+    let mut scheme = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut header_map: Map<DuplicateMap> = Map::default();
+    let mut cookies = vec![];
+    let mut regular_seen = false;
+
+    for (name, value) in headers {
+        if let Some(pseudo) = name.strip_prefix(':') {
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return Err(InvalidRequest::Malformed),
+            };
+            if regular_seen || slot.is_some() {
+                return Err(InvalidRequest::Malformed);
+            }
+            *slot = Some(value);
+            continue;
+        }
+
+        regular_seen = true;
+        if name.bytes().any(|b| b.is_ascii_uppercase())
+            || !is_valid_header_name(&name)
+            || !is_valid_header_value(&value)
+            || CONNECTION_SPECIFIC_HEADERS.contains(&name.as_str())
+            || (name == "te" && value != "trailers")
+        {
+            return Err(InvalidRequest::Malformed);
+        }
+        // Cookies may be split into several fields to compress better.
+        if name == "cookie" {
+            cookies.push(value);
+        } else if header_can_be_duplicate(&name) {
+            header_map.add(&name, value);
+        } else if header_map.add_require_single(&name, value).is_err() {
+            return Err(InvalidRequest::Rejected(BAD_REQUEST));
+        }
+    }
+    if !cookies.is_empty() {
+        header_map.add("cookie", cookies.join("; "));
+    }
+
+    let (method, path) = match (method, path, scheme) {
+        (Some(method), Some(path), Some(_)) if !path.is_empty() => (method, path),
+        _ => return Err(InvalidRequest::Malformed),
+    };
+    if !header_map.has("host")
+        && let Some(authority) = authority
+    {
+        header_map.add("host", authority);
+    }
+    if let Some(length) = header_map.get_single("content-length")
+        && length.parse::<usize>() != Ok(body.len())
+    {
+        return Err(InvalidRequest::Malformed);
+    }
+    let method = parse_method(&method).ok_or(InvalidRequest::Rejected(BAD_REQUEST))?;
+    let query_params = parse_query_params(&path);
+
+    Ok(Request {
+        method,
+        http_version: HttpVersion::Http2,
+        body,
+        path,
+        query_params,
+        headers: header_map,
+        ..Default::default()
+    })
+}
+
+/// An HTTP/1.1 request that switched the connection to h2c.
+pub(crate) struct Upgrade {
+    pub request: Request,
+    /// The decoded `HTTP2-Settings` header, a SETTINGS frame payload.
+    pub settings: Vec<u8>,
+}
+
+/// Whether the bytes read so far are the start of the HTTP/2 connection
+/// preface, i.e. a client using cleartext HTTP/2 with prior knowledge.
+pub(crate) fn is_connection_preface(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"PRI * HTTP/2.0\r\n\r\n")
+}
+
+fn has_token(req: &Request, header: &str, token: &str) -> bool {
+    req.headers.get(header).is_some_and(|values| {
+        values
+            .as_slice()
+            .iter()
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// Checks for an `Upgrade: h2c` request (RFC 7540, section 3.2) and returns
+/// the decoded `HTTP2-Settings` if the connection can be upgraded.
+pub(crate) fn h2c_upgrade_settings(req: &Request) -> Option<Vec<u8>> {
+    if req.http_version != HttpVersion::Http1_1
+        || !has_token(req, "upgrade", "h2c")
+        || !has_token(req, "connection", "upgrade")
+        || !has_token(req, "connection", "http2-settings")
+    {
+        return None;
+    }
+    let settings = req.headers.get_require_single("http2-settings").ok()??;
+    let settings = URL_SAFE_NO_PAD.decode(settings.trim_end_matches('=')).ok()?;
+    if !settings.len().is_multiple_of(6) {
+        return None;
+    }
+    Some(settings)
+}
+
+pub(crate) fn h2c_upgrade_response() -> Response {
+    status(SWITCHING_PROTOCOLS)
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "h2c")
+}
+
+/// Serves an HTTP/2 connection until the peer goes away, an error occurs or
+/// the server is cancelled. `buffered` holds bytes already read from the
+/// stream, starting with (part of) the connection preface.
+pub(crate) async fn serve_connection<T: AsyncRead + AsyncWrite + Unpin>(
+    state: &ServerState,
+    config: HttpServerConfig,
+    stream: T,
+    cancellation_token: smol::channel::Receiver<()>,
+    buffered: Vec<u8>,
+    upgrade: Option<Upgrade>,
+    connection: ConnectionInfo,
+) -> std::io::Result<()> {
+    let (reader, writer) = stream.split();
+    let (commands, command_receiver) = smol::channel::bounded(COMMAND_QUEUE_SIZE);
+    let (writer_done_tx, writer_done) = smol::channel::bounded::<()>(1);
+    let queued = Arc::new(AtomicUsize::new(0));
+
+    let writer_queued = queued.clone();
+    let write_side = async move {
+        let write_timeout = config.timeout_config.write_timeout_duration;
+        let result = write_loop(writer, command_receiver, write_timeout, writer_queued).await;
+        drop(writer_done_tx);
+        result
+    };
+
+    let mut connection = Connection {
+        state,
+        config,
+        commands,
+        outgoing: Vec::new(),
+        queued,
+        decoder: hpack::Decoder::new(hpack::DEFAULT_TABLE_SIZE),
+        streams: HashMap::new(),
+        event_streams: HashMap::new(),
+        handlers: FuturesUnordered::new(),
+        in_flight: HashSet::new(),
+        continuation: None,
+        last_stream_id: 0,
+        settings_received: false,
+        peer_going_away: false,
+        going_away: false,
+        requests: 0,
+        connection,
+    };
+    let read_side = connection.run(FrameReader { reader, buffer: buffered }, cancellation_token, writer_done, upgrade);
+
+    let (read_result, write_result) = futures::join!(read_side, write_side);
+    read_result.and(write_result)
+}
@@ -0,0 +1,138 @@
+//! HTTP/2 frame layout (RFC 9113, section 4 and 6).
+
+pub(crate) const CONNECTION_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub(crate) const FRAME_HEADER_SIZE: usize = 9;
+
+pub(crate) const DEFAULT_WINDOW_SIZE: u32 = 65_535;
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+pub(crate) const MAX_ALLOWED_FRAME_SIZE: u32 = 16_777_215;
+pub(crate) const MAX_WINDOW_SIZE: u32 = 0x7fff_ffff;
+
+pub(crate) const DATA: u8 = 0x0;
+pub(crate) const HEADERS: u8 = 0x1;
+pub(crate) const PRIORITY: u8 = 0x2;
+pub(crate) const RST_STREAM: u8 = 0x3;
+pub(crate) const SETTINGS: u8 = 0x4;
+pub(crate) const PUSH_PROMISE: u8 = 0x5;
+pub(crate) const PING: u8 = 0x6;
+pub(crate) const GOAWAY: u8 = 0x7;
+pub(crate) const WINDOW_UPDATE: u8 = 0x8;
+pub(crate) const CONTINUATION: u8 = 0x9;
+
+pub(crate) const FLAG_END_STREAM: u8 = 0x1;
+pub(crate) const FLAG_ACK: u8 = 0x1;
+pub(crate) const FLAG_END_HEADERS: u8 = 0x4;
+pub(crate) const FLAG_PADDED: u8 = 0x8;
+pub(crate) const FLAG_PRIORITY: u8 = 0x20;
+
+pub(crate) const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub(crate) const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub(crate) const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub(crate) const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub(crate) const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+pub(crate) const NO_ERROR: u32 = 0x0;
+pub(crate) const PROTOCOL_ERROR: u32 = 0x1;
+pub(crate) const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub(crate) const STREAM_CLOSED: u32 = 0x5;
+pub(crate) const FRAME_SIZE_ERROR: u32 = 0x6;
+pub(crate) const REFUSED_STREAM: u32 = 0x7;
+pub(crate) const COMPRESSION_ERROR: u32 = 0x9;
+pub(crate) const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FrameHeader {
+    pub length: u32,
+    pub frame_type: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+}
+
+impl FrameHeader {
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag == flag
+    }
+}
+
+pub(crate) fn parse_frame_header(bytes: &[u8; FRAME_HEADER_SIZE]) -> FrameHeader {
+    FrameHeader {
+        length: u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]),
+        frame_type: bytes[3],
+        flags: bytes[4],
+        // The reserved bit must be ignored when receiving.
+        stream_id: u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]) & MAX_WINDOW_SIZE,
+    }
+}
+
+pub(crate) fn encode_frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let length = (payload.len() as u32).to_be_bytes();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&length[1..]);
+    frame.push(frame_type);
+    frame.push(flags);
+    frame.extend_from_slice(&(stream_id & MAX_WINDOW_SIZE).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub(crate) fn settings_frame(settings: &[(u16, u32)]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(settings.len() * 6);
+    for (id, value) in settings {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    encode_frame(SETTINGS, 0, 0, &payload)
+}
+
+pub(crate) fn parse_settings(payload: &[u8]) -> Vec<(u16, u32)> {
+    payload
+        .chunks_exact(6)
+        .map(|setting| {
+            (
+                u16::from_be_bytes([setting[0], setting[1]]),
+                u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]),
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn settings_ack() -> Vec<u8> {
+    encode_frame(SETTINGS, FLAG_ACK, 0, &[])
+}
+
+pub(crate) fn ping_ack(data: &[u8]) -> Vec<u8> {
+    encode_frame(PING, FLAG_ACK, 0, data)
+}
+
+pub(crate) fn goaway(last_stream_id: u32, error_code: u32) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(8);
+    payload.extend_from_slice(&(last_stream_id & MAX_WINDOW_SIZE).to_be_bytes());
+    payload.extend_from_slice(&error_code.to_be_bytes());
+    encode_frame(GOAWAY, 0, 0, &payload)
+}
+
+pub(crate) fn rst_stream(stream_id: u32, error_code: u32) -> Vec<u8> {
+    encode_frame(RST_STREAM, 0, stream_id, &error_code.to_be_bytes())
+}
+
+pub(crate) fn window_update(stream_id: u32, increment: u32) -> Vec<u8> {
+    encode_frame(WINDOW_UPDATE, 0, stream_id, &(increment & MAX_WINDOW_SIZE).to_be_bytes())
+}
+
+pub(crate) fn read_u32(payload: &[u8]) -> u32 {
+    u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+}
+
+/// Removes the padding of a `PADDED` DATA or HEADERS frame. Returns `None`
+/// when the pad length doesn't fit in the payload.
+pub(crate) fn strip_padding<'a>(header: &FrameHeader, payload: &'a [u8]) -> Option<&'a [u8]> {
+    if !header.has_flag(FLAG_PADDED) {
+        return Some(payload);
+    }
+    let (&pad_length, rest) = payload.split_first()?;
+    let pad_length = pad_length as usize;
+    if pad_length > rest.len() {
+        return None;
+    }
+    Some(&rest[..rest.len() - pad_length])
+}
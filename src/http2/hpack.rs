@@ -0,0 +1,284 @@
+//! HPACK header compression (RFC 7541).
+//!
+//! The decoder implements the full format including the dynamic table. The
+//! encoder never indexes, so the peer's table stays empty and the encoder
+//! doesn't need to track `SETTINGS_HEADER_TABLE_SIZE`.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+
+use crate::http2::huffman;
+
+pub(crate) const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// Per-entry overhead used when accounting for the dynamic table size.
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum HpackError {
+    Truncated,
+    IntegerOverflow,
+    InvalidIndex,
+    InvalidHuffman,
+    InvalidTableSizeUpdate,
+    /// The header list is larger than the limit passed to `decode`.
+    HeaderListTooLarge,
+}
+
+pub(crate) struct Decoder {
+    table: VecDeque<(String, String)>,
+    table_size: usize,
+    max_table_size: usize,
+    /// The `SETTINGS_HEADER_TABLE_SIZE` we advertised, an upper bound for updates.
+    allowed_table_size: usize,
+}
+
+impl Decoder {
+    pub fn new(allowed_table_size: usize) -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            table_size: 0,
+            max_table_size: allowed_table_size,
+            allowed_table_size,
+        }
+    }
+
+    /// Decodes a complete header block, failing with `HeaderListTooLarge`
+    /// once the list gets larger than `max_list_size`, counted like
+    /// `SETTINGS_MAX_HEADER_LIST_SIZE`. The whole block has to be decoded
+    /// even for rejected requests, or the dynamic table gets out of sync,
+    /// but the fields past the limit aren't kept.
+    pub fn decode(&mut self, mut block: &[u8], max_list_size: usize) -> Result<Vec<(String, String)>, HpackError> {
+        let mut headers = Vec::new();
+        let mut fields = 0;
+        let mut list_size = 0;
+        while let Some(&first) = block.first() {
+            let (name, value) = if first & 0x80 != 0 {
+                let index = decode_integer(&mut block, 7)?;
+                let (name, value) = self.entry(index)?;
+                (Cow::Borrowed(name), Cow::Borrowed(value))
+            } else if first & 0x40 != 0 {
+                let (name, value) = self.decode_literal(&mut block, 6)?;
+                self.insert(name.clone(), value.clone());
+                (Cow::Owned(name), Cow::Owned(value))
+            } else if first & 0x20 != 0 {
+                // Size updates are only allowed at the start of a block.
+                if fields > 0 {
+                    return Err(HpackError::InvalidTableSizeUpdate);
+                }
+                let size = decode_integer(&mut block, 5)?;
+                if size > self.allowed_table_size {
+                    return Err(HpackError::InvalidTableSizeUpdate);
+                }
+                self.max_table_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // Literal without indexing (0000) or never indexed (0001).
+                let (name, value) = self.decode_literal(&mut block, 4)?;
+                (Cow::Owned(name), Cow::Owned(value))
+            };
+            fields += 1;
+            list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+            if list_size <= max_list_size {
+                headers.push((name.into_owned(), value.into_owned()));
+            }
+        }
+        if list_size > max_list_size {
+            return Err(HpackError::HeaderListTooLarge);
+        }
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> Result<(&str, &str), HpackError> {
+        if index == 0 {
+            return Err(HpackError::InvalidIndex);
+        }
+        if index <= STATIC_TABLE.len() {
+            return Ok(STATIC_TABLE[index - 1]);
+        }
+        self.table
+            .get(index - STATIC_TABLE.len() - 1)
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .ok_or(HpackError::InvalidIndex)
+    }
+
+    fn decode_literal(&self, block: &mut &[u8], prefix: u8) -> Result<(String, String), HpackError> {
+        let index = decode_integer(block, prefix)?;
+        let name = if index == 0 {
+            decode_string(block)?
+        } else {
+            self.entry(index)?.0.to_string()
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let size = name.len() + value.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // An entry larger than the whole table just empties it.
+        if size <= self.max_table_size {
+            self.table_size += size;
+            self.table.push_front((name, value));
+        }
+    }
+
+    /// Evicts the oldest entries until `incoming` more bytes fit.
+    fn evict(&mut self, incoming: usize) {
+        while self.table_size + incoming > self.max_table_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.table_size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+pub(crate) fn decode_integer(block: &mut &[u8], prefix: u8) -> Result<usize, HpackError> {
+    let (&first, mut rest) = block.split_first().ok_or(HpackError::Truncated)?;
+    let max_prefix = (1usize << prefix) - 1;
+    let mut value = first as usize & max_prefix;
+    if value == max_prefix {
+        let mut shift = 0;
+        loop {
+            let (&byte, remaining) = rest.split_first().ok_or(HpackError::Truncated)?;
+            rest = remaining;
+            if shift > 28 {
+                return Err(HpackError::IntegerOverflow);
+            }
+            value += ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    *block = rest;
+    Ok(value)
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<String, HpackError> {
+    let huffman_encoded = block.first().ok_or(HpackError::Truncated)? & 0x80 != 0;
+    let length = decode_integer(block, 7)?;
+    if length > block.len() {
+        return Err(HpackError::Truncated);
+    }
+    let (raw, rest) = block.split_at(length);
+    *block = rest;
+    let bytes = if huffman_encoded {
+        huffman::decode(raw).ok_or(HpackError::InvalidHuffman)?
+    } else {
+        raw.to_vec()
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+pub(crate) fn encode_integer(output: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max_prefix = (1usize << prefix) - 1;
+    if value < max_prefix {
+        output.push(flags | value as u8);
+        return;
+    }
+    output.push(flags | max_prefix as u8);
+    value -= max_prefix;
+    while value >= 0x80 {
+        output.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn encode_string(output: &mut Vec<u8>, value: &str) {
+    let bytes = value.as_bytes();
+    if huffman::encoded_len(bytes) < bytes.len() {
+        encode_integer(output, 0x80, 7, huffman::encoded_len(bytes));
+        output.extend_from_slice(&huffman::encode(bytes));
+    } else {
+        encode_integer(output, 0, 7, bytes.len());
+        output.extend_from_slice(bytes);
+    }
+}
+
+/// Encodes a header list, using the static table where possible and literals
+/// without indexing otherwise. Names must already be lowercase.
+pub(crate) fn encode(headers: &[(String, String)]) -> Vec<u8> {
+    let mut output = Vec::new();
+    for (name, value) in headers {
+        if let Some(index) = STATIC_TABLE.iter().position(|(n, v)| n == name && v == value) {
+            encode_integer(&mut output, 0x80, 7, index + 1);
+        } else if let Some(index) = STATIC_TABLE.iter().position(|(n, _)| n == name) {
+            encode_integer(&mut output, 0x00, 4, index + 1);
+            encode_string(&mut output, value);
+        } else {
+            output.push(0x00);
+            encode_string(&mut output, name);
+            encode_string(&mut output, value);
+        }
+    }
+    output
+}
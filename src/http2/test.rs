@@ -0,0 +1,270 @@
+#![cfg(test)]
+
+use crate::http2::frame::*;
+use crate::http2::hpack::{Decoder, HpackError, decode_integer, encode, encode_integer};
+use crate::http2::huffman;
+use crate::http2::{Command, Outbound, h2c_upgrade_settings, is_connection_preface};
+use crate::map::{DuplicateMap, Map};
+use crate::request::Request;
+
+fn hex(input: &str) -> Vec<u8> {
+    let digits: Vec<char> = input.chars().filter(|c| !c.is_whitespace()).collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).unwrap())
+        .collect()
+}
+
+fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+    headers.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn test_integer_encoding() {
+    // RFC 7541, C.1
+    let mut output = vec![];
+    encode_integer(&mut output, 0, 5, 10);
+    encode_integer(&mut output, 0, 5, 1337);
+    encode_integer(&mut output, 0, 8, 42);
+    assert_eq!(output, vec![0x0a, 0x1f, 0x9a, 0x0a, 0x2a]);
+
+    let mut input = output.as_slice();
+    assert_eq!(decode_integer(&mut input, 5), Ok(10));
+    assert_eq!(decode_integer(&mut input, 5), Ok(1337));
+    assert_eq!(decode_integer(&mut input, 8), Ok(42));
+    assert!(input.is_empty());
+}
+
+#[test]
+fn test_decode_requests_without_huffman() {
+    // RFC 7541, C.3
+    let mut decoder = Decoder::new(4096);
+    let first = decoder.decode(&hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d"), usize::MAX).unwrap();
+    assert_eq!(
+        first,
+        pairs(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")])
+    );
+
+    let second = decoder.decode(&hex("8286 84be 5808 6e6f 2d63 6163 6865"), usize::MAX).unwrap();
+    assert_eq!(second[3], (":authority".to_string(), "www.example.com".to_string()));
+    assert_eq!(second[4], ("cache-control".to_string(), "no-cache".to_string()));
+
+    let third = decoder
+        .decode(&hex("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65"), usize::MAX)
+        .unwrap();
+    assert_eq!(
+        third,
+        pairs(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ])
+    );
+}
+
+#[test]
+fn test_decode_requests_with_huffman() {
+    // RFC 7541, C.4
+    let mut decoder = Decoder::new(4096);
+    let first = decoder.decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"), usize::MAX).unwrap();
+    assert_eq!(first[3], (":authority".to_string(), "www.example.com".to_string()));
+
+    let second = decoder.decode(&hex("8286 84be 5886 a8eb 1064 9cbf"), usize::MAX).unwrap();
+    assert_eq!(second[4], ("cache-control".to_string(), "no-cache".to_string()));
+
+    let third = decoder
+        .decode(&hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"), usize::MAX)
+        .unwrap();
+    assert_eq!(third[4], ("custom-key".to_string(), "custom-value".to_string()));
+}
+
+#[test]
+fn test_decode_rejects_invalid_blocks() {
+    let mut decoder = Decoder::new(4096);
+    assert!(decoder.decode(&[0x80], usize::MAX).is_err());
+    assert!(decoder.decode(&[0xbe], usize::MAX).is_err());
+    assert!(decoder.decode(&hex("410f 7777"), usize::MAX).is_err());
+    // Table size update above the advertised limit.
+    assert!(decoder.decode(&hex("3fe2 1f"), usize::MAX).is_err());
+}
+
+#[test]
+fn test_decode_limits_header_list_size() {
+    // Adds a 4000-byte value to the dynamic table, then refers to it 1000 times.
+    let mut block = vec![0x40, 0x01, b'x'];
+    encode_integer(&mut block, 0, 7, 4000);
+    block.extend_from_slice(&[b'a'; 4000]);
+    block.extend_from_slice(&[0xbe; 1000]);
+    let mut decoder = Decoder::new(4096);
+    assert_eq!(decoder.decode(&block, 8192), Err(HpackError::HeaderListTooLarge));
+
+    // The table still got the entry.
+    let headers = decoder.decode(&[0xbe], 8192).unwrap();
+    assert_eq!(headers, vec![("x".to_string(), "a".repeat(4000))]);
+}
+
+#[test]
+fn test_huffman_roundtrip() {
+    let input = b"www.example.com";
+    let encoded = huffman::encode(input);
+    assert_eq!(encoded, hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff"));
+    assert_eq!(huffman::decode(&encoded).unwrap(), input);
+
+    let all_bytes: Vec<u8> = (0..=255).collect();
+    assert_eq!(huffman::decode(&huffman::encode(&all_bytes)).unwrap(), all_bytes);
+    // Padding has to be a prefix of EOS.
+    assert!(huffman::decode(&[0x00]).is_none());
+}
+
+#[test]
+fn test_encode_roundtrip() {
+    let headers = pairs(&[
+        (":status", "200"),
+        ("content-type", "text/plain"),
+        ("x-custom", "value"),
+        (":status", "418"),
+    ]);
+    let mut decoder = Decoder::new(4096);
+    assert_eq!(decoder.decode(&encode(&headers), usize::MAX).unwrap(), headers);
+}
+
+#[test]
+fn test_frame_header_roundtrip() {
+    let frame = encode_frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 3, b"abc");
+    let header = parse_frame_header(frame[..FRAME_HEADER_SIZE].try_into().unwrap());
+    assert_eq!(
+        header,
+        FrameHeader {
+            length: 3,
+            frame_type: HEADERS,
+            flags: FLAG_END_HEADERS | FLAG_END_STREAM,
+            stream_id: 3,
+        }
+    );
+    assert!(header.has_flag(FLAG_END_STREAM));
+    assert_eq!(&frame[FRAME_HEADER_SIZE..], b"abc");
+}
+
+#[test]
+fn test_strip_padding() {
+    let header = FrameHeader {
+        length: 5,
+        frame_type: DATA,
+        flags: FLAG_PADDED,
+        stream_id: 1,
+    };
+    assert_eq!(strip_padding(&header, &[2, b'h', b'i', 0, 0]), Some(&b"hi"[..]));
+    assert_eq!(strip_padding(&header, &[5, b'h', b'i']), None);
+}
+
+fn data_frames(output: &[u8]) -> Vec<(u32, usize, bool)> {
+    let mut frames = vec![];
+    let mut rest = output;
+    while !rest.is_empty() {
+        let header = parse_frame_header(rest[..FRAME_HEADER_SIZE].try_into().unwrap());
+        if header.frame_type == DATA {
+            frames.push((header.stream_id, header.length as usize, header.has_flag(FLAG_END_STREAM)));
+        }
+        rest = &rest[FRAME_HEADER_SIZE + header.length as usize..];
+    }
+    frames
+}
+
+#[test]
+fn test_flow_control_window() {
+    let mut outbound = Outbound::new();
+    let mut output = vec![];
+    outbound.apply(Command::InitialWindowSize(10), &mut output).unwrap();
+    let headers = pairs(&[(":status", "200")]);
+    outbound
+        .apply(Command::Headers { stream_id: 1, headers, end_stream: false }, &mut output)
+        .unwrap();
+    outbound
+        .apply(Command::Data { stream_id: 1, data: vec![0; 25], end_stream: true }, &mut output)
+        .unwrap();
+    output.clear();
+    outbound.flush(&mut output);
+    assert_eq!(data_frames(&output), vec![(1, 10, false)]);
+    assert_eq!(outbound.queued_streams(), 1);
+
+    output.clear();
+    outbound
+        .apply(Command::WindowUpdate { stream_id: 1, increment: 100 }, &mut output)
+        .unwrap();
+    outbound.flush(&mut output);
+    assert_eq!(data_frames(&output), vec![(1, 15, true)]);
+    assert_eq!(outbound.queued_streams(), 0);
+}
+
+#[test]
+fn test_flow_control_overflow() {
+    let mut outbound = Outbound::new();
+    let mut output = vec![];
+    let result = outbound.apply(Command::WindowUpdate { stream_id: 0, increment: MAX_WINDOW_SIZE }, &mut output);
+    assert_eq!(result, Err(FLOW_CONTROL_ERROR));
+}
+
+#[test]
+fn test_reset_drops_pending_data() {
+    let mut outbound = Outbound::new();
+    let mut output = vec![];
+    outbound.apply(Command::InitialWindowSize(0), &mut output).unwrap();
+    let headers = pairs(&[(":status", "200")]);
+    outbound
+        .apply(Command::Headers { stream_id: 1, headers, end_stream: false }, &mut output)
+        .unwrap();
+    outbound
+        .apply(Command::Data { stream_id: 1, data: vec![0; 5], end_stream: true }, &mut output)
+        .unwrap();
+    outbound.apply(Command::Reset(1), &mut output).unwrap();
+    outbound
+        .apply(Command::WindowUpdate { stream_id: 1, increment: 100 }, &mut output)
+        .unwrap();
+    output.clear();
+    outbound.flush(&mut output);
+    assert!(output.is_empty());
+}
+
+fn upgrade_request(headers: &[(&str, &str)]) -> Request {
+    let mut header_map: Map<DuplicateMap> = Map::default();
+    for (name, value) in headers {
+        header_map.add(name, value.to_string());
+    }
+    Request {
+        headers: header_map,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_h2c_upgrade_settings() {
+    let request = upgrade_request(&[
+        ("connection", "Upgrade, HTTP2-Settings"),
+        ("upgrade", "h2c"),
+        ("http2-settings", "AAMAAABkAAQAoAAAAAIAAAAA"),
+    ]);
+    let settings = h2c_upgrade_settings(&request).unwrap();
+    assert_eq!(parse_settings(&settings), vec![(3, 100), (4, 10_485_760), (2, 0)]);
+
+    let missing_connection_token = upgrade_request(&[
+        ("connection", "Upgrade"),
+        ("upgrade", "h2c"),
+        ("http2-settings", "AAMAAABkAAQAoAAAAAIAAAAA"),
+    ]);
+    assert!(h2c_upgrade_settings(&missing_connection_token).is_none());
+
+    let bad_settings = upgrade_request(&[
+        ("connection", "Upgrade, HTTP2-Settings"),
+        ("upgrade", "h2c"),
+        ("http2-settings", "AAMA"),
+    ]);
+    assert!(h2c_upgrade_settings(&bad_settings).is_none());
+}
+
+#[test]
+fn test_connection_preface_detection() {
+    assert!(is_connection_preface(b"PRI * HTTP/2.0\r\n\r\n"));
+    assert!(!is_connection_preface(b"GET / HTTP/1.1\r\n\r\n"));
+}
@@ -1,28 +1,375 @@
 use std::net::SocketAddr;
-use std::sync::{Arc, Weak};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, RwLock, Weak};
 use std::time::Duration;
 
-use crate::client_socket::{ClientSocket, ReadError, Socket};
+use crate::logging::{debug, error, in_connection_span, info, warning, RequestSpan};
+use crate::http2::{Upgrade, h2c_upgrade_response, h2c_upgrade_settings, is_connection_preface, serve_connection as serve_http2};
+use crate::connections::{ConnectionGuard, Connections, ShutdownSignal};
+use crate::client_socket::{BoxedStream, ClientSocket, ReadError, ReadPhase, Socket, SocketReader};
 use crate::http_server_trait::{HttpListener, get_path_params, method_matches, path_matches};
+pub use crate::listener::{Accept, Bind, ListenAddr, Listener, Peer, PeerCredentials};
+#[cfg(unix)]
+pub use crate::listener::systemd_listeners;
+use crate::listener::{ConnectionInfo, redirect_state};
+#[cfg(unix)]
+use crate::listener::bind_unix;
 pub use crate::middleware::{HttpMiddleware, MiddlewareEntry, MiddlewareHandler, MiddlewareType};
+use crate::error_renderer::{ErrorContext, ErrorRenderer};
+use crate::health::Health;
+use crate::metrics::{MeteredStream, Metrics, TlsHandshakeFailure};
+use crate::middleware::{run_loggers, run_post_request, run_pre_request};
 use crate::request::{Request, RequestParsingError, parse_request};
+use crate::router::{RouteScope, Router, nest_routes, root_scope, scoped_handler};
 use crate::response::{Response, status};
-use crate::status_code::{METHOD_NOT_ALLOWED, NOT_FOUND, PAYLOAD_TOO_LARGE};
+use crate::sse::EventStream;
+use crate::status_code::{StatusCode, BAD_REQUEST, INTERNAL_SERVER_ERROR, METHOD_NOT_ALLOWED, NOT_FOUND, PAYLOAD_TOO_LARGE, REQUEST_TIMEOUT, SERVICE_UNAVAILABLE, SWITCHING_PROTOCOLS};
 use crate::utils::bytes_contain;
+use crate::websocket::{WebSocket, WebSocketConfig, WebSocketHandler, handshake_response, is_upgrade_request};
 
 use futures::{AsyncRead, AsyncWrite, FutureExt};
-use rustls::ServerConfig;
-use rustls::pki_types::pem::PemObject;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use smol::net::{TcpListener, TcpStream};
+use smol::lock::{OnceCell, Semaphore};
+use smol::net::TcpListener;
 
 pub use crate::http_server_trait::HttpCallbacks;
+pub use crate::tls::{CertificateSource, ClientAuth, ClientCertificate, HttpsServerConfig, PrivateKeySource, TlsInfo, TlsReloadHandle};
+use crate::tls::{SharedServerConfig, spawn_reload_tasks};
 
 const BUFFER_SIZE: usize = 8192;
 
-pub struct HttpServer<'a> {
+const MALFORMED_REQUEST: &str = "The request is malformed.";
+const REQUEST_TOO_LARGE: &str = "The request is larger than the server accepts.";
+
+pub struct HttpServer {
     callbacks: Vec<HttpListener<Request, Response>>,
-    middlewares: Vec<MiddlewareEntry<'a>>,
+    scopes: Vec<RouteScope>,
+    websockets: Vec<HttpListener<WebSocket, WebSocketHandler>>,
+    middlewares: Vec<MiddlewareEntry>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    /// How long readiness fails before the listeners stop on shutdown.
+    drain_delay: Duration,
+    error_renderer: ErrorRenderer,
+}
+
+/// The routes and middlewares shared by every connection of a running server.
+pub struct ServerState {
+    pub(crate) callbacks: Vec<HttpListener<Request, Response>>,
+    pub(crate) scopes: Vec<RouteScope>,
+    pub(crate) websockets: Vec<HttpListener<WebSocket, WebSocketHandler>>,
+    pub(crate) middlewares: Vec<MiddlewareEntry>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) error_renderer: ErrorRenderer,
+}
+
+/// What the connection should do once a request has been answered.
+pub(crate) enum RequestOutcome {
+    KeepAlive,
+    Close,
+    /// The connection switched protocols and now belongs to a WebSocket handler.
+    WebSocket(Request, HttpListener<WebSocket, WebSocketHandler>),
+    /// The connection switched to HTTP/2 through `Upgrade: h2c`.
+    Http2(Upgrade),
+}
+
+impl RequestOutcome {
+    fn closing(close: bool) -> Self {
+        if close { RequestOutcome::Close } else { RequestOutcome::KeepAlive }
+    }
+}
+
+/// What answers a routed request.
+pub(crate) enum Route {
+    /// Answered by the server itself, e.g. OPTIONS from the registered routes.
+    Answered(Response),
+    Handler(Arc<dyn Fn(Request) -> Response + Send + Sync>),
+}
+
+/// Why a request couldn't be routed, independent of the protocol it came in on.
+pub(crate) enum Unrouted {
+    NotFound,
+    /// With the value of the `Allow` header.
+    MethodNotAllowed(String),
+}
+
+/// Whether the client accepts a gzip-compressed body for this response.
+/// Event streams and protocol switches are never compressed.
+fn should_compress(req: &Request, res: &Response) -> bool {
+    req.headers
+        .get_single("accept-encoding")
+        .is_some_and(|e| e.contains("gzip"))
+        && !res.content_type.is_binary
+        && res.event_stream.is_none()
+        && res.status_code != SWITCHING_PROTOCOLS
+}
+
+/// Runs a route handler, logging it and returning `None` if it panics. The
+/// request has been read in full by then, so the connection stays usable.
+fn call_handler(handler: &(dyn Fn(Request) -> Response + Send + Sync), req: Request) -> Option<Response> {
+    let (method, path, route) = (req.method.clone(), req.path.clone(), req.route.clone());
+    match std::panic::catch_unwind(AssertUnwindSafe(|| handler(req))) {
+        Ok(res) => Some(res),
+        Err(panic) => {
+            error!(
+                method = ?method,
+                path = %path,
+                route = %route.as_deref().unwrap_or(""),
+                panic = %panic_message(panic.as_ref()),
+                "Handler panicked."
+            );
+            None
+        }
+    }
+}
+
+/// The message passed to `panic!`, when it's a string.
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    match panic.downcast_ref::<&'static str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("Box<dyn Any>", String::as_str),
+    }
+}
+
+/// Adds the `Allow` header of a `405` response, unless it's already set.
+fn with_allow(mut res: Response, allow: &str) -> Response {
+    if !res.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("allow")) {
+        res.headers.push(("Allow".to_string(), allow.to_string()));
+    }
+    res
+}
+
+/// A response written without going through the middlewares, e.g. for a
+/// request that couldn't be parsed.
+fn simple_response_bytes(res: Response, close: bool) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", res.status_code.code, res.status_code.reason);
+    if !res.bytes.is_empty() {
+        head.push_str(&format!("Content-Type: {}\r\n", res.content_type));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", res.bytes.len()));
+    if close {
+        head.push_str("Connection: close\r\n");
+    }
+    for (key, value) in &res.headers {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str("\r\n");
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&res.bytes);
+    bytes
+}
+
+/// Answers a connection over the limits with a `503` and closes it, in its
+/// own task so the accept loop carries on.
+fn reject_connection<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(mut stream: T, config: &HttpServerConfig) {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    let timeout = config.timeout_config.write_timeout_duration;
+    smol::spawn(async move {
+        let reject = async {
+            stream
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\nRetry-After: 1\r\n\r\n")
+                .await?;
+            // Shuts down the sending side, then reads what the client sent so
+            // closing doesn't reset the connection before it sees the response.
+            stream.close().await?;
+            let mut buffer = [0u8; 1024];
+            while stream.read(&mut buffer).await? > 0 {}
+            Ok::<(), std::io::Error>(())
+        };
+        futures::select! {
+            _ = reject.fuse() => {}
+            _ = FutureExt::fuse(smol::Timer::after(timeout)) => {}
+        }
+    })
+    .detach();
+}
+
+impl ServerState {
+    /// Answers OPTIONS from the registered routes, otherwise runs the
+    /// matching route handler. Returns the request (without its body once a
+    /// handler took it) and the response; middlewares are left to the caller.
+    pub(crate) fn dispatch(&self, mut req: Request) -> (Request, Result<Response, Unrouted>) {
+        match self.route(&mut req) {
+            Ok(Route::Answered(res)) => (req, Ok(res)),
+            Ok(Route::Handler(handler)) => {
+                let kept_request = req.without_body();
+                let res = call_handler(handler.as_ref(), req)
+                    .unwrap_or_else(|| self.error_response(INTERNAL_SERVER_ERROR, Some(&kept_request), None));
+                (kept_request, Ok(res))
+            }
+            Err(unrouted) => (req, Err(unrouted)),
+        }
+    }
+
+    /// Like `dispatch`, running the handler on the blocking thread pool so
+    /// the connection carries on meanwhile. The response is `None` when the
+    /// handler didn't finish within `timeout`; it keeps running but its
+    /// response is dropped.
+    pub(crate) async fn dispatch_unblocked(
+        &self,
+        mut req: Request,
+        timeout: Option<Duration>,
+    ) -> (Request, Result<Option<Response>, Unrouted>) {
+        let handler = match self.route(&mut req) {
+            Ok(Route::Answered(res)) => return (req, Ok(Some(res))),
+            Ok(Route::Handler(handler)) => handler,
+            Err(unrouted) => return (req, Err(unrouted)),
+        };
+        let kept_request = req.without_body();
+        let timer = match timeout {
+            Some(timeout) => smol::Timer::after(timeout),
+            None => smol::Timer::never(),
+        };
+        let res = futures::select! {
+            res = smol::unblock(move || call_handler(handler.as_ref(), req)).fuse() => {
+                Some(res.unwrap_or_else(|| self.error_response(INTERNAL_SERVER_ERROR, Some(&kept_request), None)))
+            }
+            _ = FutureExt::fuse(timer) => None,
+        };
+        (kept_request, Ok(res))
+    }
+
+    /// Encodes the body of a response about to be sent, then runs the loggers
+    /// and records it in the metrics.
+    pub(crate) fn finish_response(&self, req: &Request, res: &mut Response) {
+        if should_compress(req, res) {
+            match crate::utils::gzip_compress(&res.bytes) {
+                Ok(compressed) => {
+                    res.headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+                    res.bytes = compressed;
+                }
+                Err(e) => warning!(error = %e, "Failed to compress response."),
+            }
+        }
+        run_loggers(&self.middlewares, req, res);
+        self.metrics.record_request(req, res.status_code.code);
+    }
+
+    /// The response for the outcome of `dispatch_unblocked`.
+    pub(crate) fn dispatched_response(
+        &self,
+        kept_request: &Request,
+        dispatched: Result<Option<Response>, Unrouted>,
+        timeout: Option<Duration>,
+    ) -> Response {
+        match dispatched {
+            Ok(Some(res)) => res,
+            Ok(None) => {
+                warning!(
+                    method = ?kept_request.method,
+                    path = %kept_request.path,
+                    timeout = ?timeout.unwrap_or_default(),
+                    "Handler timed out."
+                );
+                self.error_response(SERVICE_UNAVAILABLE, Some(kept_request), None)
+            }
+            Err(unrouted) => self.unrouted_response(kept_request, unrouted),
+        }
+    }
+
+    /// Finds what answers the request, filling in its path parameters.
+    pub(crate) fn route(&self, req: &mut Request) -> Result<Route, Unrouted> {
+        if req.method == crate::http_method::HttpMethod::OPTIONS {
+            let allowed_methods = self.allowed_methods(&req.path);
+            if allowed_methods.is_empty() {
+                return self.unrouted(req, Unrouted::NotFound);
+            }
+            return Ok(Route::Answered(status(200).header("Allow", allowed_methods.join(", "))));
+        }
+
+        let mut found_path = false;
+        for listener in &self.callbacks {
+            if !path_matches(listener, &req.path) {
+                continue;
+            }
+            found_path = true;
+            if method_matches(listener, &req.method) {
+                req.path_params = get_path_params(listener, &req.path);
+                req.route = Some(listener.path.clone());
+                return Ok(Route::Handler(listener.callback.clone()));
+            }
+        }
+
+        if found_path {
+            self.unrouted(req, Unrouted::MethodNotAllowed(self.allowed_methods(&req.path).join(", ")))
+        } else {
+            self.unrouted(req, Unrouted::NotFound)
+        }
+    }
+
+    /// Hands an unrouted request to the fallback handlers of its scope, if any.
+    fn unrouted(&self, req: &Request, unrouted: Unrouted) -> Result<Route, Unrouted> {
+        match unrouted {
+            Unrouted::NotFound => match scoped_handler(&self.scopes, &req.path, |scope| scope.fallback.as_ref()) {
+                Some(handler) => Ok(Route::Handler(handler)),
+                None => Err(Unrouted::NotFound),
+            },
+            Unrouted::MethodNotAllowed(allow) => {
+                match scoped_handler(&self.scopes, &req.path, |scope| scope.method_not_allowed.as_ref()) {
+                    Some(handler) => Ok(Route::Handler(Arc::new(move |req| with_allow(handler(req), &allow)))),
+                    None => Err(Unrouted::MethodNotAllowed(allow)),
+                }
+            }
+        }
+    }
+
+    /// The methods the routes matching `path` answer, plus OPTIONS, sorted.
+    /// Empty when no route matches.
+    fn allowed_methods(&self, path: &str) -> Vec<&'static str> {
+        let mut allowed_methods = vec![];
+        for listener in &self.callbacks {
+            if path_matches(listener, path) {
+                if listener.method == crate::http_method::HttpMethod::ALL {
+                    allowed_methods = vec![
+                        "GET", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "HEAD",
+                    ];
+                    break;
+                } else {
+                    let method_str = match listener.method {
+                        crate::http_method::HttpMethod::GET => "GET",
+                        crate::http_method::HttpMethod::POST => "POST",
+                        crate::http_method::HttpMethod::PUT => "PUT",
+                        crate::http_method::HttpMethod::DELETE => "DELETE",
+                        crate::http_method::HttpMethod::PATCH => "PATCH",
+                        crate::http_method::HttpMethod::HEAD => "HEAD",
+                        crate::http_method::HttpMethod::OPTIONS => "OPTIONS",
+                        _ => continue,
+                    };
+                    if !allowed_methods.contains(&method_str) {
+                        allowed_methods.push(method_str);
+                    }
+                }
+            }
+        }
+        if allowed_methods.is_empty() {
+            return allowed_methods;
+        }
+        if !allowed_methods.contains(&"OPTIONS") {
+            allowed_methods.push("OPTIONS");
+        }
+        allowed_methods.sort();
+        allowed_methods
+    }
+
+    /// Renders an error the server answers itself through the error renderer.
+    pub(crate) fn error_response(&self, status: StatusCode, request: Option<&Request>, detail: Option<&str>) -> Response {
+        self.error_renderer.render(&ErrorContext {
+            status,
+            request,
+            detail: detail.map(str::to_string),
+        })
+    }
+
+    /// The `404` or `405` response for a request no route answers.
+    pub(crate) fn unrouted_response(&self, request: &Request, unrouted: Unrouted) -> Response {
+        match unrouted {
+            Unrouted::NotFound => self.error_response(NOT_FOUND, Some(request), None),
+            Unrouted::MethodNotAllowed(allow) => {
+                let detail = format!("Allowed methods: {allow}");
+                with_allow(self.error_response(METHOD_NOT_ALLOWED, Some(request), Some(&detail)), &allow)
+            }
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -42,8 +389,25 @@ impl Default for HttpServerSizeConfig {
 
 #[derive(Clone, Copy)]
 pub struct HttpServerTimeoutConfig {
+    /// The longest a single read may wait for data once a request started.
     pub read_timeout_duration: Duration,
+    /// The longest a single write may take before the connection is closed.
     pub write_timeout_duration: Duration,
+    /// How long a client gets to complete the TLS handshake.
+    pub handshake_timeout_duration: Duration,
+    /// How long a connection may wait for its next request before it is
+    /// closed.
+    pub keep_alive_timeout_duration: Duration,
+    /// How long a client gets to send the request headers, counted from
+    /// their first byte. Answered with `408 Request Timeout`.
+    pub header_timeout_duration: Duration,
+    /// The average rate a request body has to arrive at once
+    /// `read_timeout_duration` has passed, otherwise `408 Request Timeout`.
+    pub body_min_bytes_per_second: Option<u64>,
+    /// How long a route handler may run before the request is answered with
+    /// `503 Service Unavailable`. HTTP/1.1 handlers run on a blocking thread
+    /// pool when set, HTTP/2 ones always do.
+    pub handler_timeout_duration: Option<Duration>,
 }
 
 impl Default for HttpServerTimeoutConfig {
@@ -51,6 +415,11 @@ impl Default for HttpServerTimeoutConfig {
         HttpServerTimeoutConfig {
             read_timeout_duration: Duration::from_secs(5),
             write_timeout_duration: Duration::from_secs(5),
+            handshake_timeout_duration: Duration::from_secs(10),
+            keep_alive_timeout_duration: Duration::from_secs(5),
+            header_timeout_duration: Duration::from_secs(10),
+            body_min_bytes_per_second: None,
+            handler_timeout_duration: None,
         }
     }
 }
@@ -67,62 +436,218 @@ impl Default for ShutdownMode {
     }
 }
 
+/// What a listener does with new connections once `max_connections` are open.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ConnectionLimitMode {
+    /// Stop accepting until a connection closes, leaving clients in the
+    /// listen backlog.
+    #[default]
+    Wait,
+    /// Accept, answer `503 Service Unavailable` and close.
+    Reject,
+}
+
+/// Limits on the connections of a listener, all unlimited by default.
 #[derive(Clone, Copy, Default)]
-pub struct HttpServerConfig {
-    pub size_config: HttpServerSizeConfig,
-    pub timeout_config: HttpServerTimeoutConfig,
-    pub shutdown_mode: ShutdownMode,
+pub struct HttpServerConnectionConfig {
+    pub max_connections: Option<usize>,
+    pub when_full: ConnectionLimitMode,
+    /// Connections over the cap are answered with a `503` and closed.
+    pub max_connections_per_ip: Option<usize>,
+    /// The connection is closed after answering this many requests. HTTP/2
+    /// connections stop accepting new streams instead.
+    pub max_requests_per_connection: Option<usize>,
 }
 
-pub struct HttpsServerConfig {
+#[derive(Clone, Copy, Default)]
+pub struct HttpServerConfig {
     pub size_config: HttpServerSizeConfig,
     pub timeout_config: HttpServerTimeoutConfig,
     pub shutdown_mode: ShutdownMode,
-    pub cert_path: String,
-    pub key_path: String,
+    pub websocket_config: WebSocketConfig,
+    pub connection_config: HttpServerConnectionConfig,
 }
 
 pub enum AcceptError {
-    Shutdown,
+    /// The server stopped accepting, its connections are to be shut down
+    /// this way.
+    Shutdown(ShutdownMode),
     IoError(std::io::Error),
 }
 
-impl<'a> HttpServer<'a> {
+/// Controls a running server. Dropping it shuts the server down according
+/// to the `shutdown_mode` of each listener.
+pub struct ServerHandle {
+    shutdown: Arc<ShutdownSignal>,
+    local_addrs: Arc<OnceCell<Vec<ListenAddr>>>,
+    connections: Vec<Arc<Connections>>,
+    /// Closed once the server has stopped and its connections are closed.
+    finished: smol::channel::Receiver<()>,
+    health: Arc<Health>,
+    tls_reloads: Vec<TlsReloadHandle>,
+}
+
+impl ServerHandle {
+    /// Stops accepting and cancels every connection, including those in the
+    /// middle of a request.
+    pub fn shutdown(&self) {
+        self.health.begin_shutdown();
+        self.shutdown.request(Some(ShutdownMode::Immediate));
+    }
+
+    /// Stops accepting and closes idle keep-alive connections. The others
+    /// close once their current request is answered, or are cancelled when
+    /// `timeout` passes or `shutdown` is called.
+    pub fn shutdown_graceful(&self, timeout: Duration) {
+        self.health.begin_shutdown();
+        self.shutdown.request(Some(ShutdownMode::Graceful(timeout)));
+    }
+
+    /// Reloads the certificates and keys of every HTTPS listener. Invalid
+    /// ones are logged and the current ones stay in place.
+    pub fn reload_tls(&self) {
+        for reload in &self.tls_reloads {
+            reload.reload();
+        }
+    }
+
+    /// Resolves once the server has stopped and its connections are closed.
+    pub async fn wait(&self) {
+        let _ = self.finished.recv().await;
+    }
+
+    /// The number of open connections across all listeners.
+    pub fn active_connections(&self) -> usize {
+        self.connections.iter().map(|connections| connections.active()).sum()
+    }
+
+    /// The number of connections accepted since the server started.
+    pub fn accepted_connections(&self) -> usize {
+        self.connections.iter().map(|connections| connections.accepted()).sum()
+    }
+
+    /// The number of connections turned away by the connection limits.
+    pub fn rejected_connections(&self) -> usize {
+        self.connections.iter().map(|connections| connections.rejected()).sum()
+    }
+
+    /// The address of each listener, in the order they were given, e.g. to
+    /// find out which port was picked for port 0. Waits until the server has
+    /// bound them, and is empty when binding failed.
+    pub async fn local_addrs(&self) -> &[ListenAddr] {
+        self.local_addrs.wait().await
+    }
+
+    /// The address of the first TCP listener.
+    pub async fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs().await.iter().find_map(ListenAddr::tcp)
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        // Leaves an explicit shutdown request in place.
+        self.shutdown.request(None);
+    }
+}
+
+impl HttpServer {
     pub fn new() -> Self {
         HttpServer {
             callbacks: vec![],
+            scopes: vec![RouteScope::root()],
+            websockets: vec![],
             middlewares: vec![],
+            metrics: Arc::new(Metrics::new()),
+            health: Arc::new(Health::new()),
+            drain_delay: Duration::ZERO,
+            error_renderer: ErrorRenderer::new(),
         }
     }
 
+    /// Answers the requests whose path no route matches, e.g. to serve the
+    /// `index.html` of a single-page app, instead of the error renderer's `404`.
+    pub fn fallback(&mut self, handler: impl Fn(Request) -> Response + Send + Sync + 'static) {
+        root_scope(&mut self.scopes).fallback = Some(Arc::new(handler));
+    }
+
+    /// Answers the requests whose path matches a route, but not their
+    /// method, instead of the error renderer's `405`. `Allow` is added
+    /// unless the handler sets it.
+    pub fn method_not_allowed(&mut self, handler: impl Fn(Request) -> Response + Send + Sync + 'static) {
+        root_scope(&mut self.scopes).method_not_allowed = Some(Arc::new(handler));
+    }
+
+    /// Mounts the routes of `router` under `prefix`. Its fallback handlers
+    /// answer the paths under `prefix` in place of the server's.
+    pub fn nest(&mut self, prefix: &str, router: Router) {
+        nest_routes(&mut self.callbacks, &mut self.scopes, prefix, router);
+    }
+
+    /// Sets how the errors the server answers itself are rendered.
+    pub fn error_renderer(&mut self, renderer: ErrorRenderer) {
+        self.error_renderer = renderer;
+    }
+
+    /// The liveness and readiness of the server, see `Health`.
+    pub fn health(&self) -> Arc<Health> {
+        self.health.clone()
+    }
+
+    /// How long `/readyz` fails on shutdown while the listeners keep
+    /// accepting, so load balancers take the server out of rotation before
+    /// connections are refused. Skipped by an immediate shutdown, zero by
+    /// default.
+    pub fn drain_delay(&mut self, delay: Duration) {
+        self.drain_delay = delay;
+    }
+
+    /// Mounts the liveness probe on `GET /healthz` and the readiness probe
+    /// on `GET /readyz`.
+    pub fn health_endpoints(&mut self) {
+        let health = self.health();
+        self.get("/healthz", health.liveness_handler());
+        self.get("/readyz", health.readiness_handler());
+    }
+
+    /// The metrics collected by the server once it runs, see `Metrics`.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Registers a WebSocket endpoint. Once the handshake succeeds the
+    /// connection is handed to `handler` and no longer serves HTTP requests.
+    pub fn websocket<T, F, Fut>(&mut self, path: T, handler: F)
+    where
+        T: Into<String>,
+        F: Fn(WebSocket) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.websockets.push(HttpListener {
+            path: path.into(),
+            method: crate::http_method::HttpMethod::GET,
+            callback: Arc::new(move |socket| -> WebSocketHandler { Box::pin(handler(socket)) }),
+        });
+    }
+
     async fn send_response<T: Socket>(
         client: &mut T,
-        req: Request,
         mut res: Response,
+        close: bool,
     ) -> std::io::Result<()> {
+        if let Some(stream) = res.event_stream.take() {
+            return Self::send_event_stream(client, res, stream).await;
+        }
+
         let mut response_header = format!(
             "HTTP/1.1 {} {}\r\n",
             res.status_code.code, res.status_code.reason
         );
 
-        if req
-            .headers
-            .get_single("connection")
-            .is_some_and(|c| c.to_lowercase() == "close")
-        {
+        if close {
             response_header.push_str("Connection: close\r\n");
         }
 
-        if req
-            .headers
-            .get_single("accept-encoding")
-            .is_some_and(|e| e.contains("gzip"))
-            && !res.content_type.is_binary
-        {
-            response_header.push_str("Content-Encoding: gzip\r\n");
-            res.bytes = crate::utils::gzip_compress(&res.bytes)?;
-        }
-
         response_header.push_str(&format!("Content-Type: {}\r\n", res.content_type));
         response_header.push_str(&format!("Content-Length: {}\r\n", res.bytes.len()));
 
@@ -181,19 +706,88 @@ impl<'a> HttpServer<'a> {
     }
 
     async fn send_simple_response<T: Socket>(client: &mut T, res: Response) -> std::io::Result<()> {
-        let response_header = format!(
-            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n\r\n",
-            res.status_code.code,
-            res.status_code.reason,
-            res.bytes.len()
+        client
+            .write_all(&simple_response_bytes(res, false))
+            .await
+            .map_err(|e| -> std::io::Error { e.into() })
+    }
+
+    /// Like `send_simple_response`, telling the client the connection closes.
+    async fn send_closing_response<T: Socket>(client: &mut T, res: Response) -> std::io::Result<()> {
+        client
+            .write_all(&simple_response_bytes(res, true))
+            .await
+            .map_err(|e| -> std::io::Error { e.into() })
+    }
+
+    /// Writes an event stream as a chunked response until every sender is
+    /// dropped, the client goes away or the server is shutting down.
+    async fn send_event_stream<T: Socket>(
+        client: &mut T,
+        res: Response,
+        stream: EventStream,
+    ) -> std::io::Result<()> {
+        let mut response_header = format!(
+            "HTTP/1.1 {} {}\r\n",
+            res.status_code.code, res.status_code.reason
         );
-        let header_bytes = response_header.as_bytes();
-        let mut full_response = Vec::with_capacity(header_bytes.len() + res.bytes.len());
-        full_response.extend_from_slice(header_bytes);
-        full_response.extend_from_slice(&res.bytes);
+        response_header.push_str(&format!("Content-Type: {}\r\n", res.content_type));
+        response_header.push_str("Cache-Control: no-cache\r\n");
+        response_header.push_str("Transfer-Encoding: chunked\r\n");
+        response_header.push_str("Connection: close\r\n");
+        for (key, value) in &res.headers {
+            response_header.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        response_header.push_str("\r\n");
+
+        let result = async {
+            client
+                .write_all(response_header.as_bytes())
+                .await
+                .map_err(|e| -> std::io::Error { e.into() })?;
+            loop {
+                let data = futures::select! {
+                    event = stream.receiver.recv().fuse() => match event {
+                        Ok(event) => event.to_bytes(),
+                        Err(_) => break,
+                    },
+                    _ = FutureExt::fuse(smol::Timer::after(stream.keep_alive)) => b": keep-alive\n\n".to_vec(),
+                    _ = client.cancelled().fuse() => break,
+                };
+                let mut chunk = format!("{:X}\r\n", data.len()).into_bytes();
+                chunk.extend_from_slice(&data);
+                chunk.extend_from_slice(b"\r\n");
+                client
+                    .write_all(&chunk)
+                    .await
+                    .map_err(|e| -> std::io::Error { e.into() })?;
+            }
+            client
+                .write_all(b"0\r\n\r\n")
+                .await
+                .map_err(|e| -> std::io::Error { e.into() })
+        }
+        .await;
+
+        // Let the handler side know nobody is listening anymore.
+        stream.receiver.close();
+        result
+    }
+
+    /// Writes a `101 Switching Protocols` response, which must not carry a body
+    /// or the entity headers added by `send_response`.
+    async fn send_upgrade_response<T: Socket>(client: &mut T, res: Response) -> std::io::Result<()> {
+        let mut response_header = format!(
+            "HTTP/1.1 {} {}\r\n",
+            res.status_code.code, res.status_code.reason
+        );
+        for (key, value) in &res.headers {
+            response_header.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        response_header.push_str("\r\n");
 
         client
-            .write_all(&full_response)
+            .write_all(response_header.as_bytes())
             .await
             .map_err(|e| -> std::io::Error { e.into() })
     }
@@ -201,103 +795,89 @@ impl<'a> HttpServer<'a> {
     async fn process_request<T: Socket>(
         request: Vec<u8>,
         extra_body_bytes: Vec<u8>,
-        callbacks: &[HttpListener<Request, Response>],
+        state: &ServerState,
         config: HttpServerConfig,
         client: &mut T,
-    ) -> std::io::Result<bool> {
+        connection: &ConnectionInfo,
+        last_request: bool,
+    ) -> std::io::Result<RequestOutcome> {
+        let received_at = std::time::Instant::now();
         let request = parse_request(client, request, extra_body_bytes, config).await;
+        if let Err(e) = &request {
+            state.metrics.record_parse_error(e);
+        }
         match request {
             Ok(mut req) => {
+                connection.apply(&mut req);
+                req.received_at = received_at;
                 if req.path.contains("http") {
                     // get the final part after hostname
                     // e.g. http://example.com/path -> /path
                     req.path = req.path.split('/').skip(3).collect::<Vec<&str>>().join("/");
                 }
 
-                let connection_close = req
-                    .headers
-                    .get_single("connection")
-                    .is_some_and(|c| c.to_lowercase() == "close");
-
-                // Handle OPTIONS request
-                if req.method == crate::http_method::HttpMethod::OPTIONS {
-                    let mut allowed_methods = vec![];
-                    for listener in callbacks {
-                        if path_matches(&listener, &req.path) {
-                            if listener.method == crate::http_method::HttpMethod::ALL {
-                                allowed_methods = vec![
-                                    "GET", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "HEAD",
-                                ];
-                                break;
-                            } else {
-                                let method_str = match listener.method {
-                                    crate::http_method::HttpMethod::GET => "GET",
-                                    crate::http_method::HttpMethod::POST => "POST",
-                                    crate::http_method::HttpMethod::PUT => "PUT",
-                                    crate::http_method::HttpMethod::DELETE => "DELETE",
-                                    crate::http_method::HttpMethod::PATCH => "PATCH",
-                                    crate::http_method::HttpMethod::HEAD => "HEAD",
-                                    crate::http_method::HttpMethod::OPTIONS => "OPTIONS",
-                                    _ => continue,
-                                };
-                                if !allowed_methods.contains(&method_str) {
-                                    allowed_methods.push(method_str);
-                                }
-                            }
-                        }
-                    }
-                    if !allowed_methods.is_empty() {
-                        if !allowed_methods.contains(&"OPTIONS") {
-                            allowed_methods.push("OPTIONS");
-                        }
-                        allowed_methods.sort();
-                        let allow_header = allowed_methods.join(", ");
-                        let res = status(200).header("Allow", allow_header);
-                        Self::send_response(client, req, res).await?;
-                        return Ok(connection_close);
-                    } else {
-                        Self::send_simple_response(client, status(NOT_FOUND)).await?;
-                        return Ok(connection_close);
-                    }
+                // h2c is only defined for cleartext connections, TLS uses ALPN.
+                if connection.tls.is_none() && let Some(settings) = h2c_upgrade_settings(&req) {
+                    Self::send_upgrade_response(client, h2c_upgrade_response()).await?;
+                    return Ok(RequestOutcome::Http2(Upgrade { request: req, settings }));
                 }
 
-                let mut sent = false;
-                let mut found_path = false;
-                for listener in callbacks {
-                    if !found_path && path_matches(&listener, &req.path) {
-                        found_path = true;
-                    }
-                    if path_matches(&listener, &req.path) && method_matches(&listener, &req.method)
-                    {
-                        let path_params = get_path_params(&listener, &req.path);
-                        req.path_params = path_params;
-                        let kept_request = Request {
-                            headers: req.headers.clone(),
-                            ..Default::default()
-                        };
-                        let res = (listener.callback)(req);
-                        match Self::send_response(client, kept_request, res).await {
-                            Ok(_) => {}
-                            Err(_) => {
-                                return Err(std::io::Error::new(
-                                    std::io::ErrorKind::BrokenPipe,
-                                    "Client disconnected.",
-                                ));
-                            }
+                let _in_flight = state.metrics.request_started();
+                let connection_close = last_request
+                    || req
+                        .headers
+                        .get_single("connection")
+                        .is_some_and(|c| c.to_lowercase() == "close");
+
+                if let Some(mut res) = run_pre_request(&state.middlewares, &mut req) {
+                    state.finish_response(&req, &mut res);
+                    Self::send_response(client, res, connection_close).await?;
+                    return Ok(RequestOutcome::closing(connection_close));
+                }
+
+                if let Some(listener) = state.websockets.iter().find(|l| path_matches(l, &req.path)) {
+                    let has_http_route = state.callbacks.iter().any(|l| path_matches(l, &req.path));
+                    if is_upgrade_request(&req) || !has_http_route {
+                        req.path_params = get_path_params(listener, &req.path);
+                        req.route = Some(listener.path.clone());
+                        let mut res = handshake_response(&req);
+                        run_post_request(&state.middlewares, &req, &mut res);
+                        state.finish_response(&req, &mut res);
+                        if res.status_code == SWITCHING_PROTOCOLS {
+                            Self::send_upgrade_response(client, res).await?;
+                            return Ok(RequestOutcome::WebSocket(req.without_body(), listener.clone()));
                         }
-                        sent = true;
-                        break;
+                        Self::send_response(client, res, connection_close).await?;
+                        return Ok(RequestOutcome::closing(connection_close));
                     }
                 }
 
-                if !sent && !found_path {
-                    Self::send_simple_response(client, status(NOT_FOUND)).await?;
-                } else if !sent && found_path {
-                    Self::send_simple_response(client, status(METHOD_NOT_ALLOWED)).await?;
+                let span = RequestSpan::new(&req);
+                let streaming = span.instrument(async {
+                    debug!(method = ?req.method, path = %req.path, "Request received.");
+                    let timeout = config.timeout_config.handler_timeout_duration;
+                    let (kept_request, dispatched) = match timeout {
+                        Some(_) => state.dispatch_unblocked(req, timeout).await,
+                        None => {
+                            let (kept_request, res) = state.dispatch(req);
+                            (kept_request, res.map(Some))
+                        }
+                    };
+                    let mut res = state.dispatched_response(&kept_request, dispatched, timeout);
+                    run_post_request(&state.middlewares, &kept_request, &mut res);
+                    state.finish_response(&kept_request, &mut res);
+                    let streaming = res.event_stream.is_some();
+                    Self::send_response(client, res, connection_close)
+                        .await
+                        .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client disconnected."))?;
+                    Ok::<_, std::io::Error>(streaming)
+                }).await?;
+                if streaming {
+                    return Ok(RequestOutcome::Close);
                 }
 
                 if connection_close {
-                    return Ok(true);
+                    return Ok(RequestOutcome::Close);
                 }
             }
             Err(
@@ -306,40 +886,52 @@ impl<'a> HttpServer<'a> {
                 | RequestParsingError::InvalidRequest
                 | RequestParsingError::UnhandledRequest,
             ) => {
-                let res = status(400);
+                let res = state.error_response(BAD_REQUEST, None, Some(MALFORMED_REQUEST));
                 Self::send_simple_response(client, res).await?;
             }
             Err(RequestParsingError::PayloadTooLarge) => {
-                let res = status(PAYLOAD_TOO_LARGE);
+                let res = state.error_response(PAYLOAD_TOO_LARGE, None, Some(REQUEST_TOO_LARGE));
                 Self::send_simple_response(client, res).await?;
             }
             Err(RequestParsingError::Cancellation) => {
-                println!("Request parsing cancelled.");
-                return Ok(true);
+                debug!(peer = %connection.peer, "Request parsing cancelled.");
+                return Ok(RequestOutcome::Close);
             }
             Err(RequestParsingError::IoError(e)) => {
-                println!("IO Error during request parsing: {:?}", e);
+                debug!(peer = %connection.peer, error = %e, "IO error during request parsing.");
                 return Err(e);
             }
             Err(RequestParsingError::Timeout) => {
-                println!("Request parsing timed out.");
-                return Ok(true);
+                debug!(peer = %connection.peer, "Timed out reading request body.");
+                let res = state.error_response(REQUEST_TIMEOUT, None, None);
+                Self::send_closing_response(client, res).await?;
+                return Ok(RequestOutcome::Close);
             }
             Err(RequestParsingError::UnexpectedError) => {
-                println!("Unexpected error during request parsing.");
-                return Ok(true);
+                warning!(peer = %connection.peer, "Unexpected error during request parsing.");
+                return Ok(RequestOutcome::Close);
             }
         }
 
-        Ok(false)
+        Ok(RequestOutcome::KeepAlive)
     }
 
-    async fn handle_connection<T: Socket>(
-        callbacks: &[HttpListener<Request, Response>],
+    async fn handle_connection<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        state: &ServerState,
         config: HttpServerConfig,
-        mut client: T,
+        mut client: ClientSocket<T>,
+        connection: ConnectionInfo,
     ) -> std::io::Result<()> {
+        let mut first_request = true;
+        let mut requests = 0;
         loop {
+            if connection.is_draining() {
+                return Ok(());
+            }
+            client.phase = ReadPhase::Idle {
+                timeout: config.timeout_config.keep_alive_timeout_duration,
+                header_timeout: config.timeout_config.header_timeout_duration,
+            };
             match client
                 .read_until(
                     "\r\n\r\n".as_bytes(),
@@ -356,100 +948,130 @@ impl<'a> HttpServer<'a> {
                 Ok((request, _)) if request.is_empty() => {
                     return Ok(());
                 }
+                Ok((request, extra_bytes)) if first_request && connection.tls.is_none() && is_connection_preface(&request) => {
+                    // HTTP/2 with prior knowledge.
+                    let buffered = [request, extra_bytes].concat();
+                    return serve_http2(state, config, client.socket, client.cancellation_token, buffered, None, connection).await;
+                }
                 Ok((request, extra_bytes)) => {
+                    first_request = false;
+                    client.phase = ReadPhase::Body {
+                        started: std::time::Instant::now(),
+                        read: 0,
+                        min_rate: config.timeout_config.body_min_bytes_per_second,
+                    };
+                    requests += 1;
+                    let last_request = config
+                        .connection_config
+                        .max_requests_per_connection
+                        .is_some_and(|max| requests >= max);
                     match Self::process_request(
                         request,
                         extra_bytes,
-                        callbacks,
+                        state,
                         config,
                         &mut client,
+                        &connection,
+                        last_request,
                     )
                     .await
                     {
-                        Ok(should_close) => {
-                            if should_close {
-                                return Ok(());
-                            } else {
-                                continue;
+                        Ok(RequestOutcome::KeepAlive) => continue,
+                        Ok(RequestOutcome::Close) => return Ok(()),
+                        Ok(RequestOutcome::WebSocket(request, listener)) => {
+                            let socket = ClientSocket {
+                                socket: Box::new(client.socket) as BoxedStream,
+                                cancellation_token: client.cancellation_token,
+                                read_timeout: config.websocket_config.read_timeout,
+                                write_timeout: config.timeout_config.write_timeout_duration,
+                                phase: ReadPhase::Streaming,
+                                draining: None,
+                            };
+                            let path = request.path.clone();
+                            let websocket = WebSocket::new(socket, request, config.websocket_config);
+                            if let Err(panic) = AssertUnwindSafe((listener.callback)(websocket)).catch_unwind().await {
+                                error!(
+                                    path = %path,
+                                    route = %listener.path,
+                                    panic = %panic_message(panic.as_ref()),
+                                    "WebSocket handler panicked."
+                                );
                             }
+                            return Ok(());
+                        }
+                        Ok(RequestOutcome::Http2(upgrade)) => {
+                            return serve_http2(state, config, client.socket, client.cancellation_token, Vec::new(), Some(upgrade), connection).await;
                         }
                         Err(e) => {
-                            println!("Error processing request: {:?}", e);
+                            debug!(peer = %connection.peer, error = %e, "Error processing request.");
                             return Err(e);
                         }
                     }
                 }
                 Err(ReadError::Cancellation) => {
-                    println!("Connection cancelled.");
+                    debug!(peer = %connection.peer, "Connection cancelled.");
+                    return Ok(());
+                }
+                Err(ReadError::Timeout) if matches!(client.phase, ReadPhase::Idle { .. }) => {
+                    debug!(peer = %connection.peer, "Connection idle, closing.");
                     return Ok(());
                 }
                 Err(ReadError::Timeout) => {
-                    println!("Read timeout from client.");
+                    debug!(peer = %connection.peer, "Timed out reading request headers.");
+                    state.metrics.record_parse_error(&RequestParsingError::Timeout);
+                    let res = state.error_response(REQUEST_TIMEOUT, None, None);
+                    Self::send_closing_response(&mut client, res).await?;
                     return Ok(());
                 }
                 Err(ReadError::MaxSizeExceeded) => {
-                    let res = status(PAYLOAD_TOO_LARGE);
+                    state.metrics.record_parse_error(&RequestParsingError::PayloadTooLarge);
+                    let res = state.error_response(PAYLOAD_TOO_LARGE, None, Some(REQUEST_TOO_LARGE));
                     Self::send_simple_response(&mut client, res).await?;
                     continue;
                 }
                 Err(ReadError::IoError(e)) => {
-                    println!("Error reading from client: {:?}", e);
+                    debug!(peer = %connection.peer, error = %e, "Error reading from client.");
                     return Err(e);
                 }
                 Err(ReadError::UnexpectedError) => {
-                    println!("Highly unexpected state reached.");
+                    warning!(peer = %connection.peer, "Highly unexpected state reached.");
                     return Ok(());
                 }
             }
         }
     }
 
-    pub async fn shutdown_server(
-        shutdown_mode: ShutdownMode,
-        cancellation_token: smol::channel::Sender<()>,
-    ) -> std::io::Result<()> {
-        match shutdown_mode {
-            ShutdownMode::Immediate => {
-                println!("Shutting down server immediately.");
-                // Send cancellation to all active connections
-                cancellation_token.send(()).await.map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Failed to send cancellation: {}", e),
-                    )
-                })?;
-            }
-            ShutdownMode::Graceful(timeout) => {
-                println!("Shutting down server gracefully (timeout: {:?}).", timeout);
-                // Wait for timeout, then cancel remaining connections
-                smol::Timer::after(timeout).await;
-                println!("Graceful shutdown period ended, cancelling remaining connections.");
-                cancellation_token.send(()).await.map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Failed to send cancellation: {}", e),
-                    )
-                })?;
+    /// Waits for the next connection, or for the server to be shut down.
+    pub async fn accept_connection<L: Accept>(
+        server: &L,
+        config: &HttpServerConfig,
+        stop: &smol::channel::Receiver<Option<ShutdownMode>>,
+    ) -> Result<(L::Stream, Peer), AcceptError> {
+        futures::select! {
+            accept_result = server.accept().fuse() => accept_result.map_err(AcceptError::IoError),
+            mode = stop.recv().fuse() => {
+                Err(AcceptError::Shutdown(mode.ok().flatten().unwrap_or(config.shutdown_mode)))
             }
         }
-        Ok(())
     }
 
-    pub async fn accept_connection(
-        server: &TcpListener,
+    /// Waits until the connection limits allow another connection, accepts
+    /// it and counts it. The guard is `None` when it has to be turned away.
+    async fn accept_within_limits<L: Accept>(
+        server: &L,
         config: &HttpServerConfig,
-        cancellation_token: smol::channel::Receiver<()>,
-        cancel_tx: smol::channel::Sender<()>,
-    ) -> Result<(TcpStream, std::net::SocketAddr), AcceptError> {
+        stop: &smol::channel::Receiver<Option<ShutdownMode>>,
+        connections: &Arc<Connections>,
+    ) -> Result<(L::Stream, Peer, Option<ConnectionGuard>), AcceptError> {
         futures::select! {
-            accept_result = server.accept().fuse() => {
-                return accept_result.map_err(|e| AcceptError::IoError(e));
-            },
-            _ = cancellation_token.recv().fuse() => {
-                Self::shutdown_server(config.shutdown_mode, cancel_tx).await.map_err(|_| AcceptError::Shutdown)?;
-                return Err(AcceptError::Shutdown);
+            _ = connections.capacity().fuse() => {}
+            mode = stop.recv().fuse() => {
+                return Err(AcceptError::Shutdown(mode.ok().flatten().unwrap_or(config.shutdown_mode)));
             }
         }
+        let (stream, peer) = Self::accept_connection(server, config, stop).await?;
+        let guard = connections.admit(&peer);
+        Ok((stream, peer, guard))
     }
 
     pub fn run(
@@ -457,158 +1079,357 @@ impl<'a> HttpServer<'a> {
         address: &str,
         port: &str,
         config: HttpServerConfig,
-    ) -> (smol::Task<std::io::Result<()>>, smol::channel::Sender<()>) {
-        let (tx, rx) = smol::channel::bounded::<()>(1);
-        let (cancel_tx, cancel_rx) = smol::channel::bounded::<()>(1);
-        let address = address.to_string();
-        let port = port.to_string();
-        let callbacks = Arc::new(self.callbacks);
-        let task = smol::spawn(async move {
-            let server = TcpListener::bind(format!("{address}:{port}").as_str()).await?;
-            println!("Server listening on http://localhost:{port}/");
-                loop {
-                    let client_connection =
-                        match Self::accept_connection(&server, &config, rx.clone(), cancel_tx.clone())
-                            .await
-                        {
-                            Ok((stream, addr)) => (stream, addr),
-                            Err(AcceptError::Shutdown) => {
-                                println!("Server is shutting down.");
-                                break;
-                            }
-                            Err(AcceptError::IoError(e)) => {
-                                println!("Error accepting connection: {:?}", e);
-                                continue;
-                            }
-                        };
+    ) -> (smol::Task<std::io::Result<()>>, ServerHandle) {
+        self.serve(vec![Listener::http(format!("{address}:{port}"), config)])
+    }
 
-                    Self::run_connection(Arc::downgrade(&callbacks), config, cancel_rx.clone(), client_connection);
+    /// Like `run`, on a socket bound beforehand.
+    pub fn run_with_listener(
+        self,
+        listener: TcpListener,
+        config: HttpServerConfig,
+    ) -> (smol::Task<std::io::Result<()>>, ServerHandle) {
+        self.serve(vec![Listener::http(listener, config)])
+    }
 
-                }   
+    /// Serves the routes on every listener, e.g. HTTP on port 80 and HTTPS
+    /// on port 443. Dropping the returned handle shuts all of them down.
+    pub fn serve(self, listeners: Vec<Listener>) -> (smol::Task<std::io::Result<()>>, ServerHandle) {
+        let signal = Arc::new(ShutdownSignal::default());
+        let (finished_tx, finished_rx) = smol::channel::bounded::<()>(1);
+        let local_addrs = Arc::new(OnceCell::new());
+        let connections: Vec<Arc<Connections>> = listeners
+            .iter()
+            .map(|listener| Arc::new(Connections::new(listener.http_config().connection_config, signal.clone())))
+            .collect();
+        let handle = ServerHandle {
+            shutdown: signal.clone(),
+            local_addrs: local_addrs.clone(),
+            connections: connections.clone(),
+            finished: finished_rx,
+            health: self.health.clone(),
+            tls_reloads: listeners
+                .iter()
+                .filter_map(|listener| match listener {
+                    Listener::Https { config, .. } => Some(config.reload_handle()),
+                    _ => None,
+                })
+                .collect(),
+        };
+        let state = Arc::new(ServerState {
+            callbacks: self.callbacks,
+            scopes: self.scopes,
+            websockets: self.websockets,
+            middlewares: self.middlewares,
+            metrics: self.metrics,
+            error_renderer: self.error_renderer,
+        });
+        let (health, drain_delay) = (self.health, self.drain_delay);
+        let task = smol::spawn(async move {
+            // Dropped last, once every connection is closed.
+            let _finished = finished_tx;
+            // If a listener fails to bind, the ones already started are
+            // cancelled when `tasks` is dropped.
+            let mut stop_senders = Vec::new();
+            let mut tasks = Vec::new();
+            let mut addrs = Vec::new();
+            let started: std::io::Result<()> = async {
+                for (listener, connections) in listeners.into_iter().zip(connections) {
+                    let (stop_tx, stop_rx) = smol::channel::bounded::<Option<ShutdownMode>>(1);
+                    stop_senders.push(stop_tx);
+                    tasks.push(match listener {
+                        Listener::Http { address, config } => {
+                            let server = address.bind().await?;
+                            let addr = server.local_addr()?;
+                            addrs.push(ListenAddr::Tcp(addr));
+                            info!("Server listening on http://{addr}/");
+                            smol::spawn(Self::accept_loop(server, config, state.clone(), stop_rx, connections))
+                        }
+                        Listener::Https { address, config } => {
+                            // Fail before binding so a bad certificate doesn't leave a dead listener.
+                            let tls_config: SharedServerConfig = match crate::tls::server_config(&config) {
+                                Ok(tls_config) => Arc::new(RwLock::new(Arc::new(tls_config))),
+                                Err(e) => {
+                                    error!(error = %e, "Failed to start HTTPS server.");
+                                    return Err(e);
+                                }
+                            };
+                            let server = address.bind().await?;
+                            let addr = server.local_addr()?;
+                            addrs.push(ListenAddr::Tcp(addr));
+                            info!("HTTPS server listening on https://{addr}/");
+                            smol::spawn(Self::accept_tls_loop(server, *config, tls_config, state.clone(), stop_rx, connections))
+                        }
+                        Listener::RedirectToHttps { address, https_port, config } => {
+                            let server = address.bind().await?;
+                            let addr = server.local_addr()?;
+                            addrs.push(ListenAddr::Tcp(addr));
+                            info!("Redirecting http://{addr}/ to HTTPS");
+                            let redirect_state = Arc::new(redirect_state(https_port, state.metrics.clone()));
+                            smol::spawn(Self::accept_loop(server, config, redirect_state, stop_rx, connections))
+                        }
+                        #[cfg(unix)]
+                        Listener::Unix { path, permissions, config } => {
+                            let server = bind_unix(&path, permissions)?;
+                            addrs.push(ListenAddr::Unix(path.clone()));
+                            info!("Server listening on unix:{path}");
+                            let accept_loop = Self::accept_loop(server, config, state.clone(), stop_rx, connections);
+                            smol::spawn(async move {
+                                let result = accept_loop.await;
+                                let _ = std::fs::remove_file(&path);
+                                result
+                            })
+                        }
+                    });
+                }
+                Ok(())
+            }
+            .await;
+            // Wakes up `ServerHandle::local_addrs` whether or not binding succeeded.
+            let _ = local_addrs.set(addrs).await;
+            started?;
+
+            let mode = signal.requested_mode().await;
+            // Also covers dropping the handle.
+            health.begin_shutdown();
+            if !drain_delay.is_zero() && !matches!(mode, Some(ShutdownMode::Immediate)) {
+                info!(delay = ?drain_delay, "Failing readiness before the listeners stop.");
+                futures::select! {
+                    _ = FutureExt::fuse(smol::Timer::after(drain_delay)) => {}
+                    _ = signal.immediate().fuse() => {}
+                }
+            }
+            // An immediate shutdown may have been asked for meanwhile.
+            let mode = signal.requested_mode().await;
+            for stop_tx in &stop_senders {
+                let _ = stop_tx.try_send(mode);
+            }
+            for task in tasks {
+                task.await?;
+            }
             Ok(())
         });
-        (task, tx)
+        (task, handle)
     }
-    
-    pub fn run_connection< T: AsyncRead + AsyncWrite + Unpin + 'static + Send>(callbacks: Weak<Vec<HttpListener<Request, Response>>> , config: HttpServerConfig, cancellation_token: smol::channel::Receiver<()>, (connection, addr): (T, SocketAddr)) {
+
+    async fn accept_loop<L: Accept>(
+        server: L,
+        config: HttpServerConfig,
+        state: Arc<ServerState>,
+        stop: smol::channel::Receiver<Option<ShutdownMode>>,
+        connections: Arc<Connections>,
+    ) -> std::io::Result<()> {
+        loop {
+            let (client, peer, guard) =
+                match Self::accept_within_limits(&server, &config, &stop, &connections).await {
+                    Ok(accepted) => accepted,
+                    Err(AcceptError::Shutdown(mode)) => {
+                        info!("Server is shutting down.");
+                        drop(server);
+                        connections.shutdown(mode).await;
+                        break;
+                    }
+                    Err(AcceptError::IoError(e)) => {
+                        warning!(error = %e, "Error accepting connection.");
+                        continue;
+                    }
+                };
+            let Some(guard) = guard else {
+                warning!(peer = %peer, "Connection limit reached, rejecting connection.");
+                reject_connection(client, &config);
+                continue;
+            };
+
+            let info = ConnectionInfo {
+                guard: Some(guard),
+                ..ConnectionInfo::new(peer)
+            };
+            Self::spawn_connection(Arc::downgrade(&state), config, connections.cancellation_token(), client, info);
+        }
+        Ok(())
+    }
+
+    pub fn run_connection< T: AsyncRead + AsyncWrite + Unpin + 'static + Send, P: Into<Peer>>(state: Weak<ServerState> , config: HttpServerConfig, cancellation_token: smol::channel::Receiver<()>, (connection, peer): (T, P)) {
+        Self::spawn_connection(state, config, cancellation_token, connection, ConnectionInfo::new(peer.into()));
+    }
+
+    /// Serves an established TLS connection with the protocol chosen through ALPN.
+    pub fn run_tls_connection<T: AsyncRead + AsyncWrite + Unpin + 'static + Send, P: Into<Peer>>(state: Weak<ServerState>, config: HttpServerConfig, cancellation_token: smol::channel::Receiver<()>, (connection, peer): (futures_rustls::server::TlsStream<T>, P)) {
+        Self::spawn_tls_connection(state, config, cancellation_token, connection, ConnectionInfo::new(peer.into()));
+    }
+
+    fn spawn_tls_connection<T: AsyncRead + AsyncWrite + Unpin + 'static + Send>(state: Weak<ServerState>, config: HttpServerConfig, cancellation_token: smol::channel::Receiver<()>, connection: futures_rustls::server::TlsStream<T>, mut info: ConnectionInfo) {
+        let session = connection.get_ref().1;
+        let http2 = session.alpn_protocol() == Some(crate::http2::ALPN_PROTOCOL);
+        info.tls = Some(Arc::new(TlsInfo::from_connection(session)));
+        if http2 {
+            Self::spawn_http2_connection(state, config, cancellation_token, connection, info);
+        } else {
+            Self::spawn_connection(state, config, cancellation_token, connection, info);
+        }
+    }
+
+    fn spawn_connection<T: AsyncRead + AsyncWrite + Unpin + 'static + Send>(state: Weak<ServerState>, config: HttpServerConfig, cancellation_token: smol::channel::Receiver<()>, connection: T, info: ConnectionInfo) {
         
-        let callbacks = match callbacks.upgrade() {
-            Some(cbs) => cbs,
+        let state = match state.upgrade() {
+            Some(state) => state,
             None => {
-                println!("Callbacks have been dropped, closing connection from {}.", addr);
+                debug!(peer = %info.peer, "Callbacks have been dropped, closing connection.");
                 return;
             }
         };
-        smol::spawn(async move {
+        let peer = info.peer.clone();
+        smol::spawn(in_connection_span(&peer, async move {
+            let _open = state.metrics.connection_opened();
+            let connection = MeteredStream::new(connection, state.metrics.clone());
             match Self::handle_connection(
-                callbacks.as_ref(),
+                state.as_ref(),
                 config,
                 ClientSocket {
                     socket: connection,
-                    cancellation_token: cancellation_token,
+                    cancellation_token,
                     read_timeout: config.timeout_config.read_timeout_duration,
+                    write_timeout: config.timeout_config.write_timeout_duration,
+                    phase: ReadPhase::Streaming,
+                    draining: info.draining(),
                 },
+                info,
             )
             .await
             {
                 Ok(_) => {
-                    println!("Connection from {} closed.", addr);
+                    debug!("Connection closed.");
                 }
                 Err(e) => {
-                    println!("Error handling connection from {}: {:?}", addr, e);
+                    debug!(error = %e, "Error handling connection.");
                 }
             }
-        }).detach();
+        })).detach();
         
     } 
 
-    pub fn setup_https(
-        cert_path: &str,
-        key_path: &str,
-    ) -> std::io::Result<futures_rustls::TlsAcceptor> {
-        let certs = CertificateDer::from_pem_file(&cert_path).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to load certificate: {}", e),
-            )
-        })?;
-        let key = PrivateKeyDer::from_pem_file(&key_path).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to load private key: {}", e),
-            )
-        })?;
-
-        let tls_config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(vec![certs], key)
-            .map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Failed to create TLS config: {}", e),
-                )
-            })?;
+    /// Like `run_connection`, for connections that negotiated HTTP/2.
+    pub fn run_http2_connection<T: AsyncRead + AsyncWrite + Unpin + 'static + Send, P: Into<Peer>>(state: Weak<ServerState>, config: HttpServerConfig, cancellation_token: smol::channel::Receiver<()>, (connection, peer): (T, P)) {
+        Self::spawn_http2_connection(state, config, cancellation_token, connection, ConnectionInfo::new(peer.into()));
+    }
 
-        let acceptor = futures_rustls::TlsAcceptor::from(std::sync::Arc::new(tls_config));
+    fn spawn_http2_connection<T: AsyncRead + AsyncWrite + Unpin + 'static + Send>(state: Weak<ServerState>, config: HttpServerConfig, cancellation_token: smol::channel::Receiver<()>, connection: T, info: ConnectionInfo) {
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => {
+                debug!(peer = %info.peer, "Callbacks have been dropped, closing connection.");
+                return;
+            }
+        };
+        let peer = info.peer.clone();
+        smol::spawn(in_connection_span(&peer, async move {
+            let _open = state.metrics.connection_opened();
+            let connection = MeteredStream::new(connection, state.metrics.clone());
+            match serve_http2(state.as_ref(), config, connection, cancellation_token, Vec::new(), None, info).await {
+                Ok(_) => {
+                    debug!("HTTP/2 connection closed.");
+                }
+                Err(e) => {
+                    debug!(error = %e, "Error handling HTTP/2 connection.");
+                }
+            }
+        })).detach();
+    }
 
-        Ok(acceptor)
+    pub fn setup_https(config: &HttpsServerConfig) -> std::io::Result<futures_rustls::TlsAcceptor> {
+        let tls_config = crate::tls::server_config(config)?;
+        Ok(futures_rustls::TlsAcceptor::from(Arc::new(tls_config)))
     }
 
     pub fn run_https(
         self,
         address: &str,
         port: &str,
-        cert_path: &str,
-        key_path: &str,
-        config: HttpServerConfig,
-    ) -> (smol::Task<std::io::Result<()>>, smol::channel::Sender<()>) {
-        let (tx, rx) = smol::channel::bounded::<()>(1);
-        let (cancel_tx, cancel_rx) = smol::channel::bounded::<()>(1);
-        let address = address.to_string();
-        let port = port.to_string();
-        let callbacks = Arc::new(self.callbacks);
-        let cert_path = cert_path.to_string();
-        let key_path = key_path.to_string();
-        let task = smol::spawn(async move {
-            let server = TcpListener::bind(format!("{address}:{port}").as_str()).await?;
-            println!("HTTPS Server listening on https://localhost:{port}/");
+        https_config: HttpsServerConfig,
+    ) -> (smol::Task<std::io::Result<()>>, ServerHandle) {
+        self.serve(vec![Listener::https(format!("{address}:{port}"), https_config)])
+    }
 
-            let acceptor = Arc::new(Self::setup_https(&cert_path, &key_path)?);
-            loop {
-                let client_connection =
-                    match Self::accept_connection(&server, &config, rx.clone(), cancel_tx.clone())
-                        .await
-                    {
-                        Ok((stream, addr)) => (stream, addr),
-                        Err(AcceptError::Shutdown) => {
-                            println!("Server is shutting down.");
-                            break;
-                        }
-                        Err(AcceptError::IoError(e)) => {
-                            println!("Error accepting connection: {:?}", e);
-                            continue;
-                        }
-                    };
+    async fn accept_tls_loop(
+        server: TcpListener,
+        https_config: HttpsServerConfig,
+        tls_config: SharedServerConfig,
+        state: Arc<ServerState>,
+        stop: smol::channel::Receiver<Option<ShutdownMode>>,
+        connections: Arc<Connections>,
+    ) -> std::io::Result<()> {
+        let config = https_config.http_config;
+        let _reload_tasks = spawn_reload_tasks(&https_config, tls_config.clone());
+        let handshakes = Arc::new(Semaphore::new(https_config.max_concurrent_handshakes));
+
+        loop {
+            let (client, peer, guard) =
+                match Self::accept_within_limits(&server, &config, &stop, &connections).await {
+                    Ok(accepted) => accepted,
+                    Err(AcceptError::Shutdown(mode)) => {
+                        info!("Server is shutting down.");
+                        drop(server);
+                        connections.shutdown(mode).await;
+                        break;
+                    }
+                    Err(AcceptError::IoError(e)) => {
+                        warning!(error = %e, "Error accepting connection.");
+                        continue;
+                    }
+                };
+            // TLS clients can't be told why before the handshake.
+            let Some(guard) = guard else {
+                warning!(peer = %peer, "Connection limit reached, dropping connection.");
+                continue;
+            };
 
-                let (client, addr) = client_connection;
-                let acceptor = acceptor.clone();
+            let permit = match handshakes.try_acquire_arc() {
+                Some(permit) => permit,
+                None => {
+                    warning!(peer = %peer, "Too many TLS handshakes in progress, dropping connection.");
+                    continue;
+                }
+            };
+            // Picks up reloaded certificates for every new handshake.
+            let acceptor = futures_rustls::TlsAcceptor::from(
+                tls_config.read().unwrap_or_else(|e| e.into_inner()).clone(),
+            );
+            let metrics = state.metrics.clone();
+            let state = Arc::downgrade(&state);
+            let cancellation_token = connections.cancellation_token();
+            let info = ConnectionInfo {
+                guard: Some(guard),
+                ..ConnectionInfo::new(peer)
+            };
 
-                match acceptor.accept(client).await {
-                    Ok(tls_stream) => {
-                        Self::run_connection(Arc::downgrade(&callbacks), config, cancel_rx.clone(), (tls_stream, addr));
+            // Handshake in the connection's task so a slow client doesn't hold up the accept loop.
+            smol::spawn(async move {
+                let handshake = futures::select! {
+                    result = acceptor.accept(client).fuse() => Some(result),
+                    _ = smol::Timer::after(config.timeout_config.handshake_timeout_duration).fuse() => None,
+                    _ = cancellation_token.recv().fuse() => return,
+                };
+                drop(permit);
+                match handshake {
+                    Some(Ok(tls_stream)) => {
+                        Self::spawn_tls_connection(state, config, cancellation_token, tls_stream, info);
                     }
-                    Err(e) => {
-                        println!("TLS handshake failed with {}: {:?}", addr, e);
+                    Some(Err(e)) => {
+                        debug!(peer = %info.peer, error = %e, "TLS handshake failed.");
+                        metrics.record_tls_handshake_failure(TlsHandshakeFailure::Error);
+                    }
+                    None => {
+                        debug!(peer = %info.peer, "TLS handshake timed out.");
+                        metrics.record_tls_handshake_failure(TlsHandshakeFailure::Timeout);
                     }
                 }
-            }
-            Ok(())
-        });
-        (task, tx)
+            }).detach();
+        }
+        Ok(())
     }
 }
 
-impl<'a> HttpCallbacks for HttpServer<'a> {
+impl HttpCallbacks for HttpServer {
     type Request = Request;
 
     type Response = Response;
@@ -618,8 +1439,8 @@ impl<'a> HttpCallbacks for HttpServer<'a> {
     }
 }
 
-impl<'a> HttpMiddleware<'a> for HttpServer<'a> {
-    fn add_middleware(&mut self, middleware_type: MiddlewareType, handler: MiddlewareHandler<'a>) {
+impl HttpMiddleware for HttpServer {
+    fn add_middleware(&mut self, middleware_type: MiddlewareType, handler: MiddlewareHandler) {
         self.middlewares.push(MiddlewareEntry {
             middleware_type,
             handler,
@@ -632,5 +1453,8 @@ pub mod prelude {
     pub use super::{HttpMiddleware, MiddlewareEntry, MiddlewareType};
     pub use crate::middleware::PathParameter;
     pub use crate::middleware::MiddlewareResult;
+    pub use crate::middleware::MiddlewareLayer;
     pub use super::HttpServer;
+    pub use super::Listener;
+    pub use crate::router::Router;
 }
@@ -1,8 +1,9 @@
 #[non_exhaustive]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum HttpVersion {
     Http1_0,
-    Http1_1
+    Http1_1,
+    Http2,
 }
 
 pub fn parse_http_version(input: &str) -> Option<HttpVersion> {
@@ -10,4 +10,21 @@ pub mod mime_type;
 pub mod map;
 pub mod http_version;
 pub mod http_server_trait;
-pub mod middleware;
\ No newline at end of file
+pub mod middleware;
+pub mod cookie;
+pub mod session;
+pub mod websocket;
+pub mod sse;
+pub mod http2;
+pub mod tls;
+pub mod listener;
+pub mod connections;
+pub mod access_log;
+pub mod request_id;
+pub mod metrics;
+pub mod health;
+pub mod error_renderer;
+pub mod router;
+pub mod cors;
+pub mod security_headers;
+mod logging;
@@ -0,0 +1,399 @@
+mod test;
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::{AsyncRead, AsyncWrite};
+use smol::net::TcpListener;
+
+use crate::connections::ConnectionGuard;
+use crate::error_renderer::ErrorRenderer;
+use crate::metrics::Metrics;
+use crate::http_server::{HttpServerConfig, ServerState};
+use crate::middleware::{MiddlewareEntry, MiddlewareHandler, MiddlewareResult, MiddlewareType, PathParameter};
+use crate::http_method::HttpMethod;
+use crate::request::Request;
+use crate::response::{Response, redirect};
+use crate::tls::{HttpsServerConfig, TlsInfo};
+
+/// An address the server accepts connections on and how they are served.
+/// Every listener of a server shares its routes and middlewares.
+pub enum Listener {
+    Http {
+        address: Bind,
+        config: HttpServerConfig,
+    },
+    Https {
+        address: Bind,
+        config: Box<HttpsServerConfig>,
+    },
+    /// Answers every request with a redirect to the same URL over HTTPS,
+    /// without running the routes or middlewares.
+    RedirectToHttps {
+        address: Bind,
+        /// The port of the HTTPS listener, left out of the URL when 443.
+        https_port: u16,
+        config: HttpServerConfig,
+    },
+    /// Plain HTTP on a Unix domain socket, e.g. behind a reverse proxy.
+    #[cfg(unix)]
+    Unix {
+        path: String,
+        /// The mode of the socket file, e.g. `0o660`. Left to the umask when
+        /// `None`.
+        permissions: Option<u32>,
+        config: HttpServerConfig,
+    },
+}
+
+impl Listener {
+    pub fn http<A: Into<Bind>>(address: A, config: HttpServerConfig) -> Self {
+        Listener::Http {
+            address: address.into(),
+            config,
+        }
+    }
+
+    pub fn https<A: Into<Bind>>(address: A, config: HttpsServerConfig) -> Self {
+        Listener::Https {
+            address: address.into(),
+            config: Box::new(config),
+        }
+    }
+
+    pub fn redirect_to_https<A: Into<Bind>>(address: A, https_port: u16) -> Self {
+        Listener::RedirectToHttps {
+            address: address.into(),
+            https_port,
+            config: HttpServerConfig::default(),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn unix<P: Into<String>>(path: P, config: HttpServerConfig) -> Self {
+        Listener::Unix {
+            path: path.into(),
+            permissions: None,
+            config,
+        }
+    }
+
+    pub(crate) fn http_config(&self) -> &HttpServerConfig {
+        match self {
+            Listener::Http { config, .. } | Listener::RedirectToHttps { config, .. } => config,
+            Listener::Https { config, .. } => &config.http_config,
+            #[cfg(unix)]
+            Listener::Unix { config, .. } => config,
+        }
+    }
+}
+
+/// Where a TCP listener gets its socket from.
+pub enum Bind {
+    /// e.g. `0.0.0.0:80`, or `127.0.0.1:0` for any free port.
+    Address(String),
+    /// A socket bound beforehand, e.g. handed over by the previous process
+    /// or by `systemd_listeners`.
+    Listener(TcpListener),
+}
+
+impl Bind {
+    pub(crate) async fn bind(self) -> std::io::Result<TcpListener> {
+        match self {
+            Bind::Address(address) => TcpListener::bind(address.as_str()).await,
+            Bind::Listener(listener) => Ok(listener),
+        }
+    }
+}
+
+impl From<&str> for Bind {
+    fn from(address: &str) -> Self {
+        Bind::Address(address.to_string())
+    }
+}
+
+impl From<String> for Bind {
+    fn from(address: String) -> Self {
+        Bind::Address(address)
+    }
+}
+
+impl From<TcpListener> for Bind {
+    fn from(listener: TcpListener) -> Self {
+        Bind::Listener(listener)
+    }
+}
+
+/// The sockets passed through systemd socket activation (`LISTEN_PID` and
+/// `LISTEN_FDS`), in the order of the socket unit. Empty when the process
+/// wasn't socket activated. The sockets are only handed out once, later
+/// calls return nothing, and the activation variables are removed from the
+/// environment so child processes don't pick them up.
+///
+/// Fails without taking any descriptor if one of them isn't a listening
+/// TCP socket.
+#[cfg(unix)]
+pub fn systemd_listeners() -> std::io::Result<Vec<TcpListener>> {
+    use std::os::fd::{BorrowedFd, FromRawFd, OwnedFd};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use rustix::io::{FdFlags, fcntl_setfd};
+    use rustix::net::{AddressFamily, SocketType, getsockname, sockopt};
+
+    const SD_LISTEN_FDS_START: i32 = 3;
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+
+    let for_this_process = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    if !for_this_process || TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    // SAFETY: as with sd_listen_fds(3), this relies on no other thread
+    // touching the environment while the sockets are taken.
+    unsafe {
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let fds = SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count;
+    for fd in fds.clone() {
+        // SAFETY: systemd passes these descriptors to this process, they
+        // stay open while borrowed here.
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        let family = getsockname(fd)?.address_family();
+        let is_tcp = (family == AddressFamily::INET || family == AddressFamily::INET6)
+            && sockopt::socket_type(fd)? == SocketType::STREAM;
+        #[cfg(not(target_vendor = "apple"))]
+        let is_tcp = is_tcp && sockopt::socket_acceptconn(fd)?;
+        if !is_tcp {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Socket activation passed a socket that isn't a listening TCP socket",
+            ));
+        }
+    }
+    fds.map(|fd| {
+        // SAFETY: checked above, and `TAKEN` makes sure the descriptors are
+        // only wrapped once.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // Processes spawned by handlers shouldn't inherit the sockets.
+        fcntl_setfd(&fd, FdFlags::CLOEXEC)?;
+        TcpListener::try_from(std::net::TcpListener::from(fd))
+    })
+    .collect()
+}
+
+/// The other end of a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Unix socket clients are identified by their credentials, `None` where
+    /// the platform doesn't report them.
+    Unix(Option<PeerCredentials>),
+}
+
+/// The address a listener accepts connections on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// The path of a Unix socket.
+    Unix(String),
+}
+
+impl ListenAddr {
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            ListenAddr::Tcp(addr) => Some(*addr),
+            ListenAddr::Unix(_) => None,
+        }
+    }
+}
+
+/// The process that connected to a Unix socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer::Tcp(addr)
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix(Some(credentials)) => write!(f, "unix:pid={},uid={}", credentials.pid, credentials.uid),
+            Peer::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+/// Who a connection is with, shared by the requests it carries.
+pub(crate) struct ConnectionInfo {
+    pub peer: Peer,
+    pub tls: Option<Arc<TlsInfo>>,
+    /// Set for connections accepted by the server, `None` for connections
+    /// handed to `run_connection`.
+    pub guard: Option<ConnectionGuard>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(peer: Peer) -> Self {
+        ConnectionInfo {
+            peer,
+            tls: None,
+            guard: None,
+        }
+    }
+
+    pub(crate) fn apply(&self, req: &mut Request) {
+        req.peer = Some(self.peer.clone());
+        req.tls = self.tls.clone();
+    }
+
+    /// Whether the server is shutting down and the connection shouldn't take
+    /// new requests.
+    pub(crate) fn is_draining(&self) -> bool {
+        self.guard.as_ref().is_some_and(ConnectionGuard::is_draining)
+    }
+
+    pub(crate) fn draining(&self) -> Option<smol::channel::Receiver<()>> {
+        self.guard.as_ref().map(ConnectionGuard::draining)
+    }
+
+    /// Resolves once the server starts draining, never for untracked connections.
+    pub(crate) async fn drained(&self) {
+        match self.draining() {
+            Some(draining) => {
+                let _ = draining.recv().await;
+            }
+            None => futures::future::pending::<()>().await,
+        }
+    }
+}
+
+/// A bound socket the server accepts connections from.
+pub trait Accept {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&self) -> impl Future<Output = std::io::Result<(Self::Stream, Peer)>> + Send;
+}
+
+impl Accept for TcpListener {
+    type Stream = smol::net::TcpStream;
+
+    async fn accept(&self) -> std::io::Result<(Self::Stream, Peer)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+        Ok((stream, Peer::Tcp(addr)))
+    }
+}
+
+#[cfg(unix)]
+impl Accept for smol::net::unix::UnixListener {
+    type Stream = smol::net::unix::UnixStream;
+
+    async fn accept(&self) -> std::io::Result<(Self::Stream, Peer)> {
+        let (stream, _) = smol::net::unix::UnixListener::accept(self).await?;
+        let credentials = peer_credentials(&stream);
+        Ok((stream, Peer::Unix(credentials)))
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(stream: &smol::net::unix::UnixStream) -> Option<PeerCredentials> {
+    let credentials = rustix::net::sockopt::socket_peercred(stream).ok()?;
+    Some(PeerCredentials {
+        pid: credentials.pid.as_raw_nonzero().get(),
+        uid: credentials.uid.as_raw(),
+        gid: credentials.gid.as_raw(),
+    })
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn peer_credentials(_stream: &smol::net::unix::UnixStream) -> Option<PeerCredentials> {
+    None
+}
+
+/// Binds a Unix socket at `path`, replacing the socket file left behind by a
+/// server that didn't shut down cleanly. A socket that still accepts
+/// connections, or a file that isn't a socket, is left alone.
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &str, permissions: Option<u32>) -> std::io::Result<smol::net::unix::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("'{}' exists and is not a socket", path),
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("Another server is listening on '{}'", path),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = smol::net::unix::UnixListener::bind(path)?;
+    if let Some(mode) = permissions {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// The redirect to `https://` for a request received in cleartext. GET and
+/// HEAD get a `301`, other methods a `308` so the client repeats them with
+/// the same method and body.
+pub(crate) fn https_redirect(req: &Request, https_port: u16) -> Response {
+    let host = req.headers.get_single("host").map(String::as_str).unwrap_or("localhost");
+    // Drop the port, keeping IPv6 literals such as `[::1]` intact.
+    let hostname = match host.find(']') {
+        Some(end) => &host[..=end],
+        None => host.split(':').next().unwrap_or(host),
+    };
+    let authority = if https_port == 443 {
+        hostname.to_string()
+    } else {
+        format!("{}:{}", hostname, https_port)
+    };
+    let separator = if req.path.starts_with('/') { "" } else { "/" };
+    let status = match req.method {
+        HttpMethod::GET | HttpMethod::HEAD => 301,
+        _ => 308,
+    };
+    redirect(format!("https://{}{}{}", authority, separator, req.path)).status(status)
+}
+
+/// The state of a `RedirectToHttps` listener: no routes and a single
+/// middleware answering every request.
+pub(crate) fn redirect_state(https_port: u16, metrics: Arc<Metrics>) -> ServerState {
+    ServerState {
+        callbacks: vec![],
+        scopes: vec![],
+        websockets: vec![],
+        middlewares: vec![MiddlewareEntry {
+            middleware_type: MiddlewareType::PreRequest(PathParameter::Wildcard),
+            handler: MiddlewareHandler::PreRequest(Arc::new(move |req: &mut Request| {
+                MiddlewareResult::SendResponseAndStopProcessing(https_redirect(req, https_port))
+            })),
+        }],
+        metrics,
+        error_renderer: ErrorRenderer::default(),
+    }
+}
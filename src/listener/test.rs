@@ -0,0 +1,48 @@
+#![cfg(test)]
+
+use crate::http_method::HttpMethod;
+use crate::listener::*;
+use crate::map::{DuplicateMap, Map};
+use crate::request::Request;
+
+fn request(method: HttpMethod, host: &str, path: &str) -> Request {
+    let mut headers: Map<DuplicateMap> = Map::default();
+    headers.add("host", host.to_string());
+    Request {
+        method,
+        path: path.to_string(),
+        headers,
+        ..Default::default()
+    }
+}
+
+fn location(response: &crate::response::Response) -> Option<&str> {
+    response
+        .headers
+        .iter()
+        .find(|(name, _)| name == "Location")
+        .map(|(_, value)| value.as_str())
+}
+
+#[test]
+fn test_redirect_keeps_path_and_query() {
+    let response = https_redirect(&request(HttpMethod::GET, "example.com", "/a/b?c=d"), 443);
+    assert_eq!(response.status_code.code, 301);
+    assert_eq!(location(&response), Some("https://example.com/a/b?c=d"));
+}
+
+#[test]
+fn test_redirect_replaces_port() {
+    let response = https_redirect(&request(HttpMethod::GET, "example.com:8080", "/"), 8443);
+    assert_eq!(location(&response), Some("https://example.com:8443/"));
+
+    let response = https_redirect(&request(HttpMethod::HEAD, "[::1]:80", "/"), 443);
+    assert_eq!(response.status_code.code, 301);
+    assert_eq!(location(&response), Some("https://[::1]/"));
+}
+
+#[test]
+fn test_redirect_preserves_method() {
+    let response = https_redirect(&request(HttpMethod::POST, "example.com", "/form"), 443);
+    assert_eq!(response.status_code.code, 308);
+}
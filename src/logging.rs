@@ -0,0 +1,115 @@
+//! Log events of the server, forwarded to `tracing` with the `tracing`
+//! feature and compiled out otherwise.
+//!
+//! The macros take the `tracing` syntax: optional `name = value`,
+//! `name = %value` or `name = ?value` fields followed by a format string.
+
+#[cfg(feature = "tracing")]
+macro_rules! event {
+    ($level:ident, $($args:tt)+) => {
+        tracing::$level!($($args)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! event {
+    ($level:ident, $($args:tt)+) => {
+        if false {
+            $crate::logging::unused!($($args)+);
+        }
+    };
+}
+
+/// Keeps the arguments of a disabled event type checked and used.
+#[cfg(not(feature = "tracing"))]
+macro_rules! unused {
+    ($name:ident = % $value:expr, $($rest:tt)+) => {
+        let _ = &$value;
+        $crate::logging::unused!($($rest)+);
+    };
+    ($name:ident = ? $value:expr, $($rest:tt)+) => {
+        let _ = &$value;
+        $crate::logging::unused!($($rest)+);
+    };
+    ($name:ident = $value:expr, $($rest:tt)+) => {
+        let _ = &$value;
+        $crate::logging::unused!($($rest)+);
+    };
+    ($format:literal $(, $arg:expr)* $(,)?) => {
+        let _ = format_args!($format $(, $arg)*);
+    };
+}
+
+macro_rules! error {
+    ($($args:tt)+) => { $crate::logging::event!(error, $($args)+) };
+}
+
+// `warn` would clash with the built-in lint attribute.
+macro_rules! warning {
+    ($($args:tt)+) => { $crate::logging::event!(warn, $($args)+) };
+}
+
+macro_rules! info {
+    ($($args:tt)+) => { $crate::logging::event!(info, $($args)+) };
+}
+
+macro_rules! debug {
+    ($($args:tt)+) => { $crate::logging::event!(debug, $($args)+) };
+}
+
+pub(crate) use {debug, error, event, info, warning};
+#[cfg(not(feature = "tracing"))]
+pub(crate) use unused;
+
+/// Runs `future` inside a span for the connection with `peer`, so the events
+/// it logs carry the peer address.
+#[cfg(feature = "tracing")]
+pub(crate) fn in_connection_span<F: Future>(peer: &crate::listener::Peer, future: F) -> impl Future<Output = F::Output> + use<F> {
+    tracing::Instrument::instrument(future, tracing::info_span!("connection", peer = %peer))
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn in_connection_span<F: Future>(_peer: &crate::listener::Peer, future: F) -> F {
+    future
+}
+
+/// The span of a request, carrying its method, path, id and trace id.
+pub(crate) struct RequestSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl RequestSpan {
+    #[cfg(feature = "tracing")]
+    pub(crate) fn new(request: &crate::request::Request) -> Self {
+        let span = tracing::info_span!(
+            "request",
+            method = ?request.method,
+            path = %request.path,
+            request_id = tracing::field::Empty,
+            trace_id = tracing::field::Empty,
+        );
+        if let Some(id) = request.request_id() {
+            span.record("request_id", id);
+        }
+        if let Some(trace) = request.trace_context() {
+            span.record("trace_id", trace.trace_id.as_str());
+        }
+        RequestSpan { span }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn new(_request: &crate::request::Request) -> Self {
+        RequestSpan {}
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn instrument<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+        tracing::Instrument::instrument(future, self.span)
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn instrument<F: Future>(self, future: F) -> F {
+        future
+    }
+}
@@ -1,3 +1,4 @@
+use http_server::access_log::{AccessLog, AccessLogFormat};
 use http_server::http_server::prelude::*;
 
 use http_server::status_code::NOT_FOUND;
@@ -7,6 +8,7 @@ use http_server::{response::{bytes, status, text}, status_code::OK};
 
 fn main() -> std::io::Result<()> {
     let mut server = HttpServer::new();
+    server.layer(AccessLog::stdout(AccessLogFormat::Common));
     
     
     let home_dir = if std::env::args().nth(1).is_some_and(|c| c == "--directory") {
@@ -0,0 +1,271 @@
+mod test;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncWrite};
+
+use crate::request::{Request, RequestParsingError};
+use crate::response::{Response, text};
+
+/// Upper bounds of the latency buckets in seconds, the Prometheus client defaults.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+const PARSE_ERRORS: [&str; 9] = [
+    "UnhandledRequest",
+    "InvalidRequest",
+    "InvalidHeader",
+    "InvalidBody",
+    "PayloadTooLarge",
+    "IoError",
+    "Timeout",
+    "Cancellation",
+    "UnexpectedError",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TlsHandshakeFailure {
+    Error,
+    Timeout,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Counters of a server, shared by all of its listeners. Mount them with
+/// `let metrics = server.metrics(); server.get("/metrics", metrics.handler());`.
+///
+/// Requests are labelled with the pattern of the route that answered them,
+/// e.g. `/users/:id`, and an empty route when none did.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    latencies: Mutex<BTreeMap<(String, String), Histogram>>,
+    in_flight: AtomicI64,
+    open_connections: AtomicI64,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    tls_handshake_failures: [AtomicU64; 2],
+    parse_errors: [AtomicU64; PARSE_ERRORS.len()],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A route handler answering with the metrics in the Prometheus text format.
+    pub fn handler(self: &Arc<Self>) -> impl Fn(Request) -> Response + Send + Sync + 'static {
+        let metrics = self.clone();
+        move |_| text(metrics.render()).content_type("text/plain; version=0.0.4")
+    }
+
+    pub fn in_flight_requests(&self) -> i64 {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn open_connections(&self) -> i64 {
+        self.open_connections.load(Ordering::SeqCst)
+    }
+
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Counts a request as in flight until the guard is dropped.
+    pub(crate) fn request_started(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard { metrics: self.clone() }
+    }
+
+    /// Counts an open connection until the guard is dropped.
+    pub(crate) fn connection_opened(self: &Arc<Self>) -> ConnectionMetricsGuard {
+        self.open_connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionMetricsGuard { metrics: self.clone() }
+    }
+
+    /// Records an answered request, timed from when it started being read.
+    pub(crate) fn record_request(&self, request: &Request, status: u16) {
+        self.observe(
+            request.route().unwrap_or(""),
+            &format!("{:?}", request.method),
+            status,
+            request.received_at().elapsed(),
+        );
+    }
+
+    pub(crate) fn observe(&self, route: &str, method: &str, status: u16, duration: Duration) {
+        let key = (route.to_string(), method.to_string());
+        *self
+            .requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry((key.0.clone(), key.1.clone(), status))
+            .or_insert(0) += 1;
+
+        let seconds = duration.as_secs_f64();
+        let mut latencies = self.latencies.lock().unwrap_or_else(|e| e.into_inner());
+        let histogram = latencies.entry(key).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    pub(crate) fn record_parse_error(&self, error: &RequestParsingError) {
+        let index = match error {
+            RequestParsingError::UnhandledRequest => 0,
+            RequestParsingError::InvalidRequest => 1,
+            RequestParsingError::InvalidHeader => 2,
+            RequestParsingError::InvalidBody => 3,
+            RequestParsingError::PayloadTooLarge => 4,
+            RequestParsingError::IoError(_) => 5,
+            RequestParsingError::Timeout => 6,
+            RequestParsingError::Cancellation => 7,
+            RequestParsingError::UnexpectedError => 8,
+        };
+        self.parse_errors[index].fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record_tls_handshake_failure(&self, failure: TlsHandshakeFailure) {
+        self.tls_handshake_failures[failure as usize].fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "http_requests_total", "counter", "Requests answered, by route, method and status.");
+        for ((route, method, status), count) in self.requests.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                escape_label(route),
+                method,
+                status,
+                count
+            );
+        }
+
+        header(&mut out, "http_request_duration_seconds", "histogram", "Time from reading a request to answering it.");
+        for ((route, method), histogram) in self.latencies.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            let labels = format!("route=\"{}\",method=\"{}\"", escape_label(route), method);
+            for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(out, "http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {bucket}");
+            }
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "http_request_duration_seconds_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "http_request_duration_seconds_count{{{labels}}} {}", histogram.count);
+        }
+
+        header(&mut out, "http_requests_in_flight", "gauge", "Requests being handled.");
+        let _ = writeln!(out, "http_requests_in_flight {}", self.in_flight_requests());
+
+        header(&mut out, "http_open_connections", "gauge", "Open client connections.");
+        let _ = writeln!(out, "http_open_connections {}", self.open_connections());
+
+        header(&mut out, "http_received_bytes_total", "counter", "Bytes read from clients, after TLS decryption.");
+        let _ = writeln!(out, "http_received_bytes_total {}", self.received_bytes());
+
+        header(&mut out, "http_sent_bytes_total", "counter", "Bytes written to clients, before TLS encryption.");
+        let _ = writeln!(out, "http_sent_bytes_total {}", self.sent_bytes());
+
+        header(&mut out, "tls_handshake_failures_total", "counter", "TLS handshakes that failed or timed out.");
+        for (reason, count) in ["error", "timeout"].iter().zip(&self.tls_handshake_failures) {
+            let _ = writeln!(out, "tls_handshake_failures_total{{reason=\"{reason}\"}} {}", count.load(Ordering::SeqCst));
+        }
+
+        header(&mut out, "http_request_parse_errors_total", "counter", "Requests that couldn't be parsed, by error.");
+        for (name, count) in PARSE_ERRORS.iter().zip(&self.parse_errors) {
+            let _ = writeln!(out, "http_request_parse_errors_total{{error=\"{name}\"}} {}", count.load(Ordering::SeqCst));
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub(crate) struct InFlightGuard {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.metrics.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) struct ConnectionMetricsGuard {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ConnectionMetricsGuard {
+    fn drop(&mut self) {
+        self.metrics.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A connection counting the bytes read from and written to it.
+pub(crate) struct MeteredStream<T> {
+    inner: T,
+    metrics: Arc<Metrics>,
+}
+
+impl<T> MeteredStream<T> {
+    pub(crate) fn new(inner: T, metrics: Arc<Metrics>) -> Self {
+        MeteredStream { inner, metrics }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for MeteredStream<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = poll {
+            self.metrics.received_bytes.fetch_add(read as u64, Ordering::SeqCst);
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for MeteredStream<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.metrics.sent_bytes.fetch_add(written as u64, Ordering::SeqCst);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,45 @@
+#![cfg(test)]
+
+use std::time::Duration;
+
+use crate::metrics::*;
+use crate::request::RequestParsingError;
+
+#[test]
+fn test_render_requests_and_latencies() {
+    let metrics = Metrics::new();
+    metrics.observe("/users/:id", "GET", 200, Duration::from_millis(30));
+    metrics.observe("/users/:id", "GET", 200, Duration::from_secs(3));
+    metrics.observe("/users/:id", "GET", 404, Duration::from_millis(1));
+
+    let output = metrics.render();
+    assert!(output.contains("# TYPE http_requests_total counter\n"));
+    assert!(output.contains("http_requests_total{route=\"/users/:id\",method=\"GET\",status=\"200\"} 2\n"));
+    assert!(output.contains("http_requests_total{route=\"/users/:id\",method=\"GET\",status=\"404\"} 1\n"));
+    assert!(output.contains("http_request_duration_seconds_bucket{route=\"/users/:id\",method=\"GET\",le=\"0.005\"} 1\n"));
+    assert!(output.contains("http_request_duration_seconds_bucket{route=\"/users/:id\",method=\"GET\",le=\"0.05\"} 2\n"));
+    assert!(output.contains("http_request_duration_seconds_bucket{route=\"/users/:id\",method=\"GET\",le=\"2.5\"} 2\n"));
+    assert!(output.contains("http_request_duration_seconds_bucket{route=\"/users/:id\",method=\"GET\",le=\"+Inf\"} 3\n"));
+    assert!(output.contains("http_request_duration_seconds_count{route=\"/users/:id\",method=\"GET\"} 3\n"));
+}
+
+#[test]
+fn test_render_counts_every_parse_error() {
+    let metrics = Metrics::new();
+    metrics.record_parse_error(&RequestParsingError::Timeout);
+    metrics.record_parse_error(&RequestParsingError::Timeout);
+    metrics.record_parse_error(&RequestParsingError::IoError(std::io::ErrorKind::Other.into()));
+
+    let output = metrics.render();
+    assert!(output.contains("http_request_parse_errors_total{error=\"Timeout\"} 2\n"));
+    assert!(output.contains("http_request_parse_errors_total{error=\"IoError\"} 1\n"));
+    assert!(output.contains("http_request_parse_errors_total{error=\"InvalidHeader\"} 0\n"));
+    assert!(output.contains("tls_handshake_failures_total{reason=\"timeout\"} 0\n"));
+}
+
+#[test]
+fn test_escape_label_values() {
+    let metrics = Metrics::new();
+    metrics.observe("/a\"b\\c", "GET", 200, Duration::ZERO);
+    assert!(metrics.render().contains("route=\"/a\\\"b\\\\c\""));
+}
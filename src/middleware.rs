@@ -1,7 +1,8 @@
-use std::borrow::Cow;
+use std::sync::Arc;
 
 use crate::{request::Request, response::Response};
 
+#[derive(Clone)]
 pub enum PathParameter {
     Exact(String),
     Begin(String),
@@ -10,43 +11,144 @@ pub enum PathParameter {
     Wildcard,
 }
 
+impl PathParameter {
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathParameter::Exact(p) => path == p,
+            PathParameter::Begin(p) => path.starts_with(p.as_str()),
+            PathParameter::End(p) => path.ends_with(p.as_str()),
+            PathParameter::Contains(p) => path.contains(p.as_str()),
+            PathParameter::Wildcard => true,
+        }
+    }
+}
+
 pub enum MiddlewareType {
     PreRequest(PathParameter),
     PostRequest(PathParameter),
     ErrorHandler(PathParameter),
+    Logger(PathParameter),
 }
 
-pub enum MiddlewareHandler<'a> {
-    PreRequest(fn(&mut Request) -> MiddlewareResult),
-    PostRequest(fn(&Request, &'a mut Response) -> MiddlewareResult<'a>),
-    ErrorHandler(fn (&Request, &'a mut Response) -> MiddlewareResult<'a>),
+pub type PreRequestHandler = Arc<dyn Fn(&mut Request) -> MiddlewareResult + Send + Sync>;
+pub type PostRequestHandler = Arc<dyn Fn(&Request, &mut Response) -> MiddlewareResult + Send + Sync>;
+pub type LoggerHandler = Arc<dyn Fn(&Request, &Response) + Send + Sync>;
+
+#[derive(Clone)]
+pub enum MiddlewareHandler {
+    PreRequest(PreRequestHandler),
+    PostRequest(PostRequestHandler),
+    ErrorHandler(PostRequestHandler),
+    Logger(LoggerHandler),
 }
 
-pub enum MiddlewareResult<'a> {
+pub enum MiddlewareResult {
     NextMiddleware,
     SkipMiddlewares,
-    SendResponseAndStopProcessing(Cow<'a, Response>)
+    SendResponseAndStopProcessing(Response)
 }
 
-pub struct MiddlewareEntry<'a> {
+pub struct MiddlewareEntry {
     pub middleware_type: MiddlewareType,
-    pub handler: MiddlewareHandler<'a>,
+    pub handler: MiddlewareHandler,
 }
 
+/// A group of middlewares that are registered together, e.g. a pre-request
+/// hook that loads some state and a post-request hook that persists it.
+pub trait MiddlewareLayer {
+    fn into_middlewares(self) -> Vec<MiddlewareEntry>;
+}
+
+
+pub trait HttpMiddleware {
 
-pub trait HttpMiddleware<'a> {
-    
-    fn add_middleware(&mut self, middleware_type: MiddlewareType, handler: MiddlewareHandler<'a>);
-    
-    fn pre_request(&mut self, path: PathParameter, handler: fn(&mut Request) -> MiddlewareResult<'_>) {
-        self.add_middleware(MiddlewareType::PreRequest(path), MiddlewareHandler::PreRequest(handler));
+    fn add_middleware(&mut self, middleware_type: MiddlewareType, handler: MiddlewareHandler);
+
+    fn pre_request(&mut self, path: PathParameter, handler: impl Fn(&mut Request) -> MiddlewareResult + Send + Sync + 'static) {
+        self.add_middleware(MiddlewareType::PreRequest(path), MiddlewareHandler::PreRequest(Arc::new(handler)));
+    }
+
+    fn post_request(&mut self, path: PathParameter, handler: impl Fn(&Request, &mut Response) -> MiddlewareResult + Send + Sync + 'static) {
+        self.add_middleware(MiddlewareType::PostRequest(path), MiddlewareHandler::PostRequest(Arc::new(handler)));
     }
-    
-    fn post_request(&mut self, path: PathParameter, handler: fn(request: &Request, response: &'a mut Response) -> MiddlewareResult<'a>) {
-        self.add_middleware(MiddlewareType::PostRequest(path), MiddlewareHandler::PostRequest(handler));
+
+    fn error_handler(&mut self, path: PathParameter, handler: impl Fn(&Request, &mut Response) -> MiddlewareResult + Send + Sync + 'static) {
+        self.add_middleware(MiddlewareType::ErrorHandler(path), MiddlewareHandler::ErrorHandler(Arc::new(handler)));
+    }
+
+    /// Sees every response as it is sent: after the post-request middlewares
+    /// and body encoding, including responses sent by pre-request middlewares.
+    fn logger(&mut self, path: PathParameter, handler: impl Fn(&Request, &Response) + Send + Sync + 'static) {
+        self.add_middleware(MiddlewareType::Logger(path), MiddlewareHandler::Logger(Arc::new(handler)));
     }
-    
-    fn error_handler(&mut self, path: PathParameter, handler: fn(request: &Request, error: &'a mut Response) -> MiddlewareResult<'a>) {
-        self.add_middleware(MiddlewareType::ErrorHandler(path), MiddlewareHandler::ErrorHandler(handler));
+
+    fn layer<L: MiddlewareLayer>(&mut self, layer: L) {
+        for entry in layer.into_middlewares() {
+            self.add_middleware(entry.middleware_type, entry.handler);
+        }
+    }
+}
+
+/// Runs the pre-request middlewares matching the request path, in
+/// registration order. Returns a response if one of them short-circuits.
+pub(crate) fn run_pre_request(middlewares: &[MiddlewareEntry], request: &mut Request) -> Option<Response> {
+    for entry in middlewares {
+        let handler = match (&entry.middleware_type, &entry.handler) {
+            (MiddlewareType::PreRequest(path), MiddlewareHandler::PreRequest(handler)) if path.matches(&request.path) => handler,
+            _ => continue,
+        };
+        match handler(request) {
+            MiddlewareResult::NextMiddleware => {}
+            MiddlewareResult::SkipMiddlewares => break,
+            MiddlewareResult::SendResponseAndStopProcessing(response) => return Some(response),
+        }
+    }
+    None
+}
+
+/// Runs the error handlers (for 4xx/5xx responses) and then the post-request
+/// middlewares matching the request path.
+pub(crate) fn run_post_request(middlewares: &[MiddlewareEntry], request: &Request, response: &mut Response) {
+    if response.status_code.code >= 400 {
+        for entry in middlewares {
+            let handler = match (&entry.middleware_type, &entry.handler) {
+                (MiddlewareType::ErrorHandler(path), MiddlewareHandler::ErrorHandler(handler)) if path.matches(&request.path) => handler,
+                _ => continue,
+            };
+            match handler(request, response) {
+                MiddlewareResult::NextMiddleware => {}
+                MiddlewareResult::SkipMiddlewares => break,
+                MiddlewareResult::SendResponseAndStopProcessing(replacement) => {
+                    *response = replacement;
+                    break;
+                }
+            }
+        }
+    }
+
+    for entry in middlewares {
+        let handler = match (&entry.middleware_type, &entry.handler) {
+            (MiddlewareType::PostRequest(path), MiddlewareHandler::PostRequest(handler)) if path.matches(&request.path) => handler,
+            _ => continue,
+        };
+        match handler(request, response) {
+            MiddlewareResult::NextMiddleware => {}
+            MiddlewareResult::SkipMiddlewares => break,
+            MiddlewareResult::SendResponseAndStopProcessing(replacement) => {
+                *response = replacement;
+                break;
+            }
+        }
+    }
+}
+
+/// Runs the loggers matching the request path on the response about to be sent.
+pub(crate) fn run_loggers(middlewares: &[MiddlewareEntry], request: &Request, response: &Response) {
+    for entry in middlewares {
+        if let (MiddlewareType::Logger(path), MiddlewareHandler::Logger(handler)) = (&entry.middleware_type, &entry.handler)
+            && path.matches(&request.path)
+        {
+            handler(request, response);
+        }
     }
 }
@@ -6,6 +6,7 @@ pub const TEXT_HTML: MimeType        = MimeType::new("text/html", false);
 pub const TEXT_CSS: MimeType         = MimeType::new("text/css", false);
 pub const TEXT_JAVASCRIPT: MimeType  = MimeType::new("text/javascript", false);
 pub const TEXT_CSV: MimeType         = MimeType::new("text/csv", false);
+pub const TEXT_EVENT_STREAM: MimeType = MimeType::new("text/event-stream", false);
 
 // Application
 pub const APPLICATION_JSON: MimeType           = MimeType::new("application/json", false);
@@ -33,7 +34,7 @@ pub const VIDEO_WEBM: MimeType = MimeType::new("video/webm", true);
 pub const MULTIPART_FORM_DATA: MimeType = MimeType::new("multipart/form-data", false);
 
 pub const ALL: &[MimeType] = &[
-    TEXT_PLAIN, TEXT_HTML, TEXT_CSS, TEXT_JAVASCRIPT, TEXT_CSV,
+    TEXT_PLAIN, TEXT_HTML, TEXT_CSS, TEXT_JAVASCRIPT, TEXT_CSV, TEXT_EVENT_STREAM,
     APPLICATION_JSON, APPLICATION_XML, APPLICATION_OCTET_STREAM,
     APPLICATION_PDF, APPLICATION_ZIP, APPLICATION_FORM_URLENCODED,
     IMAGE_PNG, IMAGE_JPEG, IMAGE_GIF, IMAGE_WEBP,
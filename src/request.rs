@@ -1,13 +1,21 @@
 mod test;
 
 use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
 
 use crate::{
+    cookie::parse_cookie_header,
     client_socket::{ReadError, Socket, SocketReader},
     http_method::{HttpMethod, parse_method},
     http_server::HttpServerConfig,
     http_version::{HttpVersion, parse_http_version},
+    listener::{Peer, PeerCredentials},
     map::{DuplicateMap, Map},
+    request_id::TraceContext,
+    session::Session,
+    tls::{ClientCertificate, TlsInfo},
 };
 
 #[derive(Debug)]
@@ -56,6 +64,123 @@ pub struct Request {
     pub query_params: Map<DuplicateMap>,
     pub headers: Map<DuplicateMap>,
     pub path_params: Map<String>,
+    pub(crate) session: Option<Session>,
+    pub(crate) tls: Option<Arc<TlsInfo>>,
+    pub(crate) peer: Option<Peer>,
+    pub(crate) received_at: Instant,
+    pub(crate) request_id: Option<String>,
+    pub(crate) trace_context: Option<TraceContext>,
+    pub(crate) route: Option<String>,
+    pub(crate) csp_nonce: Option<String>,
+}
+
+impl Request {
+    /// Parses the `Cookie` header into name/value pairs.
+    pub fn cookies(&self) -> Map<String> {
+        match self.headers.get_single("cookie") {
+            Some(header) => parse_cookie_header(header),
+            None => Map::default(),
+        }
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().get(name).cloned()
+    }
+
+    /// The `Last-Event-ID` header sent by an `EventSource` reconnecting to an event stream.
+    pub fn last_event_id(&self) -> Option<&String> {
+        self.headers.get_single("last-event-id")
+    }
+
+    /// The session loaded by `SessionLayer`, if the layer is registered for this path.
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// The other end of the connection the request came in on.
+    pub fn peer(&self) -> Option<&Peer> {
+        self.peer.as_ref()
+    }
+
+    /// The client address, `None` for requests over a Unix socket.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self.peer {
+            Some(Peer::Tcp(addr)) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// The credentials of the process connected over a Unix socket.
+    pub fn peer_credentials(&self) -> Option<&PeerCredentials> {
+        match &self.peer {
+            Some(Peer::Unix(credentials)) => credentials.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The TLS details of the connection, `None` for cleartext requests.
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_deref()
+    }
+
+    /// The hostname the client asked for through SNI.
+    pub fn server_name(&self) -> Option<&str> {
+        self.tls.as_ref()?.server_name.as_deref()
+    }
+
+    /// The verified client certificate, when the connection uses mutual TLS.
+    pub fn client_certificate(&self) -> Option<&ClientCertificate> {
+        self.tls.as_ref()?.client_certificate.as_ref()
+    }
+
+    /// A copy of the request without its body, kept around for the
+    /// post-request middlewares once the request has been handed to a handler.
+    pub(crate) fn without_body(&self) -> Request {
+        Request {
+            method: self.method.clone(),
+            http_version: self.http_version.clone(),
+            body: Vec::new(),
+            path: self.path.clone(),
+            query_params: self.query_params.clone(),
+            headers: self.headers.clone(),
+            path_params: self.path_params.clone(),
+            session: self.session.clone(),
+            tls: self.tls.clone(),
+            peer: self.peer.clone(),
+            received_at: self.received_at,
+            request_id: self.request_id.clone(),
+            trace_context: self.trace_context.clone(),
+            route: self.route.clone(),
+            csp_nonce: self.csp_nonce.clone(),
+        }
+    }
+
+    /// When the server started reading the request.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    /// The id set by `RequestIdLayer`.
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    /// The pattern of the route answering the request, e.g. `/users/:id`,
+    /// once it has been routed.
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    /// The Content-Security-Policy nonce set by `SecurityHeaders`, for the
+    /// `nonce` attribute of inline scripts and styles.
+    pub fn csp_nonce(&self) -> Option<&str> {
+        self.csp_nonce.as_deref()
+    }
+
+    /// The trace context set by `RequestIdLayer`.
+    pub fn trace_context(&self) -> Option<&TraceContext> {
+        self.trace_context.as_ref()
+    }
 }
 
 impl Default for Request {
@@ -68,6 +193,14 @@ impl Default for Request {
             query_params: Default::default(),
             headers: Default::default(),
             path_params: Default::default(),
+            session: None,
+            tls: None,
+            peer: None,
+            received_at: Instant::now(),
+            request_id: None,
+            trace_context: None,
+            route: None,
+            csp_nonce: None,
         }
     }
 }
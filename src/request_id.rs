@@ -0,0 +1,204 @@
+mod test;
+
+use std::sync::Arc;
+
+use crate::logging::warning;
+use crate::middleware::{
+    MiddlewareEntry, MiddlewareHandler, MiddlewareLayer, MiddlewareResult, MiddlewareType,
+    PathParameter,
+};
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::random_hex;
+
+const MAX_REQUEST_ID_LENGTH: usize = 200;
+
+/// The W3C trace context of a request, see https://www.w3.org/TR/trace-context/.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits shared by every span of the trace.
+    pub trace_id: String,
+    /// The span of the caller, `None` when the request started the trace.
+    pub parent_id: Option<String>,
+    /// The span of this request, 16 lowercase hex digits.
+    pub span_id: String,
+    pub flags: u8,
+    /// The vendor entries of `tracestate`, passed on untouched.
+    pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+    /// Continues the trace of a `traceparent` header, or `None` when it's invalid.
+    pub fn from_headers(traceparent: &str, trace_state: Option<&str>, span_id: String) -> Option<Self> {
+        let (trace_id, parent_id, flags) = parse_traceparent(traceparent)?;
+        Some(TraceContext {
+            trace_id,
+            parent_id: Some(parent_id),
+            span_id,
+            flags,
+            trace_state: trace_state.map(str::trim).filter(|s| !s.is_empty()).map(str::to_string),
+        })
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+
+    /// The `traceparent` of this request's span, to send along to the
+    /// services it calls.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+/// Parses `version-trace_id-parent_id-flags`, rejecting all-zero ids and
+/// unknown fields on version 00.
+pub(crate) fn parse_traceparent(value: &str) -> Option<(String, String, u8)> {
+    let value = value.trim();
+    let mut parts = value.splitn(5, '-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    let rest = parts.next();
+
+    let is_hex = |s: &str, len: usize| {
+        s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && rest.is_some()) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    Some((trace_id.to_string(), parent_id.to_string(), u8::from_str_radix(flags, 16).ok()?))
+}
+
+/// Tags every request with an id and a trace context, e.g.
+/// `server.layer(RequestIdLayer::new())`.
+///
+/// The id comes from the `X-Request-Id` header or is generated, and the trace
+/// continues the one of `traceparent`. Both are available through
+/// `Request::request_id` and `Request::trace_context`, sent back in the
+/// response headers and attached to the log events of the request. Register
+/// the layer before the middlewares that should see them.
+pub struct RequestIdLayer {
+    header: String,
+    trust_incoming: bool,
+    path: PathParameter,
+}
+
+impl RequestIdLayer {
+    pub fn new() -> Self {
+        RequestIdLayer {
+            header: "X-Request-Id".to_string(),
+            trust_incoming: true,
+            path: PathParameter::Wildcard,
+        }
+    }
+
+    /// The header the id is read from and written to.
+    pub fn header<S: Into<String>>(mut self, header: S) -> Self {
+        self.header = header.into();
+        self
+    }
+
+    /// Whether to keep the ids and trace contexts sent by clients, on by
+    /// default. Turn it off when the server is reachable without a proxy
+    /// setting them.
+    pub fn trust_incoming(mut self, trust: bool) -> Self {
+        self.trust_incoming = trust;
+        self
+    }
+
+    /// Only tag requests whose path matches `path`.
+    pub fn path(mut self, path: PathParameter) -> Self {
+        self.path = path;
+        self
+    }
+}
+
+impl Default for RequestIdLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MiddlewareLayer for RequestIdLayer {
+    fn into_middlewares(self) -> Vec<MiddlewareEntry> {
+        let (pre_header, post_header) = (self.header.to_lowercase(), self.header);
+        let trust_incoming = self.trust_incoming;
+
+        vec![
+            MiddlewareEntry {
+                middleware_type: MiddlewareType::PreRequest(self.path.clone()),
+                handler: MiddlewareHandler::PreRequest(Arc::new(move |request: &mut Request| {
+                    if let Err(e) = tag_request(request, &pre_header, trust_incoming) {
+                        warning!(error = %e, "Failed to generate a request id.");
+                    }
+                    MiddlewareResult::NextMiddleware
+                })),
+            },
+            MiddlewareEntry {
+                middleware_type: MiddlewareType::PostRequest(self.path),
+                handler: MiddlewareHandler::PostRequest(Arc::new(
+                    move |request: &Request, response: &mut Response| {
+                        if let Some(id) = request.request_id() {
+                            response.headers.push((post_header.clone(), id.to_string()));
+                        }
+                        if let Some(trace) = request.trace_context() {
+                            response.headers.push(("traceparent".to_string(), trace.traceparent()));
+                            if let Some(state) = &trace.trace_state {
+                                response.headers.push(("tracestate".to_string(), state.clone()));
+                            }
+                        }
+                        MiddlewareResult::NextMiddleware
+                    },
+                )),
+            },
+        ]
+    }
+}
+
+pub(crate) fn tag_request(request: &mut Request, header: &str, trust_incoming: bool) -> std::io::Result<()> {
+    let incoming_id = request
+        .headers
+        .get_single(header)
+        .filter(|_| trust_incoming)
+        .filter(|id| is_valid_request_id(id))
+        .cloned();
+    let span_id = random_hex(8)?;
+    let incoming_trace = if trust_incoming {
+        request.headers.get_single("traceparent").and_then(|traceparent| {
+            let state = request.headers.get_single("tracestate").map(String::as_str);
+            TraceContext::from_headers(traceparent, state, span_id.clone())
+        })
+    } else {
+        None
+    };
+
+    let trace = match incoming_trace {
+        Some(trace) => trace,
+        None => TraceContext {
+            trace_id: random_hex(16)?,
+            parent_id: None,
+            span_id,
+            flags: 0,
+            trace_state: None,
+        },
+    };
+    request.request_id = Some(match incoming_id {
+        Some(id) => id,
+        None => random_hex(16)?,
+    });
+    request.trace_context = Some(trace);
+    Ok(())
+}
+
+/// Ids are echoed in headers and logs, so only short visible ASCII ones are kept.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+}
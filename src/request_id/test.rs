@@ -0,0 +1,67 @@
+#![cfg(test)]
+
+use crate::map::{DuplicateMap, Map};
+use crate::request::Request;
+use crate::request_id::*;
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+fn request(headers: &[(&str, &str)]) -> Request {
+    let mut map: Map<DuplicateMap> = Map::default();
+    for (name, value) in headers {
+        map.add(name, value.to_string());
+    }
+    Request { headers: map, ..Default::default() }
+}
+
+#[test]
+fn test_parse_traceparent() {
+    assert_eq!(
+        parse_traceparent(TRACEPARENT),
+        Some(("4bf92f3577b34da6a3ce929d0e0e4736".to_string(), "00f067aa0ba902b7".to_string(), 1))
+    );
+    // Later versions may append fields.
+    assert!(parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_some());
+
+    assert_eq!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"), None);
+    assert_eq!(parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+    assert_eq!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+    assert_eq!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01"), None);
+    assert_eq!(parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"), None);
+    assert_eq!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7"), None);
+}
+
+#[test]
+fn test_tag_request_continues_incoming_trace() {
+    let mut req = request(&[
+        ("x-request-id", "abc-123"),
+        ("traceparent", TRACEPARENT),
+        ("tracestate", "vendor=value"),
+    ]);
+    tag_request(&mut req, "x-request-id", true).unwrap();
+
+    assert_eq!(req.request_id(), Some("abc-123"));
+    let trace = req.trace_context().unwrap();
+    assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(trace.parent_id.as_deref(), Some("00f067aa0ba902b7"));
+    assert_ne!(trace.span_id, "00f067aa0ba902b7");
+    assert!(trace.sampled());
+    assert_eq!(trace.trace_state.as_deref(), Some("vendor=value"));
+    assert_eq!(trace.traceparent(), format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", trace.span_id));
+}
+
+#[test]
+fn test_tag_request_starts_new_trace() {
+    let mut req = request(&[("x-request-id", "bad id"), ("traceparent", "garbage")]);
+    tag_request(&mut req, "x-request-id", true).unwrap();
+    assert_eq!(req.request_id().unwrap().len(), 32);
+    let trace = req.trace_context().unwrap();
+    assert_eq!(trace.parent_id, None);
+    assert_eq!(trace.trace_id.len(), 32);
+    assert!(!trace.sampled());
+
+    let mut req = request(&[("x-request-id", "abc-123"), ("traceparent", TRACEPARENT)]);
+    tag_request(&mut req, "x-request-id", false).unwrap();
+    assert_ne!(req.request_id(), Some("abc-123"));
+    assert_ne!(req.trace_context().unwrap().trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+}
@@ -1,13 +1,18 @@
 use std::borrow::Cow;
 
-use crate::{mime_type::{APPLICATION_OCTET_STREAM, MimeType, TEXT_PLAIN}, status_code::{OK, StatusCode}};
+use crate::{cookie::Cookie, sse::EventStream, mime_type::{APPLICATION_OCTET_STREAM, MimeType, TEXT_PLAIN}, status_code::{OK, StatusCode}};
 
+/// Build responses with [`status`], [`text`], [`bytes`] and friends rather
+/// than a struct literal: a response can also carry an event stream, and
+/// further fields may be added.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct Response {
     pub content_type: MimeType,
     pub bytes: Vec<u8>,
     pub status_code: StatusCode,
     pub headers: Vec<(String, String)>,
+    pub(crate) event_stream: Option<EventStream>,
 }
 
 impl Response {
@@ -16,6 +21,11 @@ impl Response {
         self
     }
     
+    pub fn cookie(mut self, cookie: Cookie) -> Self {
+        self.headers.push(("Set-Cookie".to_string(), cookie.to_header_value()));
+        self
+    }
+
     pub fn status<T: Into<StatusCode>>(mut self, status: T) -> Self {
         self.status_code = status.into();
         self
@@ -46,6 +56,7 @@ pub fn status<T: Into<StatusCode>>(status: T) -> Response {
         bytes: Vec::new(),
         status_code: status.into(),
         headers: Vec::new(),
+        event_stream: None,
     }
 }
 
@@ -55,6 +66,7 @@ pub fn text<S: AsRef<str>>(text: S) -> Response {
         bytes: text.as_ref().as_bytes().to_vec(),
         status_code: OK,
         headers: Vec::new(),
+        event_stream: None,
     };
 }
 
@@ -64,6 +76,7 @@ pub fn bytes(bytes: Vec<u8>) -> Response {
         bytes,
         status_code: OK,
         headers: Vec::new(),
+        event_stream: None,
     };
 }
 
@@ -73,6 +86,7 @@ pub fn text_response<T: Into<StatusCode>, S: AsRef<str>>(status: T, content_type
         bytes,
         status_code: status.into(),
         headers: Vec::new(),
+        event_stream: None,
     };
 }
 
@@ -82,6 +96,7 @@ pub fn binary_response<T: Into<StatusCode>, S: AsRef<str>>(status: T, content_ty
         bytes,
         status_code: status.into(),
         headers: Vec::new(),
+        event_stream: None,
     };
 }
 
@@ -91,6 +106,7 @@ pub fn response<T: Into<StatusCode>>(status: T, content_type: MimeType, bytes: V
         bytes,
         status_code: status.into(),
         headers: Vec::new(),
+        event_stream: None,
     };
 }
 
@@ -100,6 +116,7 @@ pub fn empty() -> Response {
         bytes: Vec::new(),
         status_code: OK,
         headers: Vec::new(),
+        event_stream: None,
     };
 }
 
@@ -109,5 +126,6 @@ pub fn redirect<S: AsRef<str>>(location: S) -> Response {
         bytes: Vec::new(),
         status_code: StatusCode::from_u16(302).unwrap(),
         headers: vec![("Location".to_string(), location.as_ref().to_string())],
+        event_stream: None,
     };
 }
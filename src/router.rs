@@ -0,0 +1,121 @@
+mod test;
+
+use std::sync::Arc;
+
+use crate::http_server_trait::{HttpCallbacks, HttpListener};
+use crate::request::Request;
+use crate::response::Response;
+
+pub(crate) type RouteHandler = Arc<dyn Fn(Request) -> Response + Send + Sync>;
+
+/// The handlers answering the requests under `prefix` no route matches.
+#[derive(Clone)]
+pub(crate) struct RouteScope {
+    /// Empty for the top-level routes.
+    pub(crate) prefix: String,
+    pub(crate) fallback: Option<RouteHandler>,
+    pub(crate) method_not_allowed: Option<RouteHandler>,
+}
+
+impl RouteScope {
+    pub(crate) fn root() -> Self {
+        RouteScope { prefix: String::new(), fallback: None, method_not_allowed: None }
+    }
+
+    fn contains(&self, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or(path);
+        match path.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// The innermost scope under which `path` falls that has the handler picked
+/// by `handler`.
+pub(crate) fn scoped_handler(
+    scopes: &[RouteScope],
+    path: &str,
+    handler: impl Fn(&RouteScope) -> Option<&RouteHandler>,
+) -> Option<RouteHandler> {
+    scopes
+        .iter()
+        .filter(|scope| scope.contains(path))
+        .filter_map(|scope| handler(scope).map(|h| (scope.prefix.len(), h)))
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, h)| h.clone())
+}
+
+/// Routes mounted under a common prefix with `server.nest("/api", router)`,
+/// with their own fallback and method-not-allowed handlers.
+pub struct Router {
+    pub(crate) callbacks: Vec<HttpListener<Request, Response>>,
+    pub(crate) scopes: Vec<RouteScope>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router { callbacks: vec![], scopes: vec![RouteScope::root()] }
+    }
+
+    /// Answers the requests under the router's prefix no route matches.
+    pub fn fallback(&mut self, handler: impl Fn(Request) -> Response + Send + Sync + 'static) {
+        root_scope(&mut self.scopes).fallback = Some(Arc::new(handler));
+    }
+
+    /// Answers the requests under the router's prefix whose path matches a
+    /// route, but not their method.
+    pub fn method_not_allowed(&mut self, handler: impl Fn(Request) -> Response + Send + Sync + 'static) {
+        root_scope(&mut self.scopes).method_not_allowed = Some(Arc::new(handler));
+    }
+
+    /// Mounts the routes of `router` under `prefix`, relative to this one's.
+    pub fn nest(&mut self, prefix: &str, router: Router) {
+        nest_routes(&mut self.callbacks, &mut self.scopes, prefix, router);
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpCallbacks for Router {
+    type Request = Request;
+
+    type Response = Response;
+
+    fn add_callback(&mut self, callback: HttpListener<Self::Request, Self::Response>) {
+        self.callbacks.push(callback);
+    }
+}
+
+/// Mounts the routes of `router` under `prefix`.
+pub(crate) fn nest_routes(
+    callbacks: &mut Vec<HttpListener<Request, Response>>,
+    scopes: &mut Vec<RouteScope>,
+    prefix: &str,
+    router: Router,
+) {
+    let prefix = prefix.trim_end_matches('/');
+    for mut callback in router.callbacks {
+        callback.path = match callback.path.as_str() {
+            "/" | "" => prefix.to_string(),
+            path => format!("{prefix}{path}"),
+        };
+        callbacks.push(callback);
+    }
+    for mut scope in router.scopes {
+        scope.prefix = format!("{prefix}{}", scope.prefix);
+        scopes.push(scope);
+    }
+}
+
+/// The scope of the top-level routes.
+pub(crate) fn root_scope(scopes: &mut Vec<RouteScope>) -> &mut RouteScope {
+    if !scopes.iter().any(|scope| scope.prefix.is_empty()) {
+        scopes.push(RouteScope::root());
+    }
+    scopes.iter_mut().find(|scope| scope.prefix.is_empty()).unwrap()
+}
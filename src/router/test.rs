@@ -0,0 +1,47 @@
+#![cfg(test)]
+
+use crate::http_server_trait::HttpCallbacks;
+use crate::request::Request;
+use crate::response::text;
+use crate::router::*;
+
+fn answer(handler: Option<RouteHandler>) -> Option<Vec<u8>> {
+    handler.map(|handler| handler(Request::default()).bytes)
+}
+
+#[test]
+fn test_nest_prefixes_routes_and_scopes() {
+    let mut users = Router::new();
+    users.get("/", |_| text("list"));
+    users.get("/:id", |_| text("user"));
+    users.fallback(|_| text("users fallback"));
+    let mut api = Router::new();
+    api.nest("/users/", users);
+
+    let mut scopes = vec![RouteScope::root()];
+    let mut callbacks = vec![];
+    nest_routes(&mut callbacks, &mut scopes, "/api", api);
+
+    let paths: Vec<&str> = callbacks.iter().map(|callback| callback.path.as_str()).collect();
+    assert_eq!(paths, ["/api/users", "/api/users/:id"]);
+    let prefixes: Vec<&str> = scopes.iter().map(|scope| scope.prefix.as_str()).collect();
+    assert_eq!(prefixes, ["", "/api", "/api/users"]);
+}
+
+#[test]
+fn test_innermost_scope_with_handler_wins() {
+    let mut root = RouteScope::root();
+    root.fallback = Some(std::sync::Arc::new(|_| text("root")));
+    let mut api = Router::new();
+    api.fallback(|_| text("api"));
+    let mut scopes = vec![root];
+    nest_routes(&mut vec![], &mut scopes, "/api", api);
+
+    let fallback = |path| answer(scoped_handler(&scopes, path, |scope| scope.fallback.as_ref()));
+    assert_eq!(fallback("/api/missing"), Some(b"api".to_vec()));
+    assert_eq!(fallback("/api"), Some(b"api".to_vec()));
+    assert_eq!(fallback("/api?x=1"), Some(b"api".to_vec()));
+    assert_eq!(fallback("/apis"), Some(b"root".to_vec()));
+    assert_eq!(fallback("/index.html"), Some(b"root".to_vec()));
+    assert_eq!(answer(scoped_handler(&scopes, "/api/x", |scope| scope.method_not_allowed.as_ref())), None);
+}
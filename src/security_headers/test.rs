@@ -0,0 +1,53 @@
+#![cfg(test)]
+
+use std::sync::Arc;
+
+use crate::middleware::PathParameter;
+use crate::request::Request;
+use crate::response::{Response, text};
+use crate::security_headers::*;
+use crate::tls::TlsInfo;
+
+fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+}
+
+#[test]
+fn test_defaults_send_hsts_only_over_tls() {
+    let headers = SecurityHeaders::new();
+
+    let mut res = text("ok");
+    headers.apply(&Request::default(), &mut res);
+    assert_eq!(header(&res, "X-Content-Type-Options"), Some("nosniff"));
+    assert_eq!(header(&res, "X-Frame-Options"), Some("DENY"));
+    assert_eq!(header(&res, "Referrer-Policy"), Some("strict-origin-when-cross-origin"));
+    assert_eq!(header(&res, "Strict-Transport-Security"), None);
+
+    let tls = Request { tls: Some(Arc::new(TlsInfo::default())), ..Default::default() };
+    let mut res = text("ok");
+    headers.apply(&tls, &mut res);
+    assert_eq!(header(&res, "Strict-Transport-Security"), Some("max-age=31536000; includeSubDomains"));
+}
+
+#[test]
+fn test_nonce_and_route_overrides() {
+    let headers = SecurityHeaders::new()
+        .content_security_policy("script-src 'self' {nonce}")
+        .route(PathParameter::Begin("/embed".to_string()), SecurityHeaders::new().remove("X-Frame-Options"));
+
+    let req = Request { path: "/".to_string(), csp_nonce: Some("abc".to_string()), ..Default::default() };
+    assert!(headers.needs_nonce(&req.path));
+    let mut res = text("ok").header("X-Frame-Options", "SAMEORIGIN");
+    headers.apply(&req, &mut res);
+    assert_eq!(header(&res, "Content-Security-Policy"), Some("script-src 'self' 'nonce-abc'"));
+    // Set by the handler.
+    assert_eq!(header(&res, "X-Frame-Options"), Some("SAMEORIGIN"));
+
+    let req = Request { path: "/embed/video".to_string(), ..Default::default() };
+    assert!(!headers.needs_nonce(&req.path));
+    let mut res = text("ok");
+    headers.apply(&req, &mut res);
+    assert_eq!(header(&res, "X-Frame-Options"), None);
+    assert_eq!(header(&res, "Content-Security-Policy"), None);
+    assert_eq!(header(&res, "X-Content-Type-Options"), Some("nosniff"));
+}
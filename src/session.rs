@@ -0,0 +1,509 @@
+mod test;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::logging::error;
+use crate::cookie::{Cookie, SameSite};
+use crate::middleware::{
+    MiddlewareEntry, MiddlewareHandler, MiddlewareLayer, MiddlewareResult, MiddlewareType,
+    PathParameter,
+};
+use crate::request::Request;
+use crate::response::{Response, status};
+use crate::status_code::INTERNAL_SERVER_ERROR;
+use crate::utils::random_hex;
+
+/// Size in bytes of a generated session id (hex encoded in the cookie).
+const SESSION_ID_SIZE: usize = 32;
+
+/// How often the built-in stores sweep out expired sessions.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether a store last swept at `last_purge` is due for another sweep,
+/// marking it as swept now if so.
+fn purge_due(last_purge: &Mutex<Option<Instant>>) -> bool {
+    let mut last_purge = last_purge.lock().unwrap_or_else(|e| e.into_inner());
+    if last_purge.is_some_and(|last| last.elapsed() < PURGE_INTERVAL) {
+        return false;
+    }
+    *last_purge = Some(Instant::now());
+    true
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionRecord {
+    pub data: HashMap<String, String>,
+    pub created_at: SystemTime,
+    pub last_accessed: SystemTime,
+}
+
+impl SessionRecord {
+    pub fn new() -> Self {
+        let now = SystemTime::now();
+        SessionRecord {
+            data: HashMap::new(),
+            created_at: now,
+            last_accessed: now,
+        }
+    }
+
+    pub fn is_expired(&self, config: &SessionConfig, now: SystemTime) -> bool {
+        let elapsed = |since: SystemTime| now.duration_since(since).unwrap_or(Duration::ZERO);
+        config.idle_timeout.is_some_and(|timeout| elapsed(self.last_accessed) > timeout)
+            || config.absolute_timeout.is_some_and(|timeout| elapsed(self.created_at) > timeout)
+    }
+}
+
+impl Default for SessionRecord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> std::io::Result<Option<SessionRecord>>;
+    fn save(&self, id: &str, record: &SessionRecord) -> std::io::Result<()>;
+    fn remove(&self, id: &str) -> std::io::Result<()>;
+
+    /// Drops the sessions expired under `config`, called before each save.
+    /// Stores that expire records on their own can leave it as a no-op.
+    fn purge_expired(&self, _config: &SessionConfig, _now: SystemTime) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+    last_purge: Mutex<Option<Instant>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, SessionRecord>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> std::io::Result<Option<SessionRecord>> {
+        Ok(self.sessions().get(id).cloned())
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> std::io::Result<()> {
+        self.sessions().insert(id.to_owned(), record.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> std::io::Result<()> {
+        self.sessions().remove(id);
+        Ok(())
+    }
+
+    fn purge_expired(&self, config: &SessionConfig, now: SystemTime) -> std::io::Result<()> {
+        if purge_due(&self.last_purge) {
+            self.sessions().retain(|_, record| !record.is_expired(config, now));
+        }
+        Ok(())
+    }
+}
+
+/// Stores each session in its own file inside `directory`.
+///
+/// The file holds the creation and last access times (seconds since the unix
+/// epoch) on the first two lines, followed by one escaped `key\tvalue` pair
+/// per line. Expired files are swept out on save, at most once a minute.
+pub struct FileSessionStore {
+    directory: PathBuf,
+    last_purge: Mutex<Option<Instant>>,
+}
+
+impl FileSessionStore {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> std::io::Result<Self> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        Ok(FileSessionStore {
+            directory,
+            last_purge: Mutex::new(None),
+        })
+    }
+
+    fn session_path(&self, id: &str) -> std::io::Result<PathBuf> {
+        // Ids come from the client, never let them escape the directory.
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid session id.",
+            ));
+        }
+        Ok(self.directory.join(format!("{}.session", id)))
+    }
+}
+
+fn escape_field(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_field(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+fn to_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn from_timestamp(value: &str) -> Option<SystemTime> {
+    value.trim().parse::<u64>().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+pub(crate) fn serialize_record(record: &SessionRecord) -> String {
+    let mut output = format!(
+        "{}\n{}\n",
+        to_timestamp(record.created_at),
+        to_timestamp(record.last_accessed)
+    );
+    for (key, value) in &record.data {
+        output.push_str(&format!("{}\t{}\n", escape_field(key), escape_field(value)));
+    }
+    output
+}
+
+pub(crate) fn deserialize_record(input: &str) -> Option<SessionRecord> {
+    let mut lines = input.lines();
+    let created_at = from_timestamp(lines.next()?)?;
+    let last_accessed = from_timestamp(lines.next()?)?;
+    let mut data = HashMap::new();
+    for line in lines {
+        let (key, value) = line.split_once('\t')?;
+        data.insert(unescape_field(key), unescape_field(value));
+    }
+    Some(SessionRecord {
+        data,
+        created_at,
+        last_accessed,
+    })
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, id: &str) -> std::io::Result<Option<SessionRecord>> {
+        let path = match self.session_path(id) {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(deserialize_record(&content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> std::io::Result<()> {
+        let path = self.session_path(id)?;
+        // Write to a temporary file first so readers never see a partial record.
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, serialize_record(record))?;
+        std::fs::rename(temp_path, path)
+    }
+
+    fn remove(&self, id: &str) -> std::io::Result<()> {
+        let path = match self.session_path(id) {
+            Ok(path) => path,
+            Err(_) => return Ok(()),
+        };
+        match std::fs::remove_file(path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn purge_expired(&self, config: &SessionConfig, now: SystemTime) -> std::io::Result<()> {
+        if !purge_due(&self.last_purge) {
+            return Ok(());
+        }
+        for entry in std::fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "session") {
+                continue;
+            }
+            // Files removed or rewritten meanwhile are left to the next sweep.
+            let Ok(content) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            if deserialize_record(&content).is_some_and(|record| record.is_expired(config, now)) {
+                match std::fs::remove_file(&path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct SessionConfig {
+    pub cookie_name: String,
+    pub cookie_path: String,
+    pub cookie_domain: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: SameSite,
+    /// Sessions unused for longer than this are discarded.
+    pub idle_timeout: Option<Duration>,
+    /// Sessions older than this are discarded regardless of activity.
+    pub absolute_timeout: Option<Duration>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            cookie_name: "session_id".to_string(),
+            cookie_path: "/".to_string(),
+            cookie_domain: None,
+            secure: false,
+            http_only: true,
+            same_site: SameSite::Lax,
+            idle_timeout: Some(Duration::from_secs(30 * 60)),
+            absolute_timeout: Some(Duration::from_secs(24 * 60 * 60)),
+        }
+    }
+}
+
+struct SessionState {
+    id: String,
+    record: SessionRecord,
+    /// Ids this request used before `rotate` was called, removed from the store on persist.
+    previous_ids: Vec<String>,
+    is_new: bool,
+    destroyed: bool,
+}
+
+/// The session attached to a request by [`SessionLayer`].
+///
+/// Cloning is cheap and all clones share the same state, so changes made by a
+/// handler are seen when the session is persisted after the response.
+#[derive(Clone)]
+pub struct Session {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+    fn new(id: String, record: SessionRecord, is_new: bool) -> Self {
+        Session {
+            state: Arc::new(Mutex::new(SessionState {
+                id,
+                record,
+                previous_ids: vec![],
+                is_new,
+                destroyed: false,
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SessionState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn id(&self) -> String {
+        self.state().id.clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state().record.data.get(key).cloned()
+    }
+
+    pub fn insert<K: Into<String>, V: Into<String>>(&self, key: K, value: V) {
+        self.state().record.data.insert(key.into(), value.into());
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        self.state().record.data.remove(key)
+    }
+
+    pub fn clear(&self) {
+        self.state().record.data.clear();
+    }
+
+    /// Gives the session a new id while keeping its data. Call this whenever
+    /// the privilege level changes (login, logout, role change) to prevent
+    /// session fixation.
+    pub fn rotate(&self) -> std::io::Result<()> {
+        let new_id = random_hex(SESSION_ID_SIZE)?;
+        let mut state = self.state();
+        let old_id = std::mem::replace(&mut state.id, new_id);
+        if !state.is_new {
+            state.previous_ids.push(old_id);
+        }
+        state.is_new = true;
+        Ok(())
+    }
+
+    /// Removes the session from the store and clears the client cookie.
+    pub fn destroy(&self) {
+        let mut state = self.state();
+        state.record.data.clear();
+        state.destroyed = true;
+    }
+}
+
+/// Loads the session referenced by the session cookie before the handler
+/// runs and persists it once the response has been produced.
+pub struct SessionLayer<S: SessionStore + 'static> {
+    store: Arc<S>,
+    config: SessionConfig,
+    path: PathParameter,
+}
+
+impl<S: SessionStore + 'static> SessionLayer<S> {
+    pub fn new(store: S, config: SessionConfig) -> Self {
+        SessionLayer {
+            store: Arc::new(store),
+            config,
+            path: PathParameter::Wildcard,
+        }
+    }
+
+    /// Only attach sessions to requests whose path matches `path`.
+    pub fn path(mut self, path: PathParameter) -> Self {
+        self.path = path;
+        self
+    }
+}
+
+fn session_cookie(config: &SessionConfig, id: &str) -> Cookie {
+    let mut cookie = Cookie::new(config.cookie_name.clone(), id)
+        .path(config.cookie_path.clone())
+        .secure(config.secure)
+        .http_only(config.http_only)
+        .same_site(config.same_site);
+    if let Some(domain) = &config.cookie_domain {
+        cookie = cookie.domain(domain.clone());
+    }
+    cookie
+}
+
+pub(crate) fn load_session<S: SessionStore>(
+    store: &S,
+    config: &SessionConfig,
+    request: &mut Request,
+) -> std::io::Result<()> {
+    let now = SystemTime::now();
+    let session = match request.cookie(&config.cookie_name) {
+        Some(id) => match store.load(&id)? {
+            Some(record) if !record.is_expired(config, now) => Session::new(id, record, false),
+            Some(_) => {
+                store.remove(&id)?;
+                Session::new(random_hex(SESSION_ID_SIZE)?, SessionRecord::new(), true)
+            }
+            None => Session::new(random_hex(SESSION_ID_SIZE)?, SessionRecord::new(), true),
+        },
+        None => Session::new(random_hex(SESSION_ID_SIZE)?, SessionRecord::new(), true),
+    };
+    request.session = Some(session);
+    Ok(())
+}
+
+pub(crate) fn persist_session<S: SessionStore>(
+    store: &S,
+    config: &SessionConfig,
+    session: &Session,
+    response: &mut Response,
+) -> std::io::Result<()> {
+    let mut state = session.state();
+    for previous_id in state.previous_ids.drain(..) {
+        store.remove(&previous_id)?;
+    }
+
+    if state.destroyed {
+        if !state.is_new {
+            store.remove(&state.id)?;
+            let mut cookie = session_cookie(config, "");
+            cookie.max_age = Some(Duration::ZERO);
+            response.headers.push(("Set-Cookie".to_string(), cookie.to_header_value()));
+        }
+        return Ok(());
+    }
+
+    // Don't hand out cookies for sessions nothing was ever stored in.
+    if state.is_new && state.record.data.is_empty() {
+        return Ok(());
+    }
+
+    let now = SystemTime::now();
+    store.purge_expired(config, now)?;
+    state.record.last_accessed = now;
+    store.save(&state.id, &state.record)?;
+    if state.is_new {
+        let cookie = session_cookie(config, &state.id);
+        response.headers.push(("Set-Cookie".to_string(), cookie.to_header_value()));
+    }
+    Ok(())
+}
+
+impl<S: SessionStore + 'static> MiddlewareLayer for SessionLayer<S> {
+    fn into_middlewares(self) -> Vec<MiddlewareEntry> {
+        let (pre_store, pre_config) = (self.store.clone(), self.config.clone());
+        let (post_store, post_config) = (self.store, self.config);
+        let post_path = self.path.clone();
+
+        vec![
+            MiddlewareEntry {
+                middleware_type: MiddlewareType::PreRequest(self.path),
+                handler: MiddlewareHandler::PreRequest(Arc::new(move |request: &mut Request| {
+                    match load_session(pre_store.as_ref(), &pre_config, request) {
+                        Ok(_) => MiddlewareResult::NextMiddleware,
+                        Err(e) => {
+                            error!(error = %e, "Failed to load session.");
+                            MiddlewareResult::SendResponseAndStopProcessing(status(INTERNAL_SERVER_ERROR))
+                        }
+                    }
+                })),
+            },
+            MiddlewareEntry {
+                middleware_type: MiddlewareType::PostRequest(post_path),
+                handler: MiddlewareHandler::PostRequest(Arc::new(
+                    move |request: &Request, response: &mut Response| {
+                        if let Some(session) = request.session()
+                            && let Err(e) = persist_session(post_store.as_ref(), &post_config, session, response)
+                        {
+                            error!(error = %e, "Failed to persist session.");
+                        }
+                        MiddlewareResult::NextMiddleware
+                    },
+                )),
+            },
+        ]
+    }
+}
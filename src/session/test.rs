@@ -0,0 +1,201 @@
+#![cfg(test)]
+
+use std::time::{Duration, SystemTime};
+
+use crate::map::{DuplicateMap, Map};
+use crate::request::Request;
+use crate::response::empty;
+use crate::session::*;
+
+fn request_with_cookie(cookie: &str) -> Request {
+    let mut headers: Map<DuplicateMap> = Map::default();
+    headers.add("cookie", cookie.to_string());
+    Request {
+        headers,
+        ..Default::default()
+    }
+}
+
+fn set_cookie_value(response: &crate::response::Response) -> Option<String> {
+    response
+        .headers
+        .iter()
+        .find(|(key, _)| key == "Set-Cookie")
+        .map(|(_, value)| value.clone())
+}
+
+fn cookie_id(set_cookie: &str) -> String {
+    let (pair, _) = set_cookie.split_once(';').unwrap();
+    pair.split_once('=').unwrap().1.to_string()
+}
+
+#[test]
+fn test_record_roundtrip() {
+    let mut record = SessionRecord::new();
+    record.data.insert("user".to_string(), "alice".to_string());
+    record.data.insert("weird\tkey".to_string(), "line\nbreak\\".to_string());
+
+    let parsed = deserialize_record(&serialize_record(&record)).unwrap();
+    assert_eq!(parsed.data, record.data);
+}
+
+#[test]
+fn test_record_expiry() {
+    let config = SessionConfig {
+        idle_timeout: Some(Duration::from_secs(60)),
+        absolute_timeout: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let now = SystemTime::now();
+    let mut record = SessionRecord::new();
+    assert!(!record.is_expired(&config, now));
+
+    record.last_accessed = now - Duration::from_secs(120);
+    assert!(record.is_expired(&config, now));
+
+    record.last_accessed = now;
+    record.created_at = now - Duration::from_secs(7200);
+    assert!(record.is_expired(&config, now));
+}
+
+#[test]
+fn test_new_session_without_data_sets_no_cookie() {
+    let store = MemorySessionStore::new();
+    let config = SessionConfig::default();
+    let mut request = Request::default();
+    load_session(&store, &config, &mut request).unwrap();
+
+    let mut response = empty();
+    persist_session(&store, &config, request.session().unwrap(), &mut response).unwrap();
+    assert!(set_cookie_value(&response).is_none());
+}
+
+#[test]
+fn test_session_persists_between_requests() {
+    let store = MemorySessionStore::new();
+    let config = SessionConfig::default();
+
+    let mut request = Request::default();
+    load_session(&store, &config, &mut request).unwrap();
+    request.session().unwrap().insert("user", "alice");
+    let mut response = empty();
+    persist_session(&store, &config, request.session().unwrap(), &mut response).unwrap();
+
+    let set_cookie = set_cookie_value(&response).unwrap();
+    assert!(set_cookie.contains("HttpOnly"));
+    let id = cookie_id(&set_cookie);
+
+    let mut request = request_with_cookie(&format!("session_id={}", id));
+    load_session(&store, &config, &mut request).unwrap();
+    let session = request.session().unwrap();
+    assert_eq!(session.id(), id);
+    assert_eq!(session.get("user"), Some("alice".to_string()));
+    assert_eq!(session.remove("user"), Some("alice".to_string()));
+}
+
+#[test]
+fn test_rotate_replaces_stored_id() {
+    let store = MemorySessionStore::new();
+    let config = SessionConfig::default();
+    let mut record = SessionRecord::new();
+    record.data.insert("user".to_string(), "alice".to_string());
+    store.save("abcd", &record).unwrap();
+
+    let mut request = request_with_cookie("session_id=abcd");
+    load_session(&store, &config, &mut request).unwrap();
+    let session = request.session().unwrap();
+    session.rotate().unwrap();
+    let mut response = empty();
+    persist_session(&store, &config, session, &mut response).unwrap();
+
+    let new_id = cookie_id(&set_cookie_value(&response).unwrap());
+    assert_ne!(new_id, "abcd");
+    assert!(store.load("abcd").unwrap().is_none());
+    assert_eq!(store.load(&new_id).unwrap().unwrap().data.get("user"), Some(&"alice".to_string()));
+}
+
+#[test]
+fn test_expired_session_is_replaced() {
+    let store = MemorySessionStore::new();
+    let config = SessionConfig::default();
+    let mut record = SessionRecord::new();
+    record.last_accessed = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+    store.save("abcd", &record).unwrap();
+
+    let mut request = request_with_cookie("session_id=abcd");
+    load_session(&store, &config, &mut request).unwrap();
+    assert_ne!(request.session().unwrap().id(), "abcd");
+    assert!(store.load("abcd").unwrap().is_none());
+}
+
+#[test]
+fn test_save_purges_expired_sessions() {
+    let store = MemorySessionStore::new();
+    let config = SessionConfig::default();
+    let mut record = SessionRecord::new();
+    record.last_accessed = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+    store.save("abcd", &record).unwrap();
+
+    let mut request = request_with_cookie("");
+    load_session(&store, &config, &mut request).unwrap();
+    let session = request.session().unwrap();
+    session.insert("user", "alice");
+    persist_session(&store, &config, session, &mut empty()).unwrap();
+
+    let sessions = store.sessions();
+    assert!(!sessions.contains_key("abcd"));
+    assert!(sessions.contains_key(&session.id()));
+}
+
+#[test]
+fn test_save_purges_expired_session_files() {
+    let directory = std::env::temp_dir().join(format!("http_server_purged_sessions_{}", std::process::id()));
+    let store = FileSessionStore::new(&directory).unwrap();
+    let config = SessionConfig::default();
+    let mut record = SessionRecord::new();
+    record.last_accessed = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+    store.save("abcd", &record).unwrap();
+
+    let mut request = request_with_cookie("");
+    load_session(&store, &config, &mut request).unwrap();
+    let session = request.session().unwrap();
+    session.insert("user", "alice");
+    persist_session(&store, &config, session, &mut empty()).unwrap();
+
+    assert!(!directory.join("abcd.session").exists());
+    assert!(directory.join(format!("{}.session", session.id())).exists());
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_destroy_clears_cookie() {
+    let store = MemorySessionStore::new();
+    let config = SessionConfig::default();
+    store.save("abcd", &SessionRecord::new()).unwrap();
+
+    let mut request = request_with_cookie("session_id=abcd");
+    load_session(&store, &config, &mut request).unwrap();
+    request.session().unwrap().destroy();
+    let mut response = empty();
+    persist_session(&store, &config, request.session().unwrap(), &mut response).unwrap();
+
+    assert!(set_cookie_value(&response).unwrap().contains("Max-Age=0"));
+    assert!(store.load("abcd").unwrap().is_none());
+}
+
+#[test]
+fn test_file_store() {
+    let directory = std::env::temp_dir().join(format!("http_server_sessions_{}", std::process::id()));
+    let store = FileSessionStore::new(&directory).unwrap();
+    let mut record = SessionRecord::new();
+    record.data.insert("user".to_string(), "alice".to_string());
+
+    store.save("abcd", &record).unwrap();
+    assert_eq!(store.load("abcd").unwrap().unwrap().data, record.data);
+    assert!(store.load("../abcd").unwrap().is_none());
+    assert!(store.save("../abcd", &record).is_err());
+
+    store.remove("abcd").unwrap();
+    assert!(store.load("abcd").unwrap().is_none());
+    std::fs::remove_dir_all(directory).unwrap();
+}
@@ -0,0 +1,142 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::mime_type::TEXT_EVENT_STREAM;
+use crate::response::Response;
+use crate::status_code::OK;
+
+/// Events buffered per stream before `EventSender::send` starts waiting.
+const EVENT_CHANNEL_CAPACITY: usize = 32;
+
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// A single Server-Sent Event (`event:`, `data:`, `id:` and `retry:` fields).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    pub event: Option<String>,
+    pub data: Option<String>,
+    pub id: Option<String>,
+    pub retry: Option<Duration>,
+    pub comment: Option<String>,
+}
+
+/// Field values can't contain line breaks, they would start a new field.
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn event<S: Into<String>>(mut self, event: S) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn data<S: Into<String>>(mut self, data: S) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    pub fn id<S: Into<String>>(mut self, id: S) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = String::new();
+        if let Some(comment) = &self.comment {
+            for line in comment.lines() {
+                output.push_str(&format!(": {}\n", line));
+            }
+        }
+        if let Some(event) = &self.event {
+            output.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            output.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = self.retry {
+            output.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        if let Some(data) = &self.data {
+            // Multi-line data is sent as one `data:` field per line.
+            for line in data.split('\n') {
+                output.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+            }
+        }
+        output.push('\n');
+        output.into_bytes()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamClosed;
+
+/// The sending half of an event stream created by [`sse`].
+///
+/// Sends fail with [`StreamClosed`] once the client disconnected or the
+/// server is shutting down; dropping every sender ends the response.
+#[derive(Clone)]
+pub struct EventSender {
+    sender: smol::channel::Sender<Event>,
+}
+
+impl EventSender {
+    pub async fn send(&self, event: Event) -> Result<(), StreamClosed> {
+        self.sender.send(event).await.map_err(|_| StreamClosed)
+    }
+
+    pub fn try_send(&self, event: Event) -> Result<(), StreamClosed> {
+        self.sender.try_send(event).map_err(|_| StreamClosed)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// The receiving half of an event stream, carried by the `Response`.
+#[derive(Clone)]
+pub struct EventStream {
+    pub(crate) receiver: smol::channel::Receiver<Event>,
+    pub(crate) keep_alive: Duration,
+}
+
+impl Debug for EventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventStream")
+            .field("keep_alive", &self.keep_alive)
+            .finish()
+    }
+}
+
+/// Creates a `text/event-stream` response that stays open while events are
+/// pushed through the returned sender, with a keep-alive comment every 15 seconds.
+pub fn sse() -> (EventSender, Response) {
+    sse_with_keep_alive(DEFAULT_KEEP_ALIVE)
+}
+
+pub fn sse_with_keep_alive(keep_alive: Duration) -> (EventSender, Response) {
+    let (sender, receiver) = smol::channel::bounded(EVENT_CHANNEL_CAPACITY);
+    let response = Response {
+        content_type: TEXT_EVENT_STREAM,
+        bytes: Vec::new(),
+        status_code: OK,
+        headers: Vec::new(),
+        event_stream: Some(EventStream { receiver, keep_alive }),
+    };
+    (EventSender { sender }, response)
+}
@@ -0,0 +1,440 @@
+mod test;
+mod x509;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+
+use crate::logging::{info, warning};
+use crate::http2::ALPN_PROTOCOL;
+use crate::http_server::HttpServerConfig;
+
+/// Where a certificate chain is loaded from. PEM sources hold the leaf
+/// certificate followed by its intermediates; other sections are skipped.
+#[derive(Clone, Debug)]
+pub enum CertificateSource {
+    PemFile(String),
+    Pem(Vec<u8>),
+    /// DER certificates, leaf first.
+    Der(Vec<Vec<u8>>),
+}
+
+/// Where a private key is loaded from. PKCS#8, PKCS#1 (RSA) and SEC1 (EC)
+/// keys are accepted in both PEM and DER form.
+#[derive(Clone, Debug)]
+pub enum PrivateKeySource {
+    PemFile(String),
+    Pem(Vec<u8>),
+    Der(Vec<u8>),
+}
+
+/// Whether clients have to present a certificate signed by one of the CAs.
+#[derive(Clone, Debug, Default)]
+pub enum ClientAuth {
+    #[default]
+    None,
+    /// Clients may connect without a certificate, but one that is presented
+    /// has to be valid.
+    Optional(CertificateSource),
+    Required(CertificateSource),
+}
+
+#[derive(Clone)]
+pub struct HttpsServerConfig {
+    pub http_config: HttpServerConfig,
+    /// Served to clients without SNI or asking for a host not in `hosts`.
+    pub certificate: CertificateSource,
+    pub private_key: PrivateKeySource,
+    /// Certificates selected by SNI, keyed by lowercase hostname. A
+    /// `*.example.com` entry matches any single label below `example.com`.
+    pub hosts: HashMap<String, (CertificateSource, PrivateKeySource)>,
+    pub client_auth: ClientAuth,
+    /// Connections arriving while this many handshakes are in progress are
+    /// dropped.
+    pub max_concurrent_handshakes: usize,
+    /// TLS 1.2 and 1.3 by default.
+    pub protocol_versions: Vec<&'static SupportedProtocolVersion>,
+    /// The crypto provider's defaults when empty.
+    pub cipher_suites: Vec<SupportedCipherSuite>,
+    /// Reload the certificates on `SIGHUP` (Unix only).
+    pub reload_on_signal: bool,
+    /// Reload the certificates when one of the PEM files changes, checking
+    /// their modification time at this interval.
+    pub reload_poll_interval: Option<Duration>,
+    reload_requests: ReloadRequests,
+}
+
+impl HttpsServerConfig {
+    pub fn new(certificate: CertificateSource, private_key: PrivateKeySource) -> Self {
+        HttpsServerConfig {
+            http_config: HttpServerConfig::default(),
+            certificate,
+            private_key,
+            hosts: HashMap::new(),
+            client_auth: ClientAuth::None,
+            max_concurrent_handshakes: 256,
+            protocol_versions: rustls::DEFAULT_VERSIONS.to_vec(),
+            cipher_suites: Vec::new(),
+            reload_on_signal: false,
+            reload_poll_interval: None,
+            reload_requests: ReloadRequests::default(),
+        }
+    }
+
+    /// A handle to reload the certificates of every listener started with
+    /// this configuration (or a clone of it).
+    pub fn reload_handle(&self) -> TlsReloadHandle {
+        TlsReloadHandle {
+            requests: self.reload_requests.clone(),
+        }
+    }
+
+    fn watched_files(&self) -> Vec<String> {
+        let sources = std::iter::once((&self.certificate, &self.private_key))
+            .chain(self.hosts.values().map(|(certificate, private_key)| (certificate, private_key)));
+        let mut files = Vec::new();
+        for (certificate, private_key) in sources {
+            if let CertificateSource::PemFile(path) = certificate {
+                files.push(path.clone());
+            }
+            if let PrivateKeySource::PemFile(path) = private_key {
+                files.push(path.clone());
+            }
+        }
+        if let ClientAuth::Optional(CertificateSource::PemFile(path)) | ClientAuth::Required(CertificateSource::PemFile(path)) =
+            &self.client_auth
+        {
+            files.push(path.clone());
+        }
+        files
+    }
+
+    pub fn from_pem_files<C: Into<String>, K: Into<String>>(cert_path: C, key_path: K) -> Self {
+        Self::new(
+            CertificateSource::PemFile(cert_path.into()),
+            PrivateKeySource::PemFile(key_path.into()),
+        )
+    }
+
+    pub fn add_host<T: Into<String>>(&mut self, hostname: T, certificate: CertificateSource, private_key: PrivateKeySource) {
+        self.hosts
+            .insert(hostname.into().to_ascii_lowercase(), (certificate, private_key));
+    }
+}
+
+/// The identity in a verified client certificate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// The subject distinguished name, e.g. `CN=admin, O=Example Org`, with
+    /// values escaped as in RFC 4514. `issuer` is formatted the same way.
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub issuer: String,
+    /// Uppercase hexadecimal.
+    pub serial_number: String,
+    pub dns_names: Vec<String>,
+    pub email_addresses: Vec<String>,
+    pub uris: Vec<String>,
+}
+
+/// What the TLS handshake negotiated, shared by the requests of a connection.
+#[derive(Debug, Default)]
+pub struct TlsInfo {
+    /// The hostname the client asked for through SNI.
+    pub server_name: Option<String>,
+    /// The client certificate chain, leaf first. Only verified chains get
+    /// this far, the handshake fails otherwise.
+    pub peer_certificates: Vec<CertificateDer<'static>>,
+    /// The parsed leaf of `peer_certificates`.
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+impl TlsInfo {
+    pub(crate) fn from_connection(connection: &rustls::ServerConnection) -> Self {
+        let peer_certificates: Vec<CertificateDer<'static>> = connection
+            .peer_certificates()
+            .map(|chain| chain.iter().map(|certificate| certificate.clone().into_owned()).collect())
+            .unwrap_or_default();
+        TlsInfo {
+            server_name: connection.server_name().map(|name| name.to_string()),
+            client_certificate: peer_certificates
+                .first()
+                .and_then(|leaf| x509::parse_certificate(leaf)),
+            peer_certificates,
+        }
+    }
+}
+
+fn tls_error(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+impl CertificateSource {
+    fn describe(&self) -> String {
+        match self {
+            CertificateSource::PemFile(path) => format!("'{}'", path),
+            CertificateSource::Pem(_) => "in-memory PEM".to_string(),
+            CertificateSource::Der(_) => "in-memory DER".to_string(),
+        }
+    }
+
+    pub fn load(&self) -> std::io::Result<Vec<CertificateDer<'static>>> {
+        let certificates = match self {
+            CertificateSource::PemFile(path) => CertificateDer::pem_file_iter(path)
+                .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>()),
+            CertificateSource::Pem(pem) => CertificateDer::pem_slice_iter(pem).collect::<Result<Vec<_>, _>>(),
+            CertificateSource::Der(certificates) => Ok(certificates
+                .iter()
+                .map(|der| CertificateDer::from(der.clone()))
+                .collect()),
+        }
+        .map_err(|e| tls_error(format!("Failed to load certificate chain from {}: {}", self.describe(), e)))?;
+
+        if certificates.is_empty() {
+            return Err(tls_error(format!("No certificates found in {}.", self.describe())));
+        }
+        Ok(certificates)
+    }
+}
+
+impl PrivateKeySource {
+    fn describe(&self) -> String {
+        match self {
+            PrivateKeySource::PemFile(path) => format!("'{}'", path),
+            PrivateKeySource::Pem(_) => "in-memory PEM".to_string(),
+            PrivateKeySource::Der(_) => "in-memory DER".to_string(),
+        }
+    }
+
+    pub fn load(&self) -> std::io::Result<PrivateKeyDer<'static>> {
+        match self {
+            PrivateKeySource::PemFile(path) => PrivateKeyDer::from_pem_file(path).map_err(|e| e.to_string()),
+            PrivateKeySource::Pem(pem) => PrivateKeyDer::from_pem_slice(pem).map_err(|e| e.to_string()),
+            PrivateKeySource::Der(der) => PrivateKeyDer::try_from(der.clone()).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| tls_error(format!("Failed to load private key from {}: {}", self.describe(), e)))
+    }
+}
+
+fn certified_key(
+    certificate: &CertificateSource,
+    private_key: &PrivateKeySource,
+    provider: &CryptoProvider,
+) -> std::io::Result<Arc<CertifiedKey>> {
+    let certificates = certificate.load()?;
+    let key = private_key.load()?;
+    CertifiedKey::from_der(certificates, key, provider)
+        .map(Arc::new)
+        .map_err(|e| {
+            tls_error(format!(
+                "Failed to use certificate {} with private key {}: {}",
+                certificate.describe(),
+                private_key.describe(),
+                e
+            ))
+        })
+}
+
+/// Picks the certificate for the SNI name of a handshake.
+#[derive(Debug)]
+pub(crate) struct SniResolver {
+    default: Arc<CertifiedKey>,
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn new(config: &HttpsServerConfig, provider: &CryptoProvider) -> std::io::Result<Self> {
+        let default = certified_key(&config.certificate, &config.private_key, provider)?;
+        let mut hosts = HashMap::new();
+        for (hostname, (certificate, private_key)) in &config.hosts {
+            let key = certified_key(certificate, private_key, provider)
+                .map_err(|e| tls_error(format!("Invalid certificate for host '{}': {}", hostname, e)))?;
+            hosts.insert(hostname.to_ascii_lowercase(), key);
+        }
+        Ok(SniResolver { default, hosts })
+    }
+
+    pub(crate) fn select(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(name) = server_name.map(|name| name.trim_end_matches('.').to_ascii_lowercase()) else {
+            return self.default.clone();
+        };
+        self.hosts
+            .get(&name)
+            .or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.hosts.get(&format!("*.{}", parent))
+            })
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.select(client_hello.server_name()))
+    }
+}
+
+fn client_verifier(
+    client_auth: &ClientAuth,
+    provider: &Arc<CryptoProvider>,
+) -> std::io::Result<Arc<dyn ClientCertVerifier>> {
+    let (ca_certificates, optional) = match client_auth {
+        ClientAuth::None => return Ok(WebPkiClientVerifier::no_client_auth()),
+        ClientAuth::Optional(source) => (source, true),
+        ClientAuth::Required(source) => (source, false),
+    };
+    let mut roots = RootCertStore::empty();
+    for certificate in ca_certificates.load()? {
+        roots.add(certificate).map_err(|e| {
+            tls_error(format!("Invalid client CA certificate in {}: {}", ca_certificates.describe(), e))
+        })?;
+    }
+    let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+    if optional {
+        builder = builder.allow_unauthenticated();
+    }
+    builder
+        .build()
+        .map_err(|e| tls_error(format!("Failed to set up client certificate verification: {}", e)))
+}
+
+/// Builds the rustls configuration for an HTTPS listener, offering HTTP/2
+/// and HTTP/1.1 through ALPN.
+pub fn server_config(config: &HttpsServerConfig) -> std::io::Result<ServerConfig> {
+    let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+    if !config.cipher_suites.is_empty() {
+        provider.cipher_suites = config.cipher_suites.clone();
+    }
+    let provider = Arc::new(provider);
+    let resolver = SniResolver::new(config, &provider)?;
+    let verifier = client_verifier(&config.client_auth, &provider)?;
+
+    let mut tls_config = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&config.protocol_versions)
+        .map_err(|e| tls_error(format!("Invalid TLS protocol versions or cipher suites: {}", e)))?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(Arc::new(resolver));
+
+    // Prefer HTTP/2 when the client offers it.
+    tls_config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec(), b"http/1.1".to_vec()];
+    Ok(tls_config)
+}
+
+/// The configuration used for new handshakes. Connections keep the
+/// configuration they were accepted with, so swapping it doesn't affect them.
+pub(crate) type SharedServerConfig = Arc<RwLock<Arc<ServerConfig>>>;
+
+/// The reload requests of the listeners started with a configuration,
+/// shared by its clones. Each listener gets its own channel, so one
+/// request reloads all of them.
+#[derive(Clone, Default)]
+struct ReloadRequests(Arc<Mutex<Vec<smol::channel::Sender<()>>>>);
+
+impl ReloadRequests {
+    fn subscribe(&self) -> (smol::channel::Sender<()>, smol::channel::Receiver<()>) {
+        let (sender, receiver) = smol::channel::bounded(1);
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).push(sender.clone());
+        (sender, receiver)
+    }
+
+    fn send(&self) {
+        let mut senders = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        // Listeners that stopped dropped their receiver.
+        senders.retain(|sender| !sender.is_closed());
+        for sender in senders.iter() {
+            let _ = sender.try_send(());
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsReloadHandle {
+    requests: ReloadRequests,
+}
+
+impl TlsReloadHandle {
+    /// Asks the listeners to reload their certificates and keys. Requests
+    /// made while a reload is pending are merged into it.
+    pub fn reload(&self) {
+        self.requests.send();
+    }
+}
+
+/// Rebuilds the configuration from the sources. Invalid certificates are
+/// logged and the current configuration stays in place.
+pub(crate) fn reload(config: &HttpsServerConfig, shared: &SharedServerConfig) -> std::io::Result<()> {
+    match server_config(config) {
+        Ok(tls_config) => {
+            *shared.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(tls_config);
+            info!("TLS certificates reloaded.");
+            Ok(())
+        }
+        Err(e) => {
+            warning!(error = %e, "Failed to reload TLS certificates, keeping the current ones.");
+            Err(e)
+        }
+    }
+}
+
+fn modification_times(files: &[String]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| std::fs::metadata(file).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+/// Starts the tasks reloading `shared` on request, on `SIGHUP` and when the
+/// PEM files change. The tasks stop when the returned handles are dropped.
+pub(crate) fn spawn_reload_tasks(config: &HttpsServerConfig, shared: SharedServerConfig) -> Vec<smol::Task<()>> {
+    let mut tasks = Vec::new();
+    let (sender, requests) = config.reload_requests.subscribe();
+
+    #[cfg(unix)]
+    if config.reload_on_signal {
+        match async_signal::Signals::new([async_signal::Signal::Hup]) {
+            Ok(mut signals) => {
+                let sender = sender.clone();
+                tasks.push(smol::spawn(async move {
+                    use futures::StreamExt;
+                    while signals.next().await.is_some() {
+                        let _ = sender.try_send(());
+                    }
+                }));
+            }
+            Err(e) => warning!(error = %e, "Failed to listen for SIGHUP, certificates won't reload on signal."),
+        }
+    }
+
+    if let Some(interval) = config.reload_poll_interval {
+        let files = config.watched_files();
+        let sender = sender.clone();
+        tasks.push(smol::spawn(async move {
+            let mut last_modified = modification_times(&files);
+            loop {
+                smol::Timer::after(interval).await;
+                let modified = modification_times(&files);
+                if modified != last_modified {
+                    last_modified = modified;
+                    let _ = sender.try_send(());
+                }
+            }
+        }));
+    }
+
+    let config = config.clone();
+    tasks.push(smol::spawn(async move {
+        while requests.recv().await.is_ok() {
+            let _ = reload(&config, &shared);
+        }
+    }));
+    tasks
+}
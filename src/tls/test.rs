@@ -0,0 +1,325 @@
+#![cfg(test)]
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::tls::*;
+
+fn cert_path(name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+#[test]
+fn test_loads_full_chain() {
+    let chain = CertificateSource::PemFile(cert_path("chain.pem")).load().unwrap();
+    assert_eq!(chain.len(), 2);
+
+    let pem = std::fs::read(cert_path("chain.pem")).unwrap();
+    assert_eq!(CertificateSource::Pem(pem).load().unwrap(), chain);
+}
+
+#[test]
+fn test_key_formats() {
+    for key in ["leaf_sec1.key", "leaf_pkcs8.key"] {
+        let config = HttpsServerConfig::from_pem_files(cert_path("chain.pem"), cert_path(key));
+        assert!(server_config(&config).is_ok(), "{}", key);
+    }
+    let config = HttpsServerConfig::from_pem_files(cert_path("rsa.pem"), cert_path("rsa_pkcs1.key"));
+    assert!(server_config(&config).is_ok());
+}
+
+#[test]
+fn test_in_memory_der() {
+    let certificates: Vec<Vec<u8>> = CertificateDer::pem_file_iter(cert_path("chain.pem"))
+        .unwrap()
+        .map(|certificate| certificate.unwrap().to_vec())
+        .collect();
+    let key = PrivateKeyDer::from_pem_file(cert_path("leaf_pkcs8.key")).unwrap();
+    let config = HttpsServerConfig::new(
+        CertificateSource::Der(certificates),
+        PrivateKeySource::Der(key.secret_der().to_vec()),
+    );
+    assert!(server_config(&config).is_ok());
+}
+
+#[test]
+fn test_descriptive_errors() {
+    let missing = HttpsServerConfig::from_pem_files(cert_path("missing.pem"), cert_path("leaf_sec1.key"));
+    let error = server_config(&missing).err().unwrap();
+    assert!(error.to_string().contains("missing.pem"));
+
+    let no_certificates = HttpsServerConfig::from_pem_files(cert_path("leaf_sec1.key"), cert_path("leaf_sec1.key"));
+    let error = server_config(&no_certificates).err().unwrap();
+    assert!(error.to_string().contains("No certificates found"));
+
+    let mismatched = HttpsServerConfig::from_pem_files(cert_path("chain.pem"), cert_path("rsa_pkcs1.key"));
+    let error = server_config(&mismatched).err().unwrap();
+    assert!(error.to_string().contains("rsa_pkcs1.key"));
+}
+
+#[test]
+fn test_protocol_versions_and_cipher_suites() {
+    let mut config = HttpsServerConfig::from_pem_files(cert_path("chain.pem"), cert_path("leaf_sec1.key"));
+    config.protocol_versions = vec![&rustls::version::TLS13];
+    config.cipher_suites = vec![rustls::crypto::aws_lc_rs::cipher_suite::TLS13_AES_256_GCM_SHA384];
+    let tls_config = server_config(&config).unwrap();
+    assert_eq!(tls_config.crypto_provider().cipher_suites.len(), 1);
+
+    // TLS 1.2 without any TLS 1.2 cipher suite can't work.
+    config.protocol_versions = vec![&rustls::version::TLS12];
+    assert!(server_config(&config).is_err());
+}
+
+#[test]
+fn test_sni_selects_host_certificate() {
+    let mut config = HttpsServerConfig::from_pem_files(cert_path("chain.pem"), cert_path("leaf_sec1.key"));
+    config.add_host(
+        "Other.Test",
+        CertificateSource::PemFile(cert_path("other.pem")),
+        PrivateKeySource::PemFile(cert_path("other.key")),
+    );
+    config.add_host(
+        "*.other.test",
+        CertificateSource::PemFile(cert_path("other.pem")),
+        PrivateKeySource::PemFile(cert_path("other.key")),
+    );
+    let provider = rustls::crypto::aws_lc_rs::default_provider();
+    let resolver = SniResolver::new(&config, &provider).unwrap();
+
+    let default_leaf = CertificateSource::PemFile(cert_path("chain.pem")).load().unwrap()[0].clone();
+    let other_leaf = CertificateSource::PemFile(cert_path("other.pem")).load().unwrap()[0].clone();
+    assert_eq!(resolver.select(None).cert[0], default_leaf);
+    assert_eq!(resolver.select(Some("localhost")).cert[0], default_leaf);
+    assert_eq!(resolver.select(Some("other.test")).cert[0], other_leaf);
+    assert_eq!(resolver.select(Some("API.other.test.")).cert[0], other_leaf);
+    // Wildcards only cover a single label.
+    assert_eq!(resolver.select(Some("a.b.other.test")).cert[0], default_leaf);
+}
+
+#[test]
+fn test_invalid_host_certificate_names_host() {
+    let mut config = HttpsServerConfig::from_pem_files(cert_path("chain.pem"), cert_path("leaf_sec1.key"));
+    config.add_host(
+        "other.test",
+        CertificateSource::PemFile(cert_path("other.pem")),
+        PrivateKeySource::PemFile(cert_path("leaf_sec1.key")),
+    );
+    let error = server_config(&config).err().unwrap();
+    assert!(error.to_string().contains("'other.test'"));
+}
+
+fn copy_certs(name: &str, certificate: &str, key: &str) -> (String, String) {
+    let directory = std::env::temp_dir().join(format!("http_server_tls_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let cert_file = directory.join("server.pem").to_string_lossy().into_owned();
+    let key_file = directory.join("server.key").to_string_lossy().into_owned();
+    std::fs::copy(cert_path(certificate), &cert_file).unwrap();
+    std::fs::copy(cert_path(key), &key_file).unwrap();
+    (cert_file, key_file)
+}
+
+fn shared_config(config: &HttpsServerConfig) -> SharedServerConfig {
+    Arc::new(RwLock::new(Arc::new(server_config(config).unwrap())))
+}
+
+#[test]
+fn test_reload_keeps_current_config_on_error() {
+    let (cert_file, key_file) = copy_certs("invalid", "chain.pem", "leaf_sec1.key");
+    let config = HttpsServerConfig::from_pem_files(&cert_file, &key_file);
+    let shared = shared_config(&config);
+    let before = shared.read().unwrap().clone();
+
+    // A new certificate whose key hasn't been written yet.
+    std::fs::copy(cert_path("other.pem"), &cert_file).unwrap();
+    assert!(reload(&config, &shared).is_err());
+    assert!(Arc::ptr_eq(&before, &shared.read().unwrap()));
+
+    std::fs::copy(cert_path("other.key"), &key_file).unwrap();
+    assert!(reload(&config, &shared).is_ok());
+    assert!(!Arc::ptr_eq(&before, &shared.read().unwrap()));
+}
+
+#[test]
+fn test_reload_handle_and_polling() {
+    let (cert_file, key_file) = copy_certs("polling", "chain.pem", "leaf_sec1.key");
+    let mut config = HttpsServerConfig::from_pem_files(&cert_file, &key_file);
+    config.reload_poll_interval = Some(Duration::from_millis(20));
+    let shared = shared_config(&config);
+
+    smol::block_on(async {
+        let _tasks = spawn_reload_tasks(&config, shared.clone());
+        let initial = shared.read().unwrap().clone();
+
+        config.reload_handle().reload();
+        smol::Timer::after(Duration::from_millis(100)).await;
+        let reloaded = shared.read().unwrap().clone();
+        assert!(!Arc::ptr_eq(&initial, &reloaded));
+
+        std::fs::copy(cert_path("other.pem"), &cert_file).unwrap();
+        std::fs::copy(cert_path("other.key"), &key_file).unwrap();
+        smol::Timer::after(Duration::from_millis(200)).await;
+        assert!(!Arc::ptr_eq(&reloaded, &shared.read().unwrap()));
+    });
+}
+
+#[test]
+fn test_parse_client_certificate() {
+    let leaf = CertificateSource::PemFile(cert_path("client.pem")).load().unwrap();
+    let parsed = x509::parse_certificate(&leaf[0]).unwrap();
+    assert_eq!(
+        parsed,
+        ClientCertificate {
+            subject: "CN=admin, O=Example Org, OU=Ops".to_string(),
+            common_name: Some("admin".to_string()),
+            issuer: "CN=http_server test client CA".to_string(),
+            serial_number: "1234ABCD".to_string(),
+            dns_names: vec!["admin.internal".to_string()],
+            email_addresses: vec!["admin@example.com".to_string()],
+            uris: vec!["spiffe://example/admin".to_string()],
+        }
+    );
+    assert!(x509::parse_certificate(&leaf[0][..100]).is_none());
+}
+
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    assert!(contents.len() < 128);
+    [&[tag, contents.len() as u8], contents].concat()
+}
+
+/// A name with one attribute per relative distinguished name.
+fn name(attributes: &[(&[u8], u8, &[u8])]) -> Vec<u8> {
+    let rdns: Vec<u8> = attributes
+        .iter()
+        .flat_map(|(oid, tag, value)| der(0x31, &der(0x30, &[der(0x06, oid), der(*tag, value)].concat())))
+        .collect();
+    der(0x30, &rdns)
+}
+
+/// An unsigned certificate with just the fields the parser reads.
+fn certificate(issuer: &[u8], subject: &[u8]) -> Vec<u8> {
+    let empty = der(0x30, &[]);
+    let tbs = [der(0x02, &[1]), empty.clone(), issuer.to_vec(), empty.clone(), subject.to_vec(), empty].concat();
+    der(0x30, &der(0x30, &tbs))
+}
+
+const CN: &[u8] = &[0x55, 0x04, 0x03];
+const O: &[u8] = &[0x55, 0x04, 0x0a];
+
+#[test]
+fn test_parse_certificate_escapes_hostile_names() {
+    let issuer = name(&[(CN, 0x0c, b"CA")]);
+    let subject = name(&[(CN, 0x0c, b"admin, O=Example Org"), (O, 0x13, b"Evil")]);
+    let parsed = x509::parse_certificate(&certificate(&issuer, &subject)).unwrap();
+    assert_eq!(parsed.subject, "CN=admin\\, O\\=Example Org, O=Evil");
+    assert_eq!(parsed.common_name.as_deref(), Some("admin, O=Example Org"));
+    assert_eq!(parsed.issuer, "CN=CA");
+
+    let subject = name(&[(CN, 0x0c, b"#1 +\"<a>;\\\n\0 ")]);
+    let parsed = x509::parse_certificate(&certificate(&issuer, &subject)).unwrap();
+    assert_eq!(parsed.subject, "CN=\\#1 \\+\\\"\\<a\\>\\;\\\\\\0A\\00\\ ");
+
+    let subject = name(&[(CN, 0x0c, b" admin")]);
+    let parsed = x509::parse_certificate(&certificate(&issuer, &subject)).unwrap();
+    assert_eq!(parsed.subject, "CN=\\ admin");
+}
+
+#[test]
+fn test_parse_certificate_decodes_string_types() {
+    let issuer = name(&[(CN, 0x14, b"Caf\xe9")]);
+    let subject = name(&[(CN, 0x1e, &[0x00, 0x61, 0x00, 0x64, 0xd8, 0x3d, 0xde, 0x00]), (O, 0x1c, &[0, 0, 0, 0x4f])]);
+    let parsed = x509::parse_certificate(&certificate(&issuer, &subject)).unwrap();
+    assert_eq!(parsed.subject, "CN=ad\u{1F600}, O=O");
+    assert_eq!(parsed.common_name.as_deref(), Some("ad\u{1F600}"));
+    assert_eq!(parsed.issuer, "CN=Caf\u{e9}");
+
+    // Unpaired surrogates, invalid UTF-8 and non-strings aren't guessed at.
+    let subject = name(&[(CN, 0x1e, &[0xd8, 0x3d, 0x00, 0x61]), (O, 0x0c, &[0x61, 0xff]), (O, 0x02, &[0x05])]);
+    let parsed = x509::parse_certificate(&certificate(&issuer, &subject)).unwrap();
+    assert_eq!(parsed.subject, "CN=#1e04d83d0061, O=#0c0261ff, O=#020105");
+    assert_eq!(parsed.common_name, None);
+}
+
+#[test]
+fn test_parse_certificate_joins_multi_valued_rdns() {
+    let attribute = |oid: &[u8], value: &[u8]| der(0x30, &[der(0x06, oid), der(0x0c, value)].concat());
+    let subject = der(0x30, &der(0x31, &[attribute(CN, b"a+b"), attribute(O, b"x")].concat()));
+    let parsed = x509::parse_certificate(&certificate(&name(&[]), &subject)).unwrap();
+    assert_eq!(parsed.subject, "CN=a\\+b+O=x");
+    assert_eq!(parsed.issuer, "");
+}
+
+/// Runs a handshake between in-memory client and server connections.
+fn handshake(client: &mut rustls::ClientConnection, server: &mut rustls::ServerConnection) -> Result<(), rustls::Error> {
+    while client.is_handshaking() || server.is_handshaking() {
+        let mut to_server = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut to_server).unwrap();
+        }
+        server.read_tls(&mut to_server.as_slice()).unwrap();
+        server.process_new_packets()?;
+
+        let mut to_client = Vec::new();
+        while server.wants_write() {
+            server.write_tls(&mut to_client).unwrap();
+        }
+        client.read_tls(&mut to_client.as_slice()).unwrap();
+        client.process_new_packets()?;
+
+        if to_server.is_empty() && to_client.is_empty() {
+            panic!("Handshake stalled.");
+        }
+    }
+    Ok(())
+}
+
+fn mtls_handshake(client_auth: ClientAuth, client_certificate: Option<&str>) -> Result<TlsInfo, rustls::Error> {
+    let mut config = HttpsServerConfig::from_pem_files(cert_path("chain.pem"), cert_path("leaf_sec1.key"));
+    config.client_auth = client_auth;
+    let mut server = rustls::ServerConnection::new(Arc::new(server_config(&config).unwrap())).unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateSource::PemFile(cert_path("ca.pem")).load().unwrap().remove(0)).unwrap();
+    let builder = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let client_config = match client_certificate {
+        Some(name) => builder
+            .with_client_auth_cert(
+                CertificateSource::PemFile(cert_path(&format!("{}.pem", name))).load().unwrap(),
+                PrivateKeySource::PemFile(cert_path(&format!("{}.key", name))).load().unwrap(),
+            )
+            .unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    let server_name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+    let mut client = rustls::ClientConnection::new(Arc::new(client_config), server_name).unwrap();
+
+    handshake(&mut client, &mut server)?;
+    Ok(TlsInfo::from_connection(&server))
+}
+
+#[test]
+fn test_mutual_tls() {
+    let client_ca = || CertificateSource::PemFile(cert_path("client_ca.pem"));
+
+    let info = mtls_handshake(ClientAuth::Required(client_ca()), Some("client")).unwrap();
+    assert_eq!(info.server_name.as_deref(), Some("localhost"));
+    assert_eq!(info.peer_certificates.len(), 1);
+    assert_eq!(info.client_certificate.unwrap().common_name.as_deref(), Some("admin"));
+
+    assert!(mtls_handshake(ClientAuth::Required(client_ca()), None).is_err());
+    // Signed by a CA the server doesn't trust.
+    assert!(mtls_handshake(ClientAuth::Required(client_ca()), Some("other")).is_err());
+
+    let info = mtls_handshake(ClientAuth::Optional(client_ca()), None).unwrap();
+    assert!(info.peer_certificates.is_empty());
+    assert!(info.client_certificate.is_none());
+    assert!(mtls_handshake(ClientAuth::Optional(client_ca()), Some("other")).is_err());
+
+    let info = mtls_handshake(ClientAuth::None, None).unwrap();
+    assert!(info.client_certificate.is_none());
+}
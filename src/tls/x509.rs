@@ -0,0 +1,250 @@
+//! Just enough DER (X.690) to read the identity out of an X.509 certificate
+//! (RFC 5280, section 4.1). Signatures and validity are checked by rustls.
+
+use crate::tls::ClientCertificate;
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const BOOLEAN: u8 = 0x01;
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const TELETEX_STRING: u8 = 0x14;
+const IA5_STRING: u8 = 0x16;
+const UNIVERSAL_STRING: u8 = 0x1c;
+const BMP_STRING: u8 = 0x1e;
+
+const SAN_EMAIL: u8 = 0x81;
+const SAN_DNS: u8 = 0x82;
+const SAN_URI: u8 = 0x86;
+
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+const ATTRIBUTE_NAMES: [(&[u8], &str); 8] = [
+    (OID_COMMON_NAME, "CN"),
+    (&[0x55, 0x04, 0x05], "serialNumber"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01], "emailAddress"),
+];
+
+struct Reader<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Reader { input }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.input.first().copied()
+    }
+
+    /// Reads one TLV, returning its tag and contents.
+    fn read_any(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.input.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let length = if first & 0x80 == 0 {
+            first as usize
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return None;
+            }
+            let (bytes, remaining) = rest.split_at(count);
+            rest = remaining;
+            bytes.iter().fold(0, |length, &b| (length << 8) | b as usize)
+        };
+        if length > rest.len() {
+            return None;
+        }
+        let (contents, remaining) = rest.split_at(length);
+        self.input = remaining;
+        Some((tag, contents))
+    }
+
+    fn read(&mut self, expected: u8) -> Option<&'a [u8]> {
+        match self.read_any()? {
+            (tag, contents) if tag == expected => Some(contents),
+            _ => None,
+        }
+    }
+}
+
+fn oid_to_string(oid: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut value: u64 = 0;
+    for &byte in oid {
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 != 0 {
+            continue;
+        }
+        if parts.is_empty() {
+            let first = (value / 40).min(2);
+            parts.push(first.to_string());
+            parts.push((value - first * 40).to_string());
+        } else {
+            parts.push(value.to_string());
+        }
+        value = 0;
+    }
+    parts.join(".")
+}
+
+/// Decodes a directory string, `None` for other types or invalid contents.
+fn decode_string(tag: u8, value: &[u8]) -> Option<String> {
+    match tag {
+        UTF8_STRING => String::from_utf8(value.to_vec()).ok(),
+        PRINTABLE_STRING | IA5_STRING if value.is_ascii() => String::from_utf8(value.to_vec()).ok(),
+        // Treated as Latin-1, as most implementations do.
+        TELETEX_STRING => Some(value.iter().map(|&b| b as char).collect()),
+        BMP_STRING if value.len().is_multiple_of(2) => {
+            let units = value.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+            char::decode_utf16(units).collect::<Result<_, _>>().ok()
+        }
+        UNIVERSAL_STRING if value.len().is_multiple_of(4) => value
+            .chunks_exact(4)
+            .map(|quad| char::from_u32(u32::from_be_bytes([quad[0], quad[1], quad[2], quad[3]])))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Escapes an attribute value as RFC 4514, section 2.4 requires. Control
+/// characters are hex-escaped as well so that names are safe to log.
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    escaped.push_str(&format!("\\{:02X}", byte));
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Formats a distinguished name in certificate order, e.g. `CN=admin, O=Example`,
+/// escaping values as RFC 4514 does. Values that aren't strings are written as
+/// `#` followed by their hex DER encoding. Also returns the first common name.
+fn parse_name(name: &[u8]) -> Option<(String, Option<String>)> {
+    let mut rdns = Reader::new(name);
+    let mut formatted = Vec::new();
+    let mut common_name = None;
+    while !rdns.is_empty() {
+        let mut set = Reader::new(rdns.read(SET)?);
+        let mut attributes = Vec::new();
+        while !set.is_empty() {
+            let mut attribute = Reader::new(set.read(SEQUENCE)?);
+            let oid = attribute.read(OBJECT_IDENTIFIER)?;
+            let encoded = attribute.input;
+            let (tag, value) = attribute.read_any()?;
+            let encoded = &encoded[..encoded.len() - attribute.input.len()];
+            let label = match ATTRIBUTE_NAMES.iter().find(|(known, _)| *known == oid) {
+                Some((_, label)) => label.to_string(),
+                None => oid_to_string(oid),
+            };
+            let value = match decode_string(tag, value) {
+                Some(value) => {
+                    let escaped = escape_value(&value);
+                    if oid == OID_COMMON_NAME && common_name.is_none() {
+                        common_name = Some(value);
+                    }
+                    escaped
+                }
+                None => format!("#{}", encoded.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+            };
+            attributes.push(format!("{}={}", label, value));
+        }
+        formatted.push(attributes.join("+"));
+    }
+    Some((formatted.join(", "), common_name))
+}
+
+fn parse_subject_alt_names(extension_value: &[u8], certificate: &mut ClientCertificate) -> Option<()> {
+    let mut names = Reader::new(Reader::new(extension_value).read(SEQUENCE)?);
+    while !names.is_empty() {
+        let (tag, value) = names.read_any()?;
+        let value = String::from_utf8_lossy(value).into_owned();
+        match tag {
+            SAN_DNS => certificate.dns_names.push(value),
+            SAN_EMAIL => certificate.email_addresses.push(value),
+            SAN_URI => certificate.uris.push(value),
+            _ => {}
+        }
+    }
+    Some(())
+}
+
+/// Reads the subject, issuer, serial number and subject alternative names
+/// of a DER certificate. Returns `None` for malformed certificates.
+pub(crate) fn parse_certificate(der: &[u8]) -> Option<ClientCertificate> {
+    let mut certificate = Reader::new(Reader::new(der).read(SEQUENCE)?);
+    let mut tbs = Reader::new(certificate.read(SEQUENCE)?);
+    if tbs.peek_tag() == Some(VERSION) {
+        tbs.read_any()?;
+    }
+    let serial = tbs.read(INTEGER)?;
+    tbs.read(SEQUENCE)?; // signature algorithm
+    let (issuer, _) = parse_name(tbs.read(SEQUENCE)?)?;
+    tbs.read(SEQUENCE)?; // validity
+    let (subject, common_name) = parse_name(tbs.read(SEQUENCE)?)?;
+    tbs.read(SEQUENCE)?; // subject public key info
+
+    let serial = match serial {
+        [0, rest @ ..] if !rest.is_empty() => rest,
+        serial => serial,
+    };
+    let mut parsed = ClientCertificate {
+        subject,
+        common_name,
+        issuer,
+        serial_number: serial.iter().map(|b| format!("{:02X}", b)).collect(),
+        ..Default::default()
+    };
+
+    while !tbs.is_empty() {
+        let (tag, contents) = tbs.read_any()?;
+        if tag != EXTENSIONS {
+            continue;
+        }
+        let mut extensions = Reader::new(Reader::new(contents).read(SEQUENCE)?);
+        while !extensions.is_empty() {
+            let mut extension = Reader::new(extensions.read(SEQUENCE)?);
+            let oid = extension.read(OBJECT_IDENTIFIER)?;
+            if extension.peek_tag() == Some(BOOLEAN) {
+                extension.read_any()?;
+            }
+            let value = extension.read(OCTET_STRING)?;
+            if oid == OID_SUBJECT_ALT_NAME {
+                parse_subject_alt_names(value, &mut parsed)?;
+            }
+        }
+    }
+    Some(parsed)
+}
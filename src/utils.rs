@@ -28,3 +28,65 @@ pub fn gzip_compress(data: &[u8]) -> std::io::Result<Vec<u8>> {
     encoder.write_all(data)?;
     encoder.finish()
 }
+
+pub fn random_bytes(buffer: &mut [u8]) -> std::io::Result<()> {
+    rustls::crypto::aws_lc_rs::default_provider()
+        .secure_random
+        .fill(buffer)
+        .map_err(|_| std::io::Error::other("Failed to generate random bytes"))
+}
+
+pub fn random_hex(size: usize) -> std::io::Result<String> {
+    let mut buffer = vec![0u8; size];
+    random_bytes(&mut buffer)?;
+    Ok(buffer.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The UTC date and time of `time` as (year, month, day, hour, minute,
+/// second), with months and days counted from 1.
+pub fn utc_date_time(time: std::time::SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let seconds = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (days, seconds_of_day) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+
+    // Civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year,
+        month,
+        day,
+        (seconds_of_day / 3600) as u32,
+        (seconds_of_day % 3600 / 60) as u32,
+        (seconds_of_day % 60) as u32,
+    )
+}
+
+/// `value` as a quoted JSON string.
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
@@ -0,0 +1,460 @@
+mod test;
+
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha1::{Digest, Sha1};
+
+use crate::client_socket::{BoxedStream, ClientSocket, ReadError, Socket, WriteError};
+use crate::http_method::HttpMethod;
+use crate::http_version::HttpVersion;
+use crate::request::Request;
+use crate::response::{Response, status};
+use crate::status_code::{BAD_REQUEST, SWITCHING_PROTOCOLS, UPGRADE_REQUIRED};
+
+/// Magic value appended to the client key when computing `Sec-WebSocket-Accept` (RFC 6455 section 1.3).
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const UNSUPPORTED_DATA: u16 = 1003;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const POLICY_VIOLATION: u16 = 1008;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+pub const INTERNAL_ERROR: u16 = 1011;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+#[derive(Clone, Copy)]
+pub struct WebSocketConfig {
+    /// Largest message (all fragments together) accepted from a client.
+    pub max_message_size: usize,
+    /// How long to wait for data from the client before giving up on the connection.
+    pub read_timeout: Duration,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            max_message_size: 16 * 1024 * 1024, // 16 MB
+            read_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    ProtocolError(&'static str),
+    InvalidUtf8,
+    MessageTooLarge,
+    ConnectionClosed,
+    Timeout,
+    Cancellation,
+    IoError(std::io::Error),
+}
+
+impl WebSocketError {
+    /// The close code sent to the client when this error ends the connection.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::ProtocolError(_) => Some(PROTOCOL_ERROR),
+            WebSocketError::InvalidUtf8 => Some(INVALID_PAYLOAD),
+            WebSocketError::MessageTooLarge => Some(MESSAGE_TOO_BIG),
+            _ => None,
+        }
+    }
+}
+
+impl Display for WebSocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketError::ProtocolError(reason) => write!(f, "Protocol error: {}", reason),
+            WebSocketError::InvalidUtf8 => write!(f, "Invalid UTF-8 in text message"),
+            WebSocketError::MessageTooLarge => write!(f, "Message too large"),
+            WebSocketError::ConnectionClosed => write!(f, "Connection closed"),
+            WebSocketError::Timeout => write!(f, "Read timeout"),
+            WebSocketError::Cancellation => write!(f, "Connection cancelled"),
+            WebSocketError::IoError(e) => write!(f, "IO Error: {}", e),
+        }
+    }
+}
+
+impl From<ReadError> for WebSocketError {
+    fn from(error: ReadError) -> Self {
+        match error {
+            ReadError::IoError(e) => WebSocketError::IoError(e),
+            ReadError::Timeout => WebSocketError::Timeout,
+            ReadError::Cancellation => WebSocketError::Cancellation,
+            ReadError::MaxSizeExceeded => WebSocketError::MessageTooLarge,
+            ReadError::UnexpectedError => WebSocketError::ConnectionClosed,
+        }
+    }
+}
+
+impl From<WriteError> for WebSocketError {
+    fn from(error: WriteError) -> Self {
+        match error {
+            WriteError::IoError(e) => WebSocketError::IoError(e),
+            WriteError::Timeout => WebSocketError::Timeout,
+            WriteError::Cancellation => WebSocketError::Cancellation,
+            WriteError::UnexpectedError => WebSocketError::ConnectionClosed,
+        }
+    }
+}
+
+pub type WebSocketHandler = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+fn header_has_token(request: &Request, name: &str, token: &str) -> bool {
+    request.headers.get(name).is_some_and(|values| {
+        values
+            .as_slice()
+            .iter()
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+pub fn is_upgrade_request(request: &Request) -> bool {
+    header_has_token(request, "upgrade", "websocket")
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+/// Validates the opening handshake (RFC 6455 section 4.2.1) and builds the
+/// `101 Switching Protocols` response, or the error response to send instead.
+pub fn handshake_response(request: &Request) -> Response {
+    if request.method != HttpMethod::GET || request.http_version != HttpVersion::Http1_1 {
+        return status(BAD_REQUEST);
+    }
+
+    if !is_upgrade_request(request) {
+        return status(UPGRADE_REQUIRED).header("Upgrade", "websocket").header("Connection", "Upgrade");
+    }
+
+    if !header_has_token(request, "connection", "upgrade") {
+        return status(BAD_REQUEST);
+    }
+
+    if request.headers.get_single("sec-websocket-version").map(|v| v.trim()) != Some("13") {
+        return status(UPGRADE_REQUIRED).header("Sec-WebSocket-Version", "13");
+    }
+
+    let key = match request.headers.get_single("sec-websocket-key") {
+        Some(key) => key.trim(),
+        None => return status(BAD_REQUEST),
+    };
+    match BASE64.decode(key) {
+        Ok(decoded) if decoded.len() == 16 => {}
+        _ => return status(BAD_REQUEST),
+    }
+
+    status(SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept_key(key))
+}
+
+pub(crate) struct Frame {
+    pub(crate) fin: bool,
+    pub(crate) opcode: u8,
+    pub(crate) payload: Vec<u8>,
+}
+
+async fn read_exact<T: Socket>(socket: &mut T, size: usize) -> Result<Vec<u8>, WebSocketError> {
+    if size == 0 {
+        return Ok(vec![]);
+    }
+    let bytes = socket.read_n(size).await?;
+    if bytes.len() < size {
+        return Err(WebSocketError::ConnectionClosed);
+    }
+    Ok(bytes)
+}
+
+/// Reads a single client frame, unmasking its payload. `max_size` bounds the
+/// payload length so oversized frames are rejected before being read.
+pub(crate) async fn read_frame<T: Socket>(socket: &mut T, max_size: usize) -> Result<Frame, WebSocketError> {
+    let header = read_exact(socket, 2).await?;
+    let fin = header[0] & 0x80 != 0;
+    if header[0] & 0x70 != 0 {
+        return Err(WebSocketError::ProtocolError("Reserved bits set without a negotiated extension"));
+    }
+    let opcode = header[0] & 0x0F;
+    let masked = header[1] & 0x80 != 0;
+    if !masked {
+        return Err(WebSocketError::ProtocolError("Client frames must be masked"));
+    }
+
+    let length = match header[1] & 0x7F {
+        126 => {
+            let bytes = read_exact(socket, 2).await?;
+            u16::from_be_bytes([bytes[0], bytes[1]]) as u64
+        }
+        127 => {
+            let bytes = read_exact(socket, 8).await?;
+            let mut length = [0u8; 8];
+            length.copy_from_slice(&bytes);
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+
+    if opcode >= 0x8 && (length > 125 || !fin) {
+        return Err(WebSocketError::ProtocolError("Control frames must be short and unfragmented"));
+    }
+    if length > max_size as u64 {
+        return Err(WebSocketError::MessageTooLarge);
+    }
+
+    let mask = read_exact(socket, 4).await?;
+    let mut payload = read_exact(socket, length as usize).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Frame { fin, opcode, payload })
+}
+
+pub(crate) fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+fn parse_close_payload(payload: &[u8]) -> Result<Option<CloseFrame>, WebSocketError> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => Err(WebSocketError::ProtocolError("Close frame with a truncated status code")),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            if !is_valid_close_code(code) {
+                return Err(WebSocketError::ProtocolError("Invalid close code"));
+            }
+            let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| WebSocketError::InvalidUtf8)?;
+            Ok(Some(CloseFrame { code, reason }))
+        }
+    }
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    // Control frame payloads are limited to 125 bytes.
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    payload
+}
+
+#[derive(Default)]
+pub(crate) struct ConnectionState {
+    close_sent: bool,
+    close_received: bool,
+    /// The opcode and data of a fragmented message, kept across reads since
+    /// control frames may arrive between its fragments.
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+pub(crate) async fn send_message<T: Socket>(
+    socket: &mut T,
+    state: &mut ConnectionState,
+    message: Message,
+) -> Result<(), WebSocketError> {
+    if state.close_sent {
+        return Err(WebSocketError::ConnectionClosed);
+    }
+    let frame = match message {
+        Message::Text(text) => encode_frame(OPCODE_TEXT, text.as_bytes()),
+        Message::Binary(data) => encode_frame(OPCODE_BINARY, &data),
+        Message::Ping(data) => encode_frame(OPCODE_PING, &data[..data.len().min(125)]),
+        Message::Pong(data) => encode_frame(OPCODE_PONG, &data[..data.len().min(125)]),
+        Message::Close(frame) => {
+            state.close_sent = true;
+            match frame {
+                Some(frame) => encode_frame(OPCODE_CLOSE, &close_payload(frame.code, &frame.reason)),
+                None => encode_frame(OPCODE_CLOSE, &[]),
+            }
+        }
+    };
+    socket.write_all(&frame).await?;
+    Ok(())
+}
+
+async fn read_message_inner<T: Socket>(
+    socket: &mut T,
+    state: &mut ConnectionState,
+    config: &WebSocketConfig,
+) -> Result<Message, WebSocketError> {
+    loop {
+        let remaining = config.max_message_size - state.fragments.as_ref().map_or(0, |(_, data)| data.len());
+        let frame = read_frame(socket, remaining.max(125)).await?;
+
+        match frame.opcode {
+            OPCODE_PING => {
+                if !state.close_sent {
+                    send_message(socket, state, Message::Pong(frame.payload.clone())).await?;
+                }
+                return Ok(Message::Ping(frame.payload));
+            }
+            OPCODE_PONG => return Ok(Message::Pong(frame.payload)),
+            OPCODE_CLOSE => {
+                state.close_received = true;
+                let close_frame = parse_close_payload(&frame.payload)?;
+                if !state.close_sent {
+                    let reply = close_frame.as_ref().map(|frame| CloseFrame { code: frame.code, reason: String::new() });
+                    send_message(socket, state, Message::Close(reply)).await?;
+                }
+                return Ok(Message::Close(close_frame));
+            }
+            OPCODE_TEXT | OPCODE_BINARY => {
+                if state.fragments.is_some() {
+                    return Err(WebSocketError::ProtocolError("New message started before the previous one finished"));
+                }
+                if frame.payload.len() > config.max_message_size {
+                    return Err(WebSocketError::MessageTooLarge);
+                }
+                state.fragments = Some((frame.opcode, frame.payload));
+            }
+            OPCODE_CONTINUATION => match state.fragments.as_mut() {
+                Some((_, data)) => {
+                    if data.len() + frame.payload.len() > config.max_message_size {
+                        return Err(WebSocketError::MessageTooLarge);
+                    }
+                    data.extend_from_slice(&frame.payload);
+                }
+                None => return Err(WebSocketError::ProtocolError("Continuation frame without a message to continue")),
+            },
+            _ => return Err(WebSocketError::ProtocolError("Unknown opcode")),
+        }
+
+        if frame.fin
+            && let Some((opcode, data)) = state.fragments.take()
+        {
+            return match opcode {
+                OPCODE_TEXT => String::from_utf8(data).map(Message::Text).map_err(|_| WebSocketError::InvalidUtf8),
+                _ => Ok(Message::Binary(data)),
+            };
+        }
+    }
+}
+
+/// Reads the next complete message, answering pings and the closing
+/// handshake. Returns `None` once the connection has been closed.
+pub(crate) async fn read_message<T: Socket>(
+    socket: &mut T,
+    state: &mut ConnectionState,
+    config: &WebSocketConfig,
+) -> Option<Result<Message, WebSocketError>> {
+    if state.close_received {
+        return None;
+    }
+    match read_message_inner(socket, state, config).await {
+        Ok(message) => Some(Ok(message)),
+        Err(error) => {
+            state.close_received = true;
+            if let Some(code) = error.close_code()
+                && !state.close_sent
+            {
+                let close = Message::Close(Some(CloseFrame { code, reason: String::new() }));
+                let _ = send_message(socket, state, close).await;
+            }
+            Some(Err(error))
+        }
+    }
+}
+
+/// A WebSocket connection handed to the handler registered with `HttpServer::websocket`.
+pub struct WebSocket {
+    socket: ClientSocket<BoxedStream>,
+    request: Request,
+    config: WebSocketConfig,
+    state: ConnectionState,
+}
+
+impl WebSocket {
+    pub(crate) fn new(socket: ClientSocket<BoxedStream>, request: Request, config: WebSocketConfig) -> Self {
+        WebSocket {
+            socket,
+            request,
+            config,
+            state: ConnectionState::default(),
+        }
+    }
+
+    /// The upgrade request, with its headers and path parameters.
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    pub async fn recv(&mut self) -> Option<Result<Message, WebSocketError>> {
+        read_message(&mut self.socket, &mut self.state, &self.config).await
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
+        send_message(&mut self.socket, &mut self.state, message).await
+    }
+
+    pub async fn send_text<S: Into<String>>(&mut self, text: S) -> Result<(), WebSocketError> {
+        self.send(Message::Text(text.into())).await
+    }
+
+    pub async fn send_binary<B: Into<Vec<u8>>>(&mut self, data: B) -> Result<(), WebSocketError> {
+        self.send(Message::Binary(data.into())).await
+    }
+
+    /// Starts the closing handshake and waits for the client's close frame.
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if !self.state.close_sent {
+            self.send(Message::Close(Some(CloseFrame { code, reason: reason.to_string() }))).await?;
+        }
+        while let Some(message) = self.recv().await {
+            message?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+#![cfg(test)]
+
+use macro_rules_attribute::apply;
+use smol_macros::test;
+
+use crate::client_socket::{ReadError, SocketReader, SocketWriter, WriteError};
+use crate::websocket::*;
+
+struct MockSocket {
+    data: Vec<u8>,
+    position: usize,
+    written: Vec<u8>,
+}
+
+impl MockSocket {
+    fn new(data: Vec<u8>) -> Self {
+        MockSocket { data, position: 0, written: vec![] }
+    }
+}
+
+impl SocketReader for MockSocket {
+    async fn read_buffer(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        if self.position >= self.data.len() {
+            return Ok(0);
+        }
+        let bytes_to_read = std::cmp::min(buf.len(), self.data.len() - self.position);
+        buf[..bytes_to_read].copy_from_slice(&self.data[self.position..self.position + bytes_to_read]);
+        self.position += bytes_to_read;
+        Ok(bytes_to_read)
+    }
+}
+
+impl SocketWriter for MockSocket {
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), WriteError> {
+        self.written.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+#[test]
+fn test_accept_key() {
+    // Example from RFC 6455 section 1.3
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+#[test]
+fn test_encode_frame_lengths() {
+    assert_eq!(encode_frame(0x1, b"hi"), vec![0x81, 2, b'h', b'i']);
+    let medium = encode_frame(0x2, &[0u8; 300]);
+    assert_eq!(&medium[..4], &[0x82, 126, 1, 44]);
+    let large = encode_frame(0x2, &[0u8; 70000]);
+    assert_eq!(&large[..2], &[0x82, 127]);
+    assert_eq!(large.len(), 70000 + 10);
+}
+
+#[apply(test!)]
+async fn test_read_text_message() {
+    let mut socket = MockSocket::new(client_frame(true, 0x1, b"Hello"));
+    let mut state = ConnectionState::default();
+    let message = read_message(&mut socket, &mut state, &WebSocketConfig::default()).await;
+    assert_eq!(message.unwrap().unwrap(), Message::Text("Hello".to_string()));
+}
+
+#[apply(test!)]
+async fn test_fragmented_message_with_interleaved_ping() {
+    let mut data = client_frame(false, 0x1, b"Hel");
+    data.extend(client_frame(true, 0x9, b"ping"));
+    data.extend(client_frame(true, 0x0, b"lo"));
+    let mut socket = MockSocket::new(data);
+    let mut state = ConnectionState::default();
+    let config = WebSocketConfig::default();
+
+    let ping = read_message(&mut socket, &mut state, &config).await.unwrap().unwrap();
+    assert_eq!(ping, Message::Ping(b"ping".to_vec()));
+    assert_eq!(socket.written, encode_frame(0xA, b"ping"));
+    let message = read_message(&mut socket, &mut state, &config).await.unwrap().unwrap();
+    assert_eq!(message, Message::Text("Hello".to_string()));
+}
+
+#[apply(test!)]
+async fn test_fragmented_message() {
+    let mut data = client_frame(false, 0x2, b"ab");
+    data.extend(client_frame(false, 0x0, b"cd"));
+    data.extend(client_frame(true, 0x0, b"ef"));
+    let mut socket = MockSocket::new(data);
+    let mut state = ConnectionState::default();
+    let message = read_message(&mut socket, &mut state, &WebSocketConfig::default()).await;
+    assert_eq!(message.unwrap().unwrap(), Message::Binary(b"abcdef".to_vec()));
+}
+
+#[apply(test!)]
+async fn test_unmasked_frame_is_rejected() {
+    let mut socket = MockSocket::new(vec![0x81, 0x02, b'h', b'i']);
+    let mut state = ConnectionState::default();
+    let message = read_message(&mut socket, &mut state, &WebSocketConfig::default()).await;
+    assert!(matches!(message, Some(Err(WebSocketError::ProtocolError(_)))));
+    assert_eq!(socket.written, encode_frame(0x8, &PROTOCOL_ERROR.to_be_bytes()));
+    assert!(read_message(&mut socket, &mut state, &WebSocketConfig::default()).await.is_none());
+}
+
+#[apply(test!)]
+async fn test_message_too_large() {
+    let mut data = client_frame(false, 0x2, &[0u8; 100]);
+    data.extend(client_frame(true, 0x0, &[0u8; 100]));
+    let mut socket = MockSocket::new(data);
+    let mut state = ConnectionState::default();
+    let config = WebSocketConfig { max_message_size: 150, ..Default::default() };
+    let message = read_message(&mut socket, &mut state, &config).await;
+    assert!(matches!(message, Some(Err(WebSocketError::MessageTooLarge))));
+    assert_eq!(socket.written, encode_frame(0x8, &MESSAGE_TOO_BIG.to_be_bytes()));
+}
+
+#[apply(test!)]
+async fn test_invalid_utf8_text() {
+    let mut socket = MockSocket::new(client_frame(true, 0x1, &[0xff, 0xfe]));
+    let mut state = ConnectionState::default();
+    let message = read_message(&mut socket, &mut state, &WebSocketConfig::default()).await;
+    assert!(matches!(message, Some(Err(WebSocketError::InvalidUtf8))));
+}
+
+#[apply(test!)]
+async fn test_close_handshake() {
+    let mut payload = NORMAL_CLOSURE.to_be_bytes().to_vec();
+    payload.extend_from_slice(b"bye");
+    let mut socket = MockSocket::new(client_frame(true, 0x8, &payload));
+    let mut state = ConnectionState::default();
+    let config = WebSocketConfig::default();
+
+    let message = read_message(&mut socket, &mut state, &config).await.unwrap().unwrap();
+    assert_eq!(message, Message::Close(Some(CloseFrame { code: NORMAL_CLOSURE, reason: "bye".to_string() })));
+    assert_eq!(socket.written, encode_frame(0x8, &NORMAL_CLOSURE.to_be_bytes()));
+    assert!(read_message(&mut socket, &mut state, &config).await.is_none());
+    assert!(send_message(&mut socket, &mut state, Message::Text("late".to_string())).await.is_err());
+}
@@ -2,17 +2,27 @@
 mod tests {
     use std::io::{Read, Write};
     use std::net::TcpStream;
-    use std::sync::Once;
+    use std::net::SocketAddr;
+    use std::sync::{Once, OnceLock};
     use std::time::Duration;
     use http_server::http_server::prelude::*;
+    use http_server::http_server::{ConnectionLimitMode, HttpServerConfig, ListenAddr, HttpServerConnectionConfig, HttpServerTimeoutConfig, ServerHandle, ShutdownMode};
     use http_server::response::{bytes, status, text, text_response};
     use http_server::utils::{bytes_split, gzip_compress};
+    use http_server::sse::{Event, sse};
+    use http_server::websocket::Message;
 
     static START: Once = Once::new();
+    static ADDRESS: OnceLock<SocketAddr> = OnceLock::new();
+
+    fn server_address() -> SocketAddr {
+        *ADDRESS.get().expect("Server not started")
+    }
 
     fn start_server() {
         START.call_once(|| {
-            std::thread::spawn(|| {
+            let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
                 let mut server = HttpServer::new();
                 
                 // Basic GET
@@ -101,15 +111,45 @@ mod tests {
                         .header("X-Request-Id", "12345")
                 });
                 
-                let (task, _wx) = server.run("0.0.0.0", "5000", Default::default());
+                server.get("/events", |req| {
+                    let (sender, response) = sse();
+                    let start = req.last_event_id().and_then(|id| id.parse::<u32>().ok()).unwrap_or(0);
+                    smol::spawn(async move {
+                        for id in start + 1..=start + 2 {
+                            let event = Event::new().event("tick").id(id.to_string()).data(format!("line {}\nsecond", id));
+                            if sender.send(event).await.is_err() {
+                                break;
+                            }
+                        }
+                    }).detach();
+                    response
+                });
+
+                server.get("/peer", |req| {
+                    text(req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default())
+                });
+
+                server.websocket("/ws", |mut socket| async move {
+                    while let Some(Ok(message)) = socket.recv().await {
+                        if let Message::Text(text) = message
+                            && socket.send_text(text).await.is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+                
+                // Port 0 lets the OS pick a free port, so test binaries don't race.
+                let (task, handle) = server.run("127.0.0.1", "0", Default::default());
+                addr_tx.send(smol::block_on(handle.local_addr()).unwrap()).unwrap();
                 smol::block_on(task).unwrap();
             });
-            std::thread::sleep(Duration::from_millis(200));
+            ADDRESS.set(addr_rx.recv().unwrap()).unwrap();
         });
     }
 
     fn make_request(request: &str) -> String {
-        let mut stream = TcpStream::connect("127.0.0.1:5000").expect("Failed to connect");
+        let mut stream = TcpStream::connect(server_address()).expect("Failed to connect");
         stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
         stream.write_all(request.as_bytes()).unwrap();
         
@@ -349,7 +389,7 @@ mod tests {
     #[test]
     fn test_gzip_compression() {
         start_server();
-        let mut stream = TcpStream::connect("127.0.0.1:5000").expect("Failed to connect");
+        let mut stream = TcpStream::connect(server_address()).expect("Failed to connect");
         stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
         stream.write_all(b"GET /echo/test HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n").unwrap();
         
@@ -396,7 +436,7 @@ mod tests {
     #[test]
     fn test_multiple_requests_same_connection() {
         start_server();
-        let mut stream = TcpStream::connect("127.0.0.1:5000").expect("Failed to connect");
+        let mut stream = TcpStream::connect(server_address()).expect("Failed to connect");
         stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
         
         // First request
@@ -454,7 +494,7 @@ mod tests {
     fn test_expect_100_continue() {
         start_server();
         
-        let mut stream = TcpStream::connect("127.0.0.1:5000").expect("Failed to connect");
+        let mut stream = TcpStream::connect(server_address()).expect("Failed to connect");
         stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
         
         // Send headers with Expect: 100-continue
@@ -489,4 +529,1191 @@ mod tests {
         let body = get_body(&final_response);
         assert_eq!(body, "Hello World");
     }
+
+    // ===== WebSockets =====
+
+    #[test]
+    fn test_websocket_echo() {
+        start_server();
+
+        let mut stream = TcpStream::connect(server_address()).expect("Failed to connect");
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(
+            b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        ).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).unwrap();
+        let response = String::from_utf8_lossy(&buf[..n]).to_string();
+        assert_eq!(get_status_code(&response), 101);
+        assert_eq!(get_header(&response, "Sec-WebSocket-Accept").unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        // Masked text frame "Hi"
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![0x81, 0x82];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"Hi".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        stream.write_all(&frame).unwrap();
+
+        let n = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &[0x81, 0x02, b'H', b'i']);
+    }
+
+    #[test]
+    fn test_websocket_requires_upgrade() {
+        start_server();
+        let response = make_request("GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert_eq!(get_status_code(&response), 426);
+    }
+
+    // ===== Server-Sent Events =====
+
+    #[test]
+    fn test_server_sent_events() {
+        start_server();
+        let response = make_request("GET /events HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: 5\r\n\r\n");
+        assert_eq!(get_status_code(&response), 200);
+        assert_eq!(get_header(&response, "Content-Type").unwrap(), "text/event-stream");
+        assert_eq!(get_header(&response, "Transfer-Encoding").unwrap(), "chunked");
+        assert!(response.contains("event: tick\nid: 6\ndata: line 6\ndata: second\n\n"));
+        assert!(response.contains("id: 7\n"));
+        assert!(response.ends_with("0\r\n\r\n"));
+    }
+
+    // ===== Cleartext HTTP/2 =====
+
+    fn h2_frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.push(frame_type);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Reads frames until stream 1 ends, returning the first byte of its
+    /// header block and its body.
+    fn read_h2_stream(stream: &mut TcpStream, mut received: Vec<u8>) -> (Option<u8>, Vec<u8>) {
+        let mut status = None;
+        let mut body = Vec::new();
+        loop {
+            while received.len() >= 9 {
+                let length = u32::from_be_bytes([0, received[0], received[1], received[2]]) as usize;
+                if received.len() < 9 + length {
+                    break;
+                }
+                let frame: Vec<u8> = received.drain(..9 + length).collect();
+                let stream_id = u32::from_be_bytes([frame[5], frame[6], frame[7], frame[8]]);
+                let end_stream = frame[4] & 0x1 != 0;
+                match (frame[3], stream_id) {
+                    (0x1, 1) => status = Some(frame[9]),
+                    (0x0, 1) => body.extend_from_slice(&frame[9..]),
+                    _ => continue,
+                }
+                if end_stream {
+                    return (status, body);
+                }
+            }
+            let mut buf = [0u8; 4096];
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return (status, body),
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_h2c_prior_knowledge() {
+        start_server();
+        let mut stream = TcpStream::connect(server_address()).expect("Failed to connect");
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+        // GET /echo/h2c: :method GET, :scheme http, :path literal, :authority literal
+        let mut header_block = vec![0x82, 0x86, 0x04, 0x09];
+        header_block.extend_from_slice(b"/echo/h2c");
+        header_block.extend_from_slice(&[0x01, 0x09]);
+        header_block.extend_from_slice(b"localhost");
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend(h2_frame(0x4, 0, 0, &[]));
+        request.extend(h2_frame(0x1, 0x5, 1, &header_block));
+        stream.write_all(&request).unwrap();
+
+        let (status, body) = read_h2_stream(&mut stream, Vec::new());
+        // :status 200 is index 8 of the static table
+        assert_eq!(status, Some(0x88));
+        assert_eq!(body, b"h2c");
+    }
+
+    #[test]
+    fn test_h2c_upgrade() {
+        start_server();
+        let mut stream = TcpStream::connect(server_address()).expect("Failed to connect");
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(
+            b"GET /echo/upgraded HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n\r\n"
+        ).unwrap();
+
+        let mut received = Vec::new();
+        while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+            let mut buf = [0u8; 4096];
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0);
+            received.extend_from_slice(&buf[..n]);
+        }
+        let (head, rest) = bytes_split(&received, b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&head).to_string();
+        assert_eq!(get_status_code(&head), 101);
+        assert_eq!(get_header(&head, "Upgrade").unwrap(), "h2c");
+
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend(h2_frame(0x4, 0, 0, &[]));
+        stream.write_all(&request).unwrap();
+
+        let (status, body) = read_h2_stream(&mut stream, rest);
+        assert_eq!(status, Some(0x88));
+        assert_eq!(body, b"upgraded");
+    }
+
+    /// Reads the next frame as `(type, flags, stream id, payload)`.
+    fn read_h2_frame(stream: &mut TcpStream, received: &mut Vec<u8>) -> Option<(u8, u8, u32, Vec<u8>)> {
+        loop {
+            if received.len() >= 9 {
+                let length = u32::from_be_bytes([0, received[0], received[1], received[2]]) as usize;
+                if received.len() >= 9 + length {
+                    let frame: Vec<u8> = received.drain(..9 + length).collect();
+                    let stream_id = u32::from_be_bytes([frame[5], frame[6], frame[7], frame[8]]);
+                    return Some((frame[3], frame[4], stream_id, frame[9..].to_vec()));
+                }
+            }
+            let mut buf = [0u8; 4096];
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    fn h2_get(stream_id: u32, path: &str) -> Vec<u8> {
+        let mut header_block = vec![0x82, 0x86, 0x04, path.len() as u8];
+        header_block.extend_from_slice(path.as_bytes());
+        header_block.extend_from_slice(&[0x01, 0x09]);
+        header_block.extend_from_slice(b"localhost");
+        h2_frame(0x1, 0x5, stream_id, &header_block)
+    }
+
+    #[test]
+    fn test_h2_slow_handler_does_not_block_other_streams() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.get("/slow", |_req| {
+            std::thread::sleep(Duration::from_millis(1000));
+            text("slow")
+        });
+        server.get("/fast", |_req| text("fast"));
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend(h2_frame(0x4, 0, 0, &[]));
+        request.extend(h2_get(1, "/slow"));
+        request.extend(h2_get(3, "/fast"));
+        request.extend(h2_frame(0x6, 0, 0, b"pingpong"));
+        stream.write_all(&request).unwrap();
+
+        let mut received = Vec::new();
+        let mut events = Vec::new();
+        while let Some((frame_type, flags, stream_id, _)) = read_h2_frame(&mut stream, &mut received) {
+            match (frame_type, stream_id) {
+                (0x6, 0) if flags & 0x1 != 0 => events.push("ping"),
+                (0x0 | 0x1, 1 | 3) if flags & 0x1 != 0 => events.push(if stream_id == 1 { "slow" } else { "fast" }),
+                _ => {}
+            }
+            if events.contains(&"slow") {
+                break;
+            }
+        }
+        assert_eq!(events.last(), Some(&"slow"));
+        assert!(events.contains(&"fast") && events.contains(&"ping"));
+    }
+
+    #[test]
+    fn test_h2_peer_goaway_finishes_open_streams() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.get("/slow", |_req| {
+            std::thread::sleep(Duration::from_millis(300));
+            text("slow")
+        });
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend(h2_frame(0x4, 0, 0, &[]));
+        request.extend(h2_get(1, "/slow"));
+        // GOAWAY with last stream id 0, NO_ERROR.
+        request.extend(h2_frame(0x7, 0, 0, &[0; 8]));
+        request.extend(h2_get(3, "/slow"));
+        stream.write_all(&request).unwrap();
+
+        let mut received = Vec::new();
+        let mut refused = false;
+        while let Some((frame_type, flags, stream_id, payload)) = read_h2_frame(&mut stream, &mut received) {
+            match (frame_type, stream_id) {
+                // REFUSED_STREAM
+                (0x3, 3) => refused = payload == vec![0, 0, 0, 7],
+                (0x0, 1) if flags & 0x1 != 0 => {
+                    assert_eq!(payload, b"slow");
+                    assert!(refused);
+                    return;
+                }
+                _ => assert_ne!(stream_id, 3),
+            }
+        }
+        panic!("stream 1 wasn't answered");
+    }
+
+    #[test]
+    fn test_h2_streams_count_until_written() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.get("/", |_req| text("not yet"));
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        // SETTINGS_INITIAL_WINDOW_SIZE 0: no response body can be sent.
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend(h2_frame(0x4, 0, 0, &[0, 4, 0, 0, 0, 0]));
+        for stream_id in (1..200).step_by(2) {
+            request.extend(h2_get(stream_id, "/"));
+        }
+        stream.write_all(&request).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        stream.write_all(&h2_get(201, "/")).unwrap();
+
+        let mut received = Vec::new();
+        while let Some((frame_type, _, stream_id, payload)) = read_h2_frame(&mut stream, &mut received) {
+            if frame_type == 0x3 {
+                assert_eq!(stream_id, 201);
+                // REFUSED_STREAM
+                assert_eq!(payload, vec![0, 0, 0, 7]);
+                return;
+            }
+            assert_ne!(stream_id, 201);
+        }
+        panic!("stream 201 wasn't refused");
+    }
+
+    #[test]
+    fn test_peer_address() {
+        start_server();
+        let response = make_request("GET /peer HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert_eq!(get_status_code(&response), 200);
+        assert_eq!(get_body(&response), "127.0.0.1");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_listener() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        let path = std::env::temp_dir().join(format!("http_server_test_{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        // A socket file left behind by a server that didn't clean up.
+        drop(UnixListener::bind(&path).unwrap());
+
+        let mut server = HttpServer::new();
+        server.get("/whoami", |req| {
+            let pid = req.peer_credentials().map(|credentials| credentials.pid.to_string()).unwrap_or_default();
+            text(format!("{} {}", pid, req.peer_addr().is_none()))
+        });
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let listener = Listener::Unix { path: path.clone(), permissions: Some(0o660), config };
+        let (task, handle) = server.serve(vec![listener, Listener::http("127.0.0.1:0", config)]);
+        let server_thread = std::thread::spawn(move || smol::block_on(task));
+        let addrs = smol::block_on(handle.local_addrs()).to_vec();
+        assert_eq!(addrs[0], ListenAddr::Unix(path.clone()));
+        assert_eq!(smol::block_on(handle.local_addr()), addrs[1].tcp());
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap_or_default();
+        assert_eq!(get_status_code(&response), 200);
+        if cfg!(target_os = "linux") {
+            assert_eq!(get_body(&response), format!("{} true", std::process::id()));
+        }
+
+        drop(handle);
+        server_thread.join().unwrap().unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[test]
+    fn test_run_with_listener() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.get("/", |_req| text("pre-bound"));
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, handle) = server.run_with_listener(listener, config);
+        let server_thread = std::thread::spawn(move || smol::block_on(task));
+        assert_eq!(smol::block_on(handle.local_addr()), Some(addr));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap_or_default();
+        assert_eq!(get_body(&response), "pre-bound");
+
+        handle.shutdown();
+        server_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_graceful_shutdown_finishes_in_flight_requests() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.post("/echo", |req| bytes(req.body));
+        server.get("/", |_req| text("idle"));
+        let (task, handle) = server.run_with_listener(listener, HttpServerConfig::default());
+        let server_thread = std::thread::spawn(move || smol::block_on(task));
+
+        // A keep-alive connection waiting for its next request.
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        idle.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut buffer = [0u8; 1024];
+        assert!(idle.read(&mut buffer).unwrap() > 0);
+
+        // A request whose body is still being sent.
+        let mut in_flight = TcpStream::connect(addr).unwrap();
+        in_flight.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        in_flight.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nhello").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(handle.active_connections(), 2);
+
+        handle.shutdown_graceful(Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(idle.read(&mut buffer).unwrap_or(0), 0);
+        assert!(TcpStream::connect(addr).is_err());
+
+        in_flight.write_all(b"world").unwrap();
+        let mut response = String::new();
+        in_flight.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(get_body(&response), "helloworld");
+
+        smol::block_on(handle.wait());
+        assert_eq!(handle.active_connections(), 0);
+        server_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_graceful_shutdown_timeout_cancels_connections() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.post("/echo", |req| bytes(req.body));
+        let (task, handle) = server.run_with_listener(listener, HttpServerConfig::default());
+        let server_thread = std::thread::spawn(move || smol::block_on(task));
+
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stalled.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nhello").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let started = std::time::Instant::now();
+        handle.shutdown_graceful(Duration::from_millis(200));
+        smol::block_on(handle.wait());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(handle.active_connections(), 0);
+
+        let mut buffer = [0u8; 1024];
+        assert!(!matches!(stalled.read(&mut buffer), Ok(n) if n > 0));
+        server_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_overrides_graceful_shutdown() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.post("/echo", |req| bytes(req.body));
+        let (task, handle) = server.run_with_listener(listener, HttpServerConfig::default());
+        let server_thread = std::thread::spawn(move || smol::block_on(task));
+
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nhello").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let started = std::time::Instant::now();
+        handle.shutdown_graceful(Duration::from_secs(30));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(handle.active_connections(), 1);
+        handle.shutdown();
+        smol::block_on(handle.wait());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(handle.active_connections(), 0);
+        server_thread.join().unwrap().unwrap();
+    }
+
+    fn start_configured_server(config: HttpServerConfig) -> (SocketAddr, ServerHandle) {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.get("/", |_req| text("limited"));
+        server.post("/echo", |req| bytes(req.body));
+        server.get("/slow", |_req| {
+            std::thread::sleep(Duration::from_secs(1));
+            text("slow")
+        });
+        let config = HttpServerConfig {
+            shutdown_mode: ShutdownMode::Immediate,
+            ..config
+        };
+        let (task, handle) = server.run_with_listener(listener, config);
+        task.detach();
+        (addr, handle)
+    }
+
+    fn get_on(stream: &mut TcpStream) -> String {
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = stream.read(&mut buffer).unwrap_or(0);
+        String::from_utf8_lossy(&buffer[..n]).to_string()
+    }
+
+    #[test]
+    fn test_max_requests_per_connection() {
+        let (addr, _handle) = start_configured_server(HttpServerConfig {
+            connection_config: HttpServerConnectionConfig {
+                max_requests_per_connection: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+        let first = get_on(&mut stream);
+        assert_eq!(get_header(&first, "connection"), None);
+        let second = get_on(&mut stream);
+        assert_eq!(get_body(&second), "limited");
+        assert_eq!(get_header(&second, "connection").as_deref(), Some("close"));
+        let mut buffer = [0u8; 16];
+        assert_eq!(stream.read(&mut buffer).unwrap_or(0), 0);
+    }
+
+    #[test]
+    fn test_max_connections_rejects_when_full() {
+        let (addr, handle) = start_configured_server(HttpServerConfig {
+            connection_config: HttpServerConnectionConfig {
+                max_connections: Some(1),
+                when_full: ConnectionLimitMode::Reject,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut first = TcpStream::connect(addr).unwrap();
+        first.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(get_body(&get_on(&mut first)), "limited");
+
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        second.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert_eq!(handle.active_connections(), 1);
+        assert_eq!(handle.accepted_connections(), 1);
+        assert_eq!(handle.rejected_connections(), 1);
+
+        drop(first);
+        std::thread::sleep(Duration::from_millis(100));
+        let mut third = TcpStream::connect(addr).unwrap();
+        third.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(get_body(&get_on(&mut third)), "limited");
+    }
+
+    #[test]
+    fn test_max_connections_waits_when_full() {
+        let (addr, handle) = start_configured_server(HttpServerConfig {
+            connection_config: HttpServerConnectionConfig {
+                max_connections: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut first = TcpStream::connect(addr).unwrap();
+        first.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(get_body(&get_on(&mut first)), "limited");
+
+        // Sits in the listen backlog until the first connection closes.
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        second.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut buffer = [0u8; 1024];
+        assert!(second.read(&mut buffer).is_err());
+
+        drop(first);
+        second.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let n = second.read(&mut buffer).unwrap();
+        assert_eq!(get_body(&String::from_utf8_lossy(&buffer[..n])), "limited");
+        assert_eq!(handle.rejected_connections(), 0);
+    }
+
+    #[test]
+    fn test_max_connections_per_ip() {
+        let (addr, handle) = start_configured_server(HttpServerConfig {
+            connection_config: HttpServerConnectionConfig {
+                max_connections_per_ip: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut first = TcpStream::connect(addr).unwrap();
+        first.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(get_body(&get_on(&mut first)), "limited");
+
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert!(get_on(&mut second).starts_with("HTTP/1.1 503"));
+        assert_eq!(handle.rejected_connections(), 1);
+    }
+
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap_or_default();
+        response
+    }
+
+    #[test]
+    fn test_keep_alive_timeout_closes_idle_connection() {
+        let (addr, _handle) = start_configured_server(HttpServerConfig {
+            timeout_config: HttpServerTimeoutConfig {
+                keep_alive_timeout_duration: Duration::from_millis(200),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(get_body(&get_on(&mut stream)), "limited");
+        std::thread::sleep(Duration::from_millis(400));
+        assert_eq!(read_response(&mut stream), "");
+    }
+
+    #[test]
+    fn test_header_timeout_answers_slow_headers() {
+        let (addr, _handle) = start_configured_server(HttpServerConfig {
+            timeout_config: HttpServerTimeoutConfig {
+                header_timeout_duration: Duration::from_millis(300),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        // Every line arrives well within the read timeout, the headers don't.
+        for _ in 0..5 {
+            std::thread::sleep(Duration::from_millis(100));
+            let _ = stream.write_all(b"X-Slow: 1\r\n");
+        }
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 408"));
+        assert_eq!(get_header(&response, "connection").as_deref(), Some("close"));
+    }
+
+    #[test]
+    fn test_header_timeout_counts_from_first_byte() {
+        let (addr, _handle) = start_configured_server(HttpServerConfig {
+            timeout_config: HttpServerTimeoutConfig {
+                header_timeout_duration: Duration::from_millis(300),
+                keep_alive_timeout_duration: Duration::from_secs(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        // Idle for longer than the header timeout, then send the headers in two parts.
+        std::thread::sleep(Duration::from_millis(500));
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        stream.write_all(b"Host: localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert_eq!(get_status_code(&response), 200);
+        assert_eq!(get_body(&response), "limited");
+    }
+
+    #[test]
+    fn test_body_min_rate_answers_slow_bodies() {
+        let (addr, _handle) = start_configured_server(HttpServerConfig {
+            timeout_config: HttpServerTimeoutConfig {
+                read_timeout_duration: Duration::from_millis(200),
+                body_min_bytes_per_second: Some(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\n").unwrap();
+        for _ in 0..10 {
+            std::thread::sleep(Duration::from_millis(100));
+            let _ = stream.write_all(b"x");
+        }
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 408"));
+    }
+
+    #[test]
+    fn test_handler_timeout() {
+        let (addr, _handle) = start_configured_server(HttpServerConfig {
+            timeout_config: HttpServerTimeoutConfig {
+                handler_timeout_duration: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let started = std::time::Instant::now();
+        stream.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = stream.read(&mut buffer).unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..n]).starts_with("HTTP/1.1 503"));
+        assert!(started.elapsed() < Duration::from_millis(800));
+
+        // The connection is still usable.
+        assert_eq!(get_body(&get_on(&mut stream)), "limited");
+    }
+
+    #[test]
+    fn test_h2_handler_timeout() {
+        let (addr, _handle) = start_configured_server(HttpServerConfig {
+            timeout_config: HttpServerTimeoutConfig {
+                handler_timeout_duration: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let started = std::time::Instant::now();
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend(h2_frame(0x4, 0, 0, &[]));
+        request.extend(h2_get(1, "/slow"));
+        stream.write_all(&request).unwrap();
+
+        let mut received = Vec::new();
+        let headers = loop {
+            match read_h2_frame(&mut stream, &mut received) {
+                Some((0x1, _, 1, payload)) => break payload,
+                Some(_) => continue,
+                None => panic!("no response"),
+            }
+        };
+        // :status as a literal with an indexed name.
+        assert!(headers.starts_with(&[0x08, 0x03, b'5', b'0', b'3']));
+        assert!(started.elapsed() < Duration::from_millis(800));
+    }
+
+    /// Sends the preface and `frames`, then returns the header block of the
+    /// response on stream 1 and whether the connection sent GOAWAY after it.
+    fn h2_stalled_request(addr: SocketAddr, frames: Vec<u8>) -> (Vec<u8>, bool) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend(h2_frame(0x4, 0, 0, &[]));
+        request.extend(frames);
+        stream.write_all(&request).unwrap();
+
+        let mut received = Vec::new();
+        let mut headers = None;
+        while let Some((frame_type, _, stream_id, payload)) = read_h2_frame(&mut stream, &mut received) {
+            match (frame_type, stream_id) {
+                (0x1, 1) => headers = Some(payload),
+                (0x7, 0) if headers.is_some() => return (headers.unwrap(), true),
+                (0x3, 1) => return (headers.unwrap(), false),
+                _ => {}
+            }
+        }
+        panic!("stream 1 wasn't answered");
+    }
+
+    #[test]
+    fn test_h2_stream_timeouts() {
+        let (addr, _handle) = start_configured_server(HttpServerConfig {
+            timeout_config: HttpServerTimeoutConfig {
+                read_timeout_duration: Duration::from_millis(200),
+                header_timeout_duration: Duration::from_millis(200),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        // POST /echo: :method POST, :scheme http, :path literal, :authority literal
+        let mut header_block = vec![0x83, 0x86, 0x04, 0x05];
+        header_block.extend_from_slice(b"/echo");
+        header_block.extend_from_slice(&[0x01, 0x09]);
+        header_block.extend_from_slice(b"localhost");
+
+        // The body never arrives: the stream is answered and reset.
+        let started = std::time::Instant::now();
+        let (headers, closed) = h2_stalled_request(addr, h2_frame(0x1, 0x4, 1, &header_block));
+        // :status as a literal with an indexed name.
+        assert!(headers.starts_with(&[0x08, 0x03, b'4', b'0', b'8']));
+        assert!(!closed);
+        assert!(started.elapsed() < Duration::from_millis(800));
+
+        // The header block is never finished: the connection is closed.
+        let (headers, closed) = h2_stalled_request(addr, h2_frame(0x1, 0x1, 1, &header_block[..4]));
+        assert!(headers.starts_with(&[0x08, 0x03, b'4', b'0', b'8']));
+        assert!(closed);
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_access_log() {
+        use http_server::access_log::{AccessLog, AccessLogFormat};
+
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let buffer = SharedBuffer::default();
+        let mut server = HttpServer::new();
+        server.layer(AccessLog::new(AccessLogFormat::Combined, buffer.clone()));
+        server.pre_request(PathParameter::Exact("/private".to_string()), |_req| {
+            MiddlewareResult::SendResponseAndStopProcessing(text("unauthorized").status(401))
+        });
+        server.get("/", |_req| text("logged"));
+        server.get("/large", |_req| text("a".repeat(1000)));
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUser-Agent: test-agent\r\n\r\n").unwrap();
+        let mut response = [0u8; 1024];
+        assert!(stream.read(&mut response).unwrap() > 0);
+        stream.write_all(b"GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let _ = read_response(&mut stream);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream
+            .write_all(b"GET /large HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = vec![];
+        let _ = stream.read_to_end(&mut response);
+        let head = String::from_utf8_lossy(&response).to_string();
+        let sent = get_header(&head, "Content-Length").unwrap();
+        assert_ne!(sent, "1000");
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /private HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 401"));
+
+        let log = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("127.0.0.1 - - ["));
+        assert!(lines[0].ends_with("\"GET / HTTP/1.1\" 200 6 \"-\" \"test-agent\""));
+        assert!(lines[1].contains("\"GET /missing HTTP/1.1\" 404 "));
+        assert!(lines[2].contains(&format!("\"GET /large HTTP/1.1\" 200 {sent} ")));
+        assert!(lines[3].contains("\"GET /private HTTP/1.1\" 401 12 "));
+    }
+
+    #[test]
+    fn test_request_id_layer() {
+        use http_server::request_id::RequestIdLayer;
+
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.layer(RequestIdLayer::new());
+        server.get("/", |req| {
+            let trace = req.trace_context().unwrap();
+            text(format!("{} {}", req.request_id().unwrap(), trace.trace_id))
+        });
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: req-42\r\n\
+                traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\r\n\
+                tracestate: vendor=value\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let response = read_response(&mut stream);
+
+        assert!(response.contains("X-Request-Id: req-42\r\n"));
+        assert!(response.contains("traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(response.contains("tracestate: vendor=value\r\n"));
+        assert!(response.ends_with("req-42 4bf92f3577b34da6a3ce929d0e0e4736"));
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        let metrics = server.metrics();
+        server.get("/metrics", metrics.handler());
+        server.get("/users/:id", |_req| text("user"));
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /users/1 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = [0u8; 1024];
+        assert!(stream.read(&mut response).unwrap() > 0);
+        stream.write_all(b"GET /users/2 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(stream.read(&mut response).unwrap() > 0);
+        stream.write_all(b"BROKEN\r\n\r\n").unwrap();
+        assert!(stream.read(&mut response).unwrap() > 0);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("http_requests_total{route=\"/users/:id\",method=\"GET\",status=\"200\"} 2\n"));
+        assert!(response.contains("http_request_duration_seconds_count{route=\"/users/:id\",method=\"GET\"} 2\n"));
+        assert!(response.contains("http_request_parse_errors_total{error=\"UnhandledRequest\"} 1\n"));
+        assert!(response.contains("http_requests_in_flight 1\n"));
+        assert!(response.contains("http_open_connections 2\n"));
+        assert!(!response.contains("http_received_bytes_total 0\n"));
+    }
+
+    #[test]
+    fn test_readiness_fails_during_graceful_shutdown() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.health_endpoints();
+        let health = server.health();
+        health.readiness_check("always", || Ok(()));
+        let (task, handle) = server.run_with_listener(listener, HttpServerConfig::default());
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        handle.shutdown_graceful(Duration::from_secs(1));
+        let err = health.readiness().unwrap_err();
+        assert_eq!(err, vec![("shutdown".to_string(), "server is shutting down".to_string())]);
+        smol::block_on(handle.wait());
+    }
+
+    #[test]
+    fn test_drain_delay_fails_readiness_while_accepting() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.health_endpoints();
+        server.drain_delay(Duration::from_millis(500));
+        let (task, handle) = server.run_with_listener(listener, HttpServerConfig::default());
+        let server_thread = std::thread::spawn(move || smol::block_on(task));
+
+        handle.shutdown_graceful(Duration::from_secs(1));
+        std::thread::sleep(Duration::from_millis(100));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 503"));
+
+        smol::block_on(handle.wait());
+        assert!(TcpStream::connect(addr).is_err());
+        server_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_handler_panic_answers_500_and_keeps_connection() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.get("/panic", |_req| panic!("handler failed"));
+        server.get("/", |_req| text("still here"));
+        server.error_handler(PathParameter::Wildcard, |_req, res| {
+            MiddlewareResult::SendResponseAndStopProcessing(res.clone().body("custom error"))
+        });
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /panic HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = [0u8; 1024];
+        let read = stream.read(&mut response).unwrap();
+        let response = String::from_utf8_lossy(&response[..read]);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.ends_with("custom error"));
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).ends_with("still here"));
+    }
+
+    #[test]
+    fn test_error_renderer_problem_json() {
+        use http_server::error_renderer::ErrorRenderer;
+
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.post("/items", |_req| text("created"));
+        server.put("/items", |_req| text("replaced"));
+        server.error_renderer(ErrorRenderer::new().problem_json());
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /items HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("Content-Type: application/problem+json\r\n"));
+        assert!(response.contains("Allow: OPTIONS, POST, PUT\r\n"));
+        assert!(response.ends_with(
+            "{\"type\":\"about:blank\",\"title\":\"Method Not Allowed\",\"status\":405,\"detail\":\"Allowed methods: OPTIONS, POST, PUT\",\"instance\":\"/items\"}"
+        ));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /items HTTP/1.1\r\nBad Header\r\n\r\n").unwrap();
+        let mut response = [0u8; 1024];
+        let read = stream.read(&mut response).unwrap();
+        let response = String::from_utf8_lossy(&response[..read]);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.contains("Content-Type: application/problem+json\r\n"));
+        assert!(response.ends_with("\"status\":400,\"detail\":\"The request is malformed.\"}"));
+    }
+
+    #[test]
+    fn test_h2_body_too_large_is_rendered_and_counted() {
+        use http_server::error_renderer::ErrorRenderer;
+        use http_server::http_server::HttpServerSizeConfig;
+
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        let metrics = server.metrics();
+        server.get("/metrics", metrics.handler());
+        server.post("/items", |_req| text("created"));
+        server.error_renderer(ErrorRenderer::new().problem_json());
+        let config = HttpServerConfig {
+            shutdown_mode: ShutdownMode::Immediate,
+            size_config: HttpServerSizeConfig { request_body_max_size: 10, ..Default::default() },
+            ..Default::default()
+        };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        // POST /items: :method POST, :scheme http, :path literal, :authority literal
+        let mut header_block = vec![0x83, 0x86, 0x04, 0x06];
+        header_block.extend_from_slice(b"/items");
+        header_block.extend_from_slice(&[0x01, 0x09]);
+        header_block.extend_from_slice(b"localhost");
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend(h2_frame(0x4, 0, 0, &[]));
+        request.extend(h2_frame(0x1, 0x4, 1, &header_block));
+        request.extend(h2_frame(0x0, 0, 1, &[b'x'; 20]));
+        stream.write_all(&request).unwrap();
+
+        let mut received = Vec::new();
+        let mut headers = None;
+        let mut body = None;
+        while let Some((frame_type, flags, stream_id, payload)) = read_h2_frame(&mut stream, &mut received) {
+            match (frame_type, stream_id) {
+                (0x1, 1) => headers = Some(payload),
+                (0x0, 1) if flags & 0x1 != 0 => {
+                    body = Some(payload);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        // :status as a literal with an indexed name.
+        assert!(headers.unwrap().starts_with(&[0x08, 0x03, b'4', b'1', b'3']));
+        assert!(body.unwrap().ends_with(b"\"status\":413}"));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.contains("http_request_parse_errors_total{error=\"PayloadTooLarge\"} 1\n"));
+    }
+
+    #[test]
+    fn test_fallback_and_method_not_allowed_per_router() {
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.get("/", |_req| text("home"));
+        server.fallback(|req| text(format!("index.html for {}", req.path)));
+        let mut api = Router::new();
+        api.post("/items", |_req| text("created"));
+        api.fallback(|_req| text("no such endpoint").status(404));
+        api.method_not_allowed(|req| text(format!("{:?} not allowed", req.method)).status(405));
+        server.nest("/api", api);
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let request = |request: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            read_response(&mut stream)
+        };
+
+        let response = request("GET /settings/profile HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("index.html for /settings/profile"));
+
+        let response = request("GET /api/missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("no such endpoint"));
+
+        let response = request("GET /api/items HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("Allow: OPTIONS, POST\r\n"));
+        assert!(response.ends_with("GET not allowed"));
+
+        let response = request("POST /api/items HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("created"));
+    }
+
+    #[test]
+    fn test_cors_preflight_skips_later_middlewares() {
+        use http_server::cors::Cors;
+
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.layer(Cors::new().allow_origin("https://app.example.com").allow_headers(["Authorization"]));
+        server.pre_request(PathParameter::Wildcard, |req| {
+            if req.headers.get_single("authorization").is_some() {
+                MiddlewareResult::NextMiddleware
+            } else {
+                MiddlewareResult::SendResponseAndStopProcessing(text("unauthorized").status(401))
+            }
+        });
+        server.delete("/items/:id", |_req| text("deleted"));
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let request = |request: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            read_response(&mut stream)
+        };
+
+        let response = request(
+            "OPTIONS /items/1 HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\n\
+            Access-Control-Request-Method: DELETE\r\nAccess-Control-Request-Headers: authorization\r\n\
+            Connection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(response.contains("Access-Control-Allow-Methods: DELETE, OPTIONS\r\n"));
+        assert!(response.contains("Access-Control-Allow-Headers: authorization\r\n"));
+        assert!(response.contains("Access-Control-Allow-Origin: https://app.example.com\r\n"));
+        assert!(response.contains("Vary: Origin\r\n"));
+
+        let response = request(
+            "DELETE /items/1 HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\n\
+            Authorization: Bearer token\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.contains("Access-Control-Allow-Origin: https://app.example.com\r\n"));
+        assert!(response.ends_with("deleted"));
+    }
+
+    #[test]
+    fn test_security_headers_with_csp_nonce() {
+        use http_server::security_headers::SecurityHeaders;
+
+        let listener = smol::block_on(smol::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = HttpServer::new();
+        server.layer(
+            SecurityHeaders::new()
+                .content_security_policy("script-src 'self' {nonce}")
+                .route(PathParameter::Begin("/embed".to_string()), SecurityHeaders::new().frame_options("SAMEORIGIN")),
+        );
+        server.get("/", |req| text(format!("<script nonce=\"{}\"></script>", req.csp_nonce().unwrap_or_default())));
+        server.get("/embed", |_req| text("embedded"));
+        let config = HttpServerConfig { shutdown_mode: ShutdownMode::Immediate, ..Default::default() };
+        let (task, _handle) = server.run_with_listener(listener, config);
+        task.detach();
+
+        let request = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            stream
+                .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").as_bytes())
+                .unwrap();
+            read_response(&mut stream)
+        };
+
+        let response = request("/");
+        let nonce = response.split("nonce=\"").nth(1).unwrap().split('"').next().unwrap().to_string();
+        assert!(!nonce.is_empty());
+        assert!(response.contains(&format!("Content-Security-Policy: script-src 'self' 'nonce-{nonce}'\r\n")));
+        assert!(response.contains("X-Content-Type-Options: nosniff\r\n"));
+        assert!(response.contains("X-Frame-Options: DENY\r\n"));
+        // Plain HTTP.
+        assert!(!response.contains("Strict-Transport-Security"));
+
+        let response = request("/embed");
+        assert!(response.contains("X-Frame-Options: SAMEORIGIN\r\n"));
+        assert!(!response.contains("Content-Security-Policy"));
+    }
 }
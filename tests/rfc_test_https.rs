@@ -5,7 +5,8 @@ mod tests {
     use std::sync::{Arc, Once};
     use std::time::{Duration, Instant};
     use http_server::http_server::prelude::*;
-    use http_server::response::{bytes, status};
+    use http_server::http_server::HttpsServerConfig;
+    use http_server::response::{bytes, status, text};
     use rustls::pki_types::ServerName;
     use rustls::client::danger::{ServerCertVerifier, HandshakeSignatureValid, ServerCertVerified};
     use rustls::pki_types::{CertificateDer, UnixTime};
@@ -94,8 +95,10 @@ mod tests {
                         return bytes(body)
                     }
                 });
+
+                server.get("/sni", |req| text(req.server_name().unwrap_or("none")));
                 
-                let (task, _wx) = server.run_https("0.0.0.0", "4443", "local_certs/server.cert", "local_certs/server.key", Default::default()); 
+                let (task, _wx) = server.run_https("0.0.0.0", "4443", HttpsServerConfig::from_pem_files("local_certs/server.cert", "local_certs/server.key")); 
                 
                 smol::block_on(task).unwrap();
             });
@@ -103,6 +106,36 @@ mod tests {
         });
     }
 
+    // ---- Same routes over HTTPS, HTTP and an HTTPS redirect ----
+    static START_MULTI: Once = Once::new();
+
+    fn start_multi_listener_server() {
+        START_MULTI.call_once(|| {
+            std::thread::spawn(|| {
+                let mut server = HttpServer::new();
+                server.get("/scheme", |req| text(if req.tls().is_some() { "https" } else { "http" }));
+
+                let (task, _wx) = server.serve(vec![
+                    Listener::https("0.0.0.0:4444", HttpsServerConfig::from_pem_files("local_certs/server.cert", "local_certs/server.key")),
+                    Listener::http("0.0.0.0:4480", Default::default()),
+                    Listener::redirect_to_https("0.0.0.0:4481", 4444),
+                ]);
+
+                smol::block_on(task).unwrap();
+            });
+            std::thread::sleep(Duration::from_millis(200));
+        });
+    }
+
+    fn plain_request(port: u16, request: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut buf = [0u8; 4096];
+        let n = stream.read(&mut buf).unwrap_or(0);
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
     // ---- Test case structure ----
     struct TestCase<'a> {
         request: &'a str,
@@ -115,7 +148,11 @@ mod tests {
 
     // ---- Core test runner ----
     fn run_test_case(tc: &TestCase) -> bool {
-        let mut tcp_stream = match TcpStream::connect(("127.0.0.1", 4443)) {
+        run_test_case_on(4443, tc)
+    }
+
+    fn run_test_case_on(port: u16, tc: &TestCase) -> bool {
+        let mut tcp_stream = match TcpStream::connect(("127.0.0.1", port)) {
             Ok(s) => s,
             Err(_) => {
                 return false;
@@ -635,4 +672,180 @@ mod tests {
         };
         assert!(run_test_case(&case));
     }
+
+    #[test]
+    fn sni_name_on_request() {
+        start_server();
+        let case = TestCase {
+            request: "GET /sni HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            description: "The SNI name sent by the client is exposed on the request",
+            expected_status: &[(200, 200)],
+            expected_timeout: false,
+            expected_body: Some("localhost"),
+        };
+        assert!(run_test_case(&case));
+    }
+
+    #[test]
+    fn stalled_handshake_does_not_block_others() {
+        start_server();
+        // Connects but never sends a ClientHello.
+        let _stalled = TcpStream::connect(("127.0.0.1", 4443)).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let case = TestCase {
+            request: "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            description: "A client stuck in the handshake doesn't hold up other connections",
+            expected_status: &[(200, 200)],
+            expected_timeout: false,
+            expected_body: None,
+        };
+        assert!(run_test_case(&case));
+    }
+
+    #[test]
+    fn listeners_share_routes() {
+        start_multi_listener_server();
+        let case = TestCase {
+            request: "GET /scheme HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            description: "The HTTPS listener serves the shared routes",
+            expected_status: &[(200, 200)],
+            expected_timeout: false,
+            expected_body: Some("https"),
+        };
+        assert!(run_test_case_on(4444, &case));
+
+        let response = plain_request(4480, "GET /scheme HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert_eq!(parse_status_code(&response), 200);
+        assert_eq!(parse_body(&response), "http");
+    }
+
+    #[test]
+    fn redirect_listener_points_to_https() {
+        start_multi_listener_server();
+        let response = plain_request(4481, "GET /scheme?a=b HTTP/1.1\r\nHost: localhost:4481\r\n\r\n");
+        assert_eq!(parse_status_code(&response), 301);
+        assert!(response.contains("Location: https://localhost:4444/scheme?a=b\r\n"));
+
+        let response = plain_request(4481, "POST /scheme HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(parse_status_code(&response), 308);
+    }
+
+    fn h2_frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.push(frame_type);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn http2_via_alpn() {
+        start_server();
+        let mut tcp_stream = TcpStream::connect(("127.0.0.1", 4443)).unwrap();
+        tcp_stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+
+        let mut config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SelfSignedVerifier::new()))
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let server_name = ServerName::try_from("localhost").unwrap().to_owned();
+        let mut tls_conn = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let mut tls_stream = rustls::Stream::new(&mut tls_conn, &mut tcp_stream);
+
+        // POST / with the body "hello": :method POST, :scheme https, :path /, :authority localhost
+        let mut header_block = vec![0x83, 0x87, 0x84, 0x01, 0x09];
+        header_block.extend_from_slice(b"localhost");
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend(h2_frame(0x4, 0, 0, &[]));
+        request.extend(h2_frame(0x1, 0x4, 1, &header_block));
+        request.extend(h2_frame(0x0, 0x1, 1, b"hello"));
+        tls_stream.write_all(&request).unwrap();
+        assert_eq!(tls_stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+
+        let mut received = Vec::new();
+        let mut status = None;
+        let mut body = Vec::new();
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(2) {
+            let mut buf = [0u8; 4096];
+            match tls_stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(_) => continue,
+            }
+            let mut finished = false;
+            while received.len() >= 9 {
+                let length = u32::from_be_bytes([0, received[0], received[1], received[2]]) as usize;
+                if received.len() < 9 + length {
+                    break;
+                }
+                let frame: Vec<u8> = received.drain(..9 + length).collect();
+                let stream_id = u32::from_be_bytes([frame[5], frame[6], frame[7], frame[8]]);
+                match (frame[3], stream_id) {
+                    // :status 200 is index 8 of the static table
+                    (0x1, 1) => status = Some(frame[9]),
+                    (0x0, 1) => {
+                        body.extend_from_slice(&frame[9..]);
+                        finished = frame[4] & 0x1 != 0;
+                    }
+                    _ => {}
+                }
+            }
+            if finished {
+                break;
+            }
+        }
+        assert_eq!(status, Some(0x88));
+        assert_eq!(body, b"hello");
+    }
+
+    /// The leaf certificate the server at `addr` presents.
+    fn server_certificate(addr: std::net::SocketAddr) -> Vec<u8> {
+        let mut tcp_stream = TcpStream::connect(addr).unwrap();
+        tcp_stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SelfSignedVerifier::new()))
+            .with_no_client_auth();
+        let server_name = ServerName::try_from("localhost").unwrap().to_owned();
+        let mut tls_conn = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+        while tls_conn.is_handshaking() {
+            tls_conn.complete_io(&mut tcp_stream).unwrap();
+        }
+        tls_conn.peer_certificates().unwrap()[0].to_vec()
+    }
+
+    #[test]
+    fn reload_tls_reaches_every_listener() {
+        let certs = format!("{}/tests/certs", env!("CARGO_MANIFEST_DIR"));
+        let directory = std::env::temp_dir().join(format!("http_server_reload_tls_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let cert_file = directory.join("server.pem").to_string_lossy().into_owned();
+        let key_file = directory.join("server.key").to_string_lossy().into_owned();
+        std::fs::copy(format!("{certs}/chain.pem"), &cert_file).unwrap();
+        std::fs::copy(format!("{certs}/leaf_sec1.key"), &key_file).unwrap();
+
+        let mut server = HttpServer::new();
+        server.get("/", |_req| text("ok"));
+        let config = HttpsServerConfig::from_pem_files(&cert_file, &key_file);
+        let (task, handle) = server.serve(vec![
+            Listener::https("127.0.0.1:0", config.clone()),
+            Listener::https("127.0.0.1:0", config),
+        ]);
+        task.detach();
+        let addrs: Vec<_> = smol::block_on(handle.local_addrs()).iter().filter_map(|addr| addr.tcp()).collect();
+        let before: Vec<Vec<u8>> = addrs.iter().map(|addr| server_certificate(*addr)).collect();
+
+        std::fs::copy(format!("{certs}/other.pem"), &cert_file).unwrap();
+        std::fs::copy(format!("{certs}/other.key"), &key_file).unwrap();
+        handle.reload_tls();
+        std::thread::sleep(Duration::from_millis(200));
+
+        for (addr, before) in addrs.iter().zip(before) {
+            assert_ne!(server_certificate(*addr), before);
+        }
+        handle.shutdown();
+    }
 }